@@ -0,0 +1,293 @@
+use std::str::CharRange;
+use std::vec;
+
+use compile::code;
+use compile::inst;
+
+/// most bits `exec_bounded` may use to remember the addresses it tried
+/// at every offset
+pub static MAX_VISITED: uint = 256 * 1024;
+
+enum Job {
+    /// run the program from an address at a byte offset
+    Step(uint, uint),
+    /// restore a capture slot when backtracking past its `Save`
+    Restore(uint, Option<uint>),
+    /// restore a loop counter when backtracking past its `Reset` or `Increment`
+    RestoreCounter(uint, uint),
+    /// restore the offset a split was last reached at when backtracking past it
+    RestoreEntry(uint, Option<uint>),
+}
+
+/// Run `code` from address `pc`, anchored at byte offset `start` of `string`,
+/// by depth-first search, returning the capture slots of the first match found,
+/// or of the first one ending at the end of `string` if `full` is set.
+/// This takes exponential time in the worst case, so the NFA simulation
+/// in `re` is preferred whenever the program allows it.
+pub fn exec(code: &code::Code, nslots: uint, ncounters: uint, pc: uint, string: &str,
+            start: uint, full: bool) -> Option<~[Option<uint>]> {
+    let counters = vec::from_elem(ncounters, 0u);
+    match run(code, vec::from_elem(nslots, None), counters, pc, string, start, full) {
+        Some((slots, _)) => Some(slots),
+        None => None,
+    }
+}
+
+/// Whether `exec_bounded` can run `code`: it has nothing but matches,
+/// jumps, saves and assertions, so that how a path goes on from an address
+/// depends on nothing but the offset it is at
+pub fn supports_bounded(code: &code::Code) -> bool {
+    range(0, code.len()).all(|pc| match code.op(pc) {
+        code::Match(_) | code::Jmp(_) | code::Split(*) | code::Save(_) | code::Assert(_)
+        | code::Succeed => true,
+        _ => false,
+    })
+}
+
+/// Like `exec`, for programs that `supports_bounded`, but never trying an
+/// address at an offset twice, as it failed the first time, which takes
+/// linear time at the cost of a bit per address and offset. The caller
+/// keeps `code.len()` times the length of the text left under
+/// `MAX_VISITED`.
+pub fn exec_bounded(code: &code::Code, nslots: uint, string: &str,
+                    start: uint) -> Option<~[Option<uint>]> {
+    let width = string.len() - start + 1;
+    let mut visited = vec::from_elem((code.len() * width + 31) / 32, 0u32);
+    let mut slots = vec::from_elem(nslots, None);
+    let mut stack = ~[Step(0, start)];
+    while !stack.is_empty() {
+        match stack.pop() {
+            Restore(slot, old) => slots[slot] = old,
+            RestoreCounter(*) | RestoreEntry(*) => fail!("Unexpected loop job."),
+            Step(pc, pos) => {
+                let bit = pc * width + pos - start;
+                let (word, mask) = (bit / 32, 1 << (bit % 32) as u32);
+                if visited[word] & mask == 0 {
+                    visited[word] |= mask;
+                    if step_bounded(code, string, pc, pos, &mut slots, &mut stack) {
+                        return Some(slots);
+                    }
+                }
+            },
+        }
+    }
+    None
+}
+
+/// Run the instruction at `pc` at offset `pos` for `exec_bounded`,
+/// pushing the ways on onto `stack`, and return whether it succeeds
+fn step_bounded(code: &code::Code, string: &str, pc: uint, pos: uint,
+                slots: &mut ~[Option<uint>], stack: &mut ~[Job]) -> bool {
+    match code.op(pc) {
+        code::Match(m) => if pos < string.len() {
+            let CharRange {ch, next} = string.char_range_at(pos);
+            if m.matches(ch) {
+                stack.push(Step(pc + 1, next));
+            }
+        },
+        code::Jmp(a) => stack.push(Step(a, pos)),
+        code::Split(a, b) => {
+            stack.push(Step(b, pos));
+            stack.push(Step(a, pos));
+        },
+        code::Save(slot) => {
+            if slot < slots.len() {
+                stack.push(Restore(slot, slots[slot]));
+                slots[slot] = Some(pos);
+            }
+            stack.push(Step(pc + 1, pos));
+        },
+        code::Assert(ref a) => if a.holds(string, pos) {
+            stack.push(Step(pc + 1, pos));
+        },
+        code::Succeed => return true,
+        _ => fail!("Instruction not supported by the bounded backtracker."),
+    }
+    false
+}
+
+/// Like `exec`, but starting from the given capture slots and loop
+/// counters and also returning the offset where the match ends
+fn run(code: &code::Code, slots: ~[Option<uint>], counters: ~[uint], pc: uint, string: &str,
+       start: uint, full: bool) -> Option<(~[Option<uint>], uint)> {
+    let mut slots = slots;
+    let mut counters = counters;
+    // offset at which the current path last reached every split
+    let mut entries = vec::from_elem(code.len(), None);
+    let mut stack = ~[Step(pc, start)];
+    while !stack.is_empty() {
+        match stack.pop() {
+            Restore(slot, old) => slots[slot] = old,
+            RestoreCounter(counter, old) => counters[counter] = old,
+            RestoreEntry(pc, old) => entries[pc] = old,
+            Step(pc, pos) => match step(code, string, pc, pos, &mut slots, &mut counters,
+                                        &mut entries, &mut stack) {
+                Some(end) if !full || end == string.len() => return Some((slots, end)),
+                _ => {},
+            },
+        }
+    }
+    None
+}
+
+/// Follow one path through the program, pushing the alternatives it
+/// skips onto `stack`, until it succeeds, returning the end offset, or fails.
+/// A path reaching a split again at the offset it last reached it at went
+/// through an iteration of a loop that matched nothing, and fails there,
+/// leaving the way out of the loop pushed the first time; loops that
+/// count their iterations are bounded anyway, and need empty ones to
+/// reach their minimum.
+fn step(code: &code::Code, string: &str, pc: uint, pos: uint, slots: &mut ~[Option<uint>],
+        counters: &mut ~[uint], entries: &mut ~[Option<uint>], stack: &mut ~[Job])
+        -> Option<uint> {
+    let mut pc = pc;
+    let mut pos = pos;
+    loop {
+        match code.op(pc) {
+            code::Match(m) => {
+                if pos >= string.len() {
+                    return None;
+                }
+                let CharRange {ch, next} = string.char_range_at(pos);
+                if !m.matches(ch) {
+                    return None;
+                }
+                pc += 1;
+                pos = next;
+            },
+            code::Jmp(a) => pc = a,
+            code::Split(a, b) => {
+                if !counted(code, a) && !counted(code, b) {
+                    if entries[pc] == Some(pos) {
+                        return None;
+                    }
+                    stack.push(RestoreEntry(pc, entries[pc]));
+                    entries[pc] = Some(pos);
+                }
+                stack.push(Step(b, pos));
+                pc = a;
+            },
+            code::Assert(ref a) => {
+                if !a.holds(string, pos) {
+                    return None;
+                }
+                pc += 1;
+            },
+            code::LookAhead(body, negated) => {
+                // the body sees the groups captured so far, but the groups
+                // inside it do not capture
+                let found = run(code, slots.clone(), counters.clone(), body, string, pos,
+                                false).is_some();
+                if found == negated {
+                    return None;
+                }
+                pc += 1;
+            },
+            code::LookBehind(body, negated, width) => {
+                let found = match inst::rewind(string, pos, width) {
+                    Some(start) => run(code, slots.clone(), counters.clone(), body, string,
+                                       start, false).is_some(),
+                    None => false,
+                };
+                if found == negated {
+                    return None;
+                }
+                pc += 1;
+            },
+            code::IfGroup(idx, no) => match (slots[2 * idx], slots[2 * idx + 1]) {
+                (Some(_), Some(_)) => pc += 1,
+                _ => pc = no,
+            },
+            // the counters of the loops inside the group are not used after it
+            code::Atomic(body) => match run(code, slots.clone(), counters.clone(), body, string,
+                                            pos, false) {
+                Some((new_slots, end)) => {
+                    // keep the captures of the group, but never backtrack into it
+                    for i in range(0, new_slots.len()) {
+                        if new_slots[i] != slots[i] {
+                            stack.push(Restore(i, slots[i]));
+                            slots[i] = new_slots[i];
+                        }
+                    }
+                    pc += 1;
+                    pos = end;
+                },
+                None => return None,
+            },
+            code::Save(slot) => {
+                stack.push(Restore(slot, slots[slot]));
+                slots[slot] = Some(pos);
+                pc += 1;
+            },
+            code::Backref(idx, fold) => match (slots[2 * idx], slots[2 * idx + 1]) {
+                (Some(s), Some(e)) => match match_backref(string, pos, s, e, fold) {
+                    Some(end) => {
+                        pc += 1;
+                        pos = end;
+                    },
+                    None => return None,
+                },
+                // a group that did not take part in the match never matches
+                _ => return None,
+            },
+            code::Reset(counter) => {
+                stack.push(RestoreCounter(counter, counters[counter]));
+                counters[counter] = 0;
+                pc += 1;
+            },
+            code::Increment(counter) => {
+                stack.push(RestoreCounter(counter, counters[counter]));
+                counters[counter] += 1;
+                pc += 1;
+            },
+            code::CountBelow(counter, n) => {
+                if counters[counter] >= n {
+                    return None;
+                }
+                pc += 1;
+            },
+            code::CountAtLeast(counter, n) => {
+                if counters[counter] < n {
+                    return None;
+                }
+                pc += 1;
+            },
+            code::Succeed => return Some(pos),
+        }
+    }
+}
+
+/// Offset right after the text a backreference matches at `pos`, being the
+/// text between `s` and `e` again, compared char by char if `fold` as
+/// the case folded may take a different number of bytes
+fn match_backref(string: &str, pos: uint, s: uint, e: uint, fold: bool) -> Option<uint> {
+    if !fold {
+        return if string.slice_from(pos).starts_with(string.slice(s, e)) {
+            Some(pos + e - s)
+        } else {
+            None
+        };
+    }
+    let (mut pos, mut s) = (pos, s);
+    while s < e {
+        if pos == string.len() {
+            return None;
+        }
+        let CharRange {ch: a, next: after_a} = string.char_range_at(s);
+        let CharRange {ch: b, next: after_b} = string.char_range_at(pos);
+        if !inst::same_ignoring_case(a, b) {
+            return None;
+        }
+        s = after_a;
+        pos = after_b;
+    }
+    Some(pos)
+}
+
+/// Whether `pc` starts the body of a loop counting its iterations
+fn counted(code: &code::Code, pc: uint) -> bool {
+    match code.op(pc) {
+        code::CountBelow(*) => true,
+        _ => false,
+    }
+}
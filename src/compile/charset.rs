@@ -0,0 +1,187 @@
+use std::cmp;
+
+use compile::unicode;
+
+/// Largest code point
+pub static MAX_CHAR: char = '\U0010ffff';
+
+/// Set of chars kept as sorted, disjoint and non-adjacent inclusive
+/// ranges, from which classes are built
+#[deriving(Clone)]
+pub struct CharSet {
+    priv ranges: ~[(char, char)],
+}
+
+impl CharSet {
+    /// Empty set
+    pub fn new() -> CharSet {
+        CharSet {
+            ranges: ~[],
+        }
+    }
+
+    /// Set of the chars falling in any of the inclusive ranges, which may
+    /// come in any order and overlap
+    pub fn from_ranges(ranges: &[(char, char)]) -> CharSet {
+        let mut set = CharSet::new();
+        for &(lo, hi) in ranges.iter() {
+            set.insert(lo, hi);
+        }
+        set
+    }
+
+    /// Add the chars from `lo` to `hi` inclusive
+    pub fn insert(&mut self, lo: char, hi: char) {
+        // first range ending no earlier than right before `lo`
+        let (mut low, mut high) = (0, self.ranges.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let (_, end) = self.ranges[mid];
+            if end as uint + 1 < lo as uint {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        // absorb the ranges the new one overlaps or touches
+        let (mut lo, mut hi) = (lo, hi);
+        while low < self.ranges.len() {
+            let (start, end) = self.ranges[low];
+            if start as uint > hi as uint + 1 {
+                break;
+            }
+            lo = cmp::min(lo, start);
+            hi = cmp::max(hi, end);
+            self.ranges.remove(low);
+        }
+        self.ranges.insert(low, (lo, hi));
+    }
+
+    /// Chars in either set
+    pub fn union(&self, other: &CharSet) -> CharSet {
+        let a: &[(char, char)] = self.ranges;
+        let b: &[(char, char)] = other.ranges;
+        let mut result: ~[(char, char)] = ~[];
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            let next = if j == b.len() || (i < a.len() && a[i] < b[j]) {
+                i += 1;
+                a[i - 1]
+            } else {
+                j += 1;
+                b[j - 1]
+            };
+            let (lo, hi) = next;
+            match result.last_opt() {
+                Some(&(_, last)) if lo as u32 <= last as u32 + 1 => {
+                    let idx = result.len() - 1;
+                    let (first, _) = result[idx];
+                    result[idx] = (first, cmp::max(hi, last));
+                },
+                _ => result.push(next),
+            }
+        }
+        CharSet {
+            ranges: result,
+        }
+    }
+
+    /// Chars in both sets
+    pub fn intersect(&self, other: &CharSet) -> CharSet {
+        let a: &[(char, char)] = self.ranges;
+        let b: &[(char, char)] = other.ranges;
+        let mut result = ~[];
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            let ((a_lo, a_hi), (b_lo, b_hi)) = (a[i], b[j]);
+            let (lo, hi) = (cmp::max(a_lo, b_lo), cmp::min(a_hi, b_hi));
+            if lo <= hi {
+                result.push((lo, hi));
+            }
+            // the range ending first cannot overlap anything further
+            if a_hi < b_hi {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        CharSet {
+            ranges: result,
+        }
+    }
+
+    /// Chars outside the set
+    pub fn negate(&self) -> CharSet {
+        let mut result = ~[];
+        let mut next = 0u32;
+        for &(lo, hi) in self.ranges.iter() {
+            if lo as u32 > next {
+                result.push((next as char, (lo as u32 - 1) as char));
+            }
+            next = hi as u32 + 1;
+        }
+        if next <= MAX_CHAR as u32 {
+            result.push((next as char, MAX_CHAR));
+        }
+        CharSet {
+            ranges: result,
+        }
+    }
+
+    /// The set with every char that simple case folding makes the same as
+    /// one in it added, as `(?i)` matches in Unicode mode, so that 'é'
+    /// brings in 'É' and 'k' the Kelvin sign
+    pub fn fold_case(&self) -> CharSet {
+        let mut variants = ~[];
+        for &(lo, hi) in self.ranges.iter() {
+            for &(_, other) in unicode::case_pairs(lo, hi).iter() {
+                variants.push((other, other));
+            }
+        }
+        self.union(&CharSet::from_ranges(variants))
+    }
+
+    /// The set with the other case of every ASCII letter in it added, as
+    /// `(?i)` matches outside of Unicode mode
+    pub fn fold_ascii_case(&self) -> CharSet {
+        let mut folded = self.clone();
+        for &(lo, hi) in self.ranges.iter() {
+            let (l, h) = (cmp::max(lo, 'a'), cmp::min(hi, 'z'));
+            if l <= h {
+                folded.insert((l as u8 - 32) as char, (h as u8 - 32) as char);
+            }
+            let (l, h) = (cmp::max(lo, 'A'), cmp::min(hi, 'Z'));
+            if l <= h {
+                folded.insert((l as u8 + 32) as char, (h as u8 + 32) as char);
+            }
+        }
+        folded
+    }
+
+    /// Whether `c` is in the set, found by binary search
+    pub fn contains(&self, c: char) -> bool {
+        let (mut low, mut high) = (0, self.ranges.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let (lo, hi) = self.ranges[mid];
+            if c < lo {
+                high = mid;
+            } else if c > hi {
+                low = mid + 1;
+            } else {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Sorted, disjoint and non-adjacent ranges making up the set
+    pub fn ranges<'a>(&'a self) -> &'a [(char, char)] {
+        let ranges: &'a [(char, char)] = self.ranges;
+        ranges
+    }
+}
@@ -0,0 +1,174 @@
+use compile::inst;
+
+/// Largest operand the first word of an instruction can hold, and so the
+/// largest address, slot or group number, and the largest repetition count
+pub static MAX_OPERAND: uint = (1 << 24) - 1;
+
+// opcodes, kept in the top byte of the first word of an instruction
+static MATCH: u32 = 0;
+static JMP: u32 = 1;
+static SUCCEED: u32 = 2;
+static SPLIT: u32 = 3;
+static ASSERT: u32 = 4;
+static SAVE: u32 = 5;
+static LOOK_AHEAD: u32 = 6;
+static NOT_LOOK_AHEAD: u32 = 7;
+static LOOK_BEHIND: u32 = 8;
+static NOT_LOOK_BEHIND: u32 = 9;
+static BACKREF: u32 = 10;
+static IF_GROUP: u32 = 11;
+static ATOMIC: u32 = 12;
+static RESET: u32 = 13;
+static INCREMENT: u32 = 14;
+static COUNT_BELOW: u32 = 15;
+static COUNT_AT_LEAST: u32 = 16;
+static BACKREF_FOLD: u32 = 17;
+
+/// Instruction decoded from a `Code`, borrowing its match from there;
+/// see `inst::Instruction` for what each one does
+pub enum Op<'self> {
+    Match(&'self inst::Match),
+    Jmp(uint),
+    Succeed,
+    Split(uint, uint),
+    Assert(inst::Assertion),
+    Save(uint),
+    LookAhead(uint, bool),
+    LookBehind(uint, bool, uint),
+    Backref(uint, bool),
+    IfGroup(uint, uint),
+    Atomic(uint),
+    Reset(uint),
+    Increment(uint),
+    CountBelow(uint, uint),
+    CountAtLeast(uint, uint),
+}
+
+/// Program encoded in two 32-bit words per instruction: the opcode in the
+/// top byte of the first word and an operand in the rest of it, then a
+/// second operand. Matches, which may hold a whole class, are kept apart
+/// and referred to by index.
+pub struct Code {
+    priv words: ~[u32],
+    priv matches: ~[inst::Match],
+}
+
+impl Code {
+    /// Encode `code`, in which no operand, be it an address, a group
+    /// number, a count or a width, may be larger than `MAX_OPERAND`
+    pub fn new(code: &[inst::Instruction]) -> Code {
+        let mut words = ~[];
+        let mut matches = ~[];
+        for instruction in code.iter() {
+            let (opcode, a, b) = match *instruction {
+                inst::Match(ref m) => {
+                    matches.push(m.clone());
+                    (MATCH, matches.len() - 1, 0)
+                },
+                inst::Jmp(a) => (JMP, a, 0),
+                inst::Succeed => (SUCCEED, 0, 0),
+                inst::Split(a, b) => (SPLIT, a, b),
+                inst::Assert(ref a) => (ASSERT, encode_assertion(a), 0),
+                inst::Save(slot) => (SAVE, slot, 0),
+                inst::LookAhead(body, negated) =>
+                    (if negated { NOT_LOOK_AHEAD } else { LOOK_AHEAD }, body, 0),
+                inst::LookBehind(body, negated, width) =>
+                    (if negated { NOT_LOOK_BEHIND } else { LOOK_BEHIND }, body, width),
+                inst::Backref(group, fold) => (if fold { BACKREF_FOLD } else { BACKREF }, group, 0),
+                inst::IfGroup(group, no) => (IF_GROUP, group, no),
+                inst::Atomic(body) => (ATOMIC, body, 0),
+                inst::Reset(counter) => (RESET, counter, 0),
+                inst::Increment(counter) => (INCREMENT, counter, 0),
+                inst::CountBelow(counter, n) => (COUNT_BELOW, counter, n),
+                inst::CountAtLeast(counter, n) => (COUNT_AT_LEAST, counter, n),
+            };
+            assert!(a <= MAX_OPERAND && b <= MAX_OPERAND);
+            words.push(opcode << 24 | a as u32);
+            words.push(b as u32);
+        }
+        Code {
+            words: words,
+            matches: matches,
+        }
+    }
+
+    /// Number of instructions
+    pub fn len(&self) -> uint {
+        self.words.len() / 2
+    }
+
+    /// Decode the instruction at address `pc`, as the engines do
+    pub fn op<'a>(&'a self, pc: uint) -> Op<'a> {
+        let word = self.words[2 * pc];
+        let a = (word & 0xffffff) as uint;
+        let b = self.words[2 * pc + 1] as uint;
+        match word >> 24 {
+            MATCH => Match(&self.matches[a]),
+            JMP => Jmp(a),
+            SUCCEED => Succeed,
+            SPLIT => Split(a, b),
+            ASSERT => Assert(decode_assertion(a)),
+            SAVE => Save(a),
+            LOOK_AHEAD => LookAhead(a, false),
+            NOT_LOOK_AHEAD => LookAhead(a, true),
+            LOOK_BEHIND => LookBehind(a, false, b),
+            NOT_LOOK_BEHIND => LookBehind(a, true, b),
+            BACKREF => Backref(a, false),
+            BACKREF_FOLD => Backref(a, true),
+            IF_GROUP => IfGroup(a, b),
+            ATOMIC => Atomic(a),
+            RESET => Reset(a),
+            INCREMENT => Increment(a),
+            COUNT_BELOW => CountBelow(a, b),
+            COUNT_AT_LEAST => CountAtLeast(a, b),
+            _ => fail!("Invalid opcode."),
+        }
+    }
+
+    /// Decode the instruction at address `pc` into an `inst::Instruction`
+    /// of its own, copying its match
+    pub fn get(&self, pc: uint) -> inst::Instruction {
+        match self.op(pc) {
+            Match(m) => inst::Match(m.clone()),
+            Jmp(a) => inst::Jmp(a),
+            Succeed => inst::Succeed,
+            Split(a, b) => inst::Split(a, b),
+            Assert(a) => inst::Assert(a),
+            Save(slot) => inst::Save(slot),
+            LookAhead(body, negated) => inst::LookAhead(body, negated),
+            LookBehind(body, negated, width) => inst::LookBehind(body, negated, width),
+            Backref(group, fold) => inst::Backref(group, fold),
+            IfGroup(group, no) => inst::IfGroup(group, no),
+            Atomic(body) => inst::Atomic(body),
+            Reset(counter) => inst::Reset(counter),
+            Increment(counter) => inst::Increment(counter),
+            CountBelow(counter, n) => inst::CountBelow(counter, n),
+            CountAtLeast(counter, n) => inst::CountAtLeast(counter, n),
+        }
+    }
+}
+
+fn encode_assertion(assertion: &inst::Assertion) -> uint {
+    match *assertion {
+        inst::Begin => 0,
+        inst::End => 1,
+        inst::EndBeforeNewline => 2,
+        inst::BeginLine => 3,
+        inst::EndLine => 4,
+        inst::WordBoundary => 5,
+        inst::NotWordBoundary => 6,
+    }
+}
+
+fn decode_assertion(n: uint) -> inst::Assertion {
+    match n {
+        0 => inst::Begin,
+        1 => inst::End,
+        2 => inst::EndBeforeNewline,
+        3 => inst::BeginLine,
+        4 => inst::EndLine,
+        5 => inst::WordBoundary,
+        6 => inst::NotWordBoundary,
+        _ => fail!("Invalid assertion."),
+    }
+}
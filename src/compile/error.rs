@@ -0,0 +1,159 @@
+use std::cmp;
+
+/// What is wrong with a pattern
+#[deriving(Clone, Eq)]
+pub enum ErrorKind {
+    /// the pattern ends in the middle of a construct, such as an open group
+    UnexpectedEnd,
+    /// the char cannot appear where it does, such as a quantifier with
+    /// nothing to repeat
+    UnexpectedChar(char),
+    /// a ')' closing no group
+    UnbalancedParen,
+    /// a malformed `\x`, `\u` or `\c` escape
+    InvalidEscape,
+    /// an octal escape while `Options::octal` is off
+    OctalDisabled,
+    /// a `\p` or `\X` escape while `Options::unicode` is off
+    UnicodeDisabled,
+    /// an escape of a char above `\xFF`, or a char beyond ASCII in a
+    /// class, while `Options::unicode` is off
+    NotAByte,
+    UnknownProperty(~str),
+    UnknownPosixClass(~str),
+    /// a class range whose ends are out of order
+    InvalidClassRange(char, char),
+    /// a '{' not followed by a valid `{n}`, `{n,}` or `{n,m}`
+    InvalidRepetition,
+    /// a counted repetition whose maximum is below its minimum
+    InvalidRepetitionRange(uint, uint),
+    /// a backreference to a group that is not open or closed before it
+    InvalidBackreference,
+    UnknownGroupExtension(char),
+    InvalidGroupName,
+    DuplicateGroupName(~str),
+    UnknownGroupName(~str),
+    UnknownFlag(char),
+    /// a '-' in a flag group followed by no flag
+    MissingFlags,
+    /// a lookbehind whose body can match strings of different lengths
+    VariableLookbehind,
+    /// a conditional group whose condition names no group
+    InvalidCondition,
+    /// a conditional group with more than a yes and a no branch
+    TooManyBranches,
+    /// a group nested deeper than `Options::nest_limit`
+    NestTooDeep,
+    /// a program of that many instructions, more than `Options::size_limit`
+    ProgramTooBig(uint),
+    /// the pattern needs backtracking while `Options::backtrack` is off
+    BacktrackingDisabled,
+    /// the pattern uses a feature that a DFA cannot run, such as a lookaround
+    UnsupportedByDfa,
+    /// determinizing the pattern needs more states than
+    /// `Options::dfa_state_limit`
+    TooManyStates(uint),
+    /// the pattern needs backtracking while `Options::longest` is on
+    LongestNeedsNfa,
+    /// the pattern needs backtracking while `Options::earliest` is on
+    EarliestNeedsNfa,
+    /// the pattern uses a feature that a stream cannot run, such as a
+    /// lookaround, see `re::Regexp::stream`
+    UnsupportedByStream,
+}
+
+/// Error returned when compiling a pattern fails
+#[deriving(Clone, Eq)]
+pub struct ParseError {
+    kind: ErrorKind,
+    /// byte offset in the pattern of the construct at fault, or `None`
+    /// if the error is about the pattern as a whole, such as the size of
+    /// its program, or if there is no pattern, as for `expr::Expr`
+    position: Option<uint>,
+}
+
+impl ParseError {
+    pub fn new(kind: ErrorKind, position: uint) -> ParseError {
+        ParseError {
+            kind: kind,
+            position: Some(position),
+        }
+    }
+
+    /// Error that no single construct of the pattern is at fault for
+    pub fn without_position(kind: ErrorKind) -> ParseError {
+        ParseError {
+            kind: kind,
+            position: None,
+        }
+    }
+
+    /// Render the error as its message, then the line of `pattern` at
+    /// fault with a '^' under the offending char:
+    ///
+    ///     Unbalanced ')' at 4
+    ///     (ab))
+    ///         ^
+    ///
+    /// or, for an error without a position, the whole pattern and no '^'
+    pub fn diagnostic(&self, pattern: &str) -> ~str {
+        let position = match self.position {
+            Some(position) => cmp::min(position, pattern.len()),
+            None => return fmt!("%s\n%s", self.to_str(), pattern),
+        };
+        let line_start = pattern.slice_to(position).rfind('\n').map_default(0, |&i| i + 1);
+        let line_end = pattern.slice_from(position).find('\n')
+                              .map_default(pattern.len(), |&i| position + i);
+        let mut caret = ~"";
+        for c in pattern.slice(line_start, position).iter() {
+            // keep tabs so the caret lines up however they are displayed
+            caret.push_char(if c == '\t' { '\t' } else { ' ' });
+        }
+        caret.push_char('^');
+        fmt!("%s\n%s\n%s", self.to_str(), pattern.slice(line_start, line_end), caret)
+    }
+}
+
+impl ToStr for ParseError {
+    fn to_str(&self) -> ~str {
+        let what = match self.kind {
+            UnexpectedEnd => ~"Unexpected end of pattern",
+            UnexpectedChar(c) => fmt!("Unexpected char '%c'", c),
+            UnbalancedParen => ~"Unbalanced ')'",
+            InvalidEscape => ~"Invalid escape",
+            OctalDisabled => ~"Octal escapes are disabled",
+            UnicodeDisabled => ~"Unicode properties are disabled",
+            NotAByte => ~"Char that is not a byte outside of Unicode mode",
+            UnknownProperty(ref name) => fmt!("Unknown Unicode property '%s'", *name),
+            UnknownPosixClass(ref name) => fmt!("Unknown POSIX class '%s'", *name),
+            InvalidClassRange(lo, hi) => fmt!("Invalid range '%c-%c'", lo, hi),
+            InvalidRepetition => ~"Malformed repetition",
+            InvalidRepetitionRange(min, max) => fmt!("Invalid repetition {%u,%u}", min, max),
+            InvalidBackreference => ~"Invalid backreference",
+            UnknownGroupExtension(c) => fmt!("Unknown group extension '%c'", c),
+            InvalidGroupName => ~"Invalid group name",
+            DuplicateGroupName(ref name) => fmt!("Duplicate group name '%s'", *name),
+            UnknownGroupName(ref name) => fmt!("Unknown group name '%s'", *name),
+            UnknownFlag(c) => fmt!("Unknown flag '%c'", c),
+            MissingFlags => ~"Missing flags after '-'",
+            VariableLookbehind => ~"Lookbehind does not have a fixed width",
+            InvalidCondition => ~"Invalid condition",
+            TooManyBranches => ~"Too many branches in conditional",
+            NestTooDeep => ~"Groups nested too deeply",
+            ProgramTooBig(size) => fmt!("Program of %u instructions is too big", size),
+            BacktrackingDisabled =>
+                return ~"Pattern needs the backtracking engine, which is disabled.",
+            UnsupportedByDfa => return ~"Pattern uses features a DFA cannot run.",
+            UnsupportedByStream => return ~"Pattern uses features a stream cannot run.",
+            TooManyStates(limit) => return fmt!("DFA needs more than %u states.", limit),
+            LongestNeedsNfa =>
+                return ~"Pattern needs the backtracking engine, which cannot find longest matches.",
+            EarliestNeedsNfa =>
+                return ~"Pattern needs the backtracking engine, which cannot stop early.",
+        };
+        match self.position {
+            Some(position) => fmt!("%s at %u", what, position),
+            None => what,
+        }
+    }
+}
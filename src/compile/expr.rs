@@ -0,0 +1,319 @@
+use std::cmp;
+use std::hashmap::HashMap;
+use std::util;
+
+use compile;
+use compile::Options;
+use compile::code;
+use compile::error;
+use compile::error::ParseError;
+use compile::inst;
+use compile::parse;
+
+/// Span of the nodes built by an `Expr`, which come from no pattern text
+static NO_SPAN: parse::Span = (0, 0);
+
+/// Regular expression assembled from parts instead of parsed from a
+/// pattern, so that text taken from program data needs no escaping:
+///
+///     let version = Expr::literal("v").then(Expr::class('0', '9').plus().capture())
+///                                     .or(Expr::literal("latest"));
+///
+/// As with `re::RegexpBuilder`, every method takes the expression by value
+/// and returns the new one.
+pub struct Expr {
+    priv ast: ~[parse::Ast],
+    /// name of every capture group in the order they open, or `None` if it
+    /// has none; their indices are only known once the whole expression is
+    priv names: ~[Option<~str>],
+}
+
+impl Expr {
+    /// Match the empty string
+    pub fn empty() -> Expr {
+        Expr {
+            ast: ~[],
+            names: ~[],
+        }
+    }
+
+    /// Match `text` verbatim
+    pub fn literal(text: &str) -> Expr {
+        let mut ast = ~[];
+        for c in text.iter() {
+            ast.push(fragment(parse::Match(inst::Char(c))));
+        }
+        Expr {
+            ast: ast,
+            names: ~[],
+        }
+    }
+
+    /// Match any char from `lo` to `hi` inclusive; fails if `lo` comes
+    /// after `hi`, as `[z-a]` does not parse
+    pub fn class(lo: char, hi: char) -> Expr {
+        Expr::one_of([(lo, hi)])
+    }
+
+    /// Match any char falling in one of the inclusive ranges; fails if a
+    /// range ends before it starts
+    pub fn one_of(ranges: &[(char, char)]) -> Expr {
+        check_ranges(ranges);
+        Expr::single(parse::Match(inst::Class(ranges.to_owned(), false)))
+    }
+
+    /// Match any char outside all of the inclusive ranges; fails if a
+    /// range ends before it starts
+    pub fn none_of(ranges: &[(char, char)]) -> Expr {
+        check_ranges(ranges);
+        Expr::single(parse::Match(inst::Class(ranges.to_owned(), true)))
+    }
+
+    /// Match any char, '\n' included
+    pub fn any() -> Expr {
+        Expr::single(parse::Match(inst::Any))
+    }
+
+    /// Match the empty string where the assertion holds
+    pub fn assertion(assertion: inst::Assertion) -> Expr {
+        Expr::single(parse::Assert(assertion))
+    }
+
+    fn single(one: parse::One) -> Expr {
+        Expr {
+            ast: ~[fragment(one)],
+            names: ~[],
+        }
+    }
+
+    /// Match this expression followed by `other`
+    pub fn then(self, other: Expr) -> Expr {
+        let Expr { ast: ast, names: names } = self;
+        let Expr { ast: other_ast, names: other_names } = other;
+        let mut ast = ast;
+        let mut names = names;
+        ast.push_all_move(other_ast);
+        names.push_all_move(other_names);
+        Expr {
+            ast: ast,
+            names: names,
+        }
+    }
+
+    /// Match this expression or, if that fails, `other`
+    pub fn or(self, other: Expr) -> Expr {
+        let Expr { ast: ast, names: names } = self;
+        let Expr { ast: other_ast, names: other_names } = other;
+        let mut names = names;
+        names.push_all_move(other_names);
+        let mut alternatives = branches(ast);
+        alternatives.push_all_move(branches(other_ast));
+        Expr {
+            ast: ~[parse::Or(alternatives, NO_SPAN)],
+            names: names,
+        }
+    }
+
+    /// Group the expression without capturing, like `(?:...)`
+    pub fn group(self) -> Expr {
+        let Expr { ast: ast, names: names } = self;
+        Expr {
+            ast: ~[fragment(parse::Group(None, ast, NO_SPAN))],
+            names: names,
+        }
+    }
+
+    /// Capture what the expression matches in a group numbered, as in a
+    /// pattern, by the order in which groups open
+    pub fn capture(self) -> Expr {
+        self.capture_as(None)
+    }
+
+    /// Capture what the expression matches in a group that can also be
+    /// looked up by `name`
+    pub fn named(self, name: &str) -> Expr {
+        self.capture_as(Some(name.to_owned()))
+    }
+
+    fn capture_as(self, name: Option<~str>) -> Expr {
+        let Expr { ast: ast, names: names } = self;
+        let mut all = ~[name];
+        all.push_all_move(names);
+        Expr {
+            // the index is set by `number` when compiling
+            ast: ~[fragment(parse::Group(Some(0), ast, NO_SPAN))],
+            names: all,
+        }
+    }
+
+    /// Match the expression zero or one time
+    pub fn optional(self) -> Expr {
+        self.quantify(parse::QMark)
+    }
+
+    /// Match the expression any number of times
+    pub fn star(self) -> Expr {
+        self.quantify(parse::Star)
+    }
+
+    /// Match the expression at least once
+    pub fn plus(self) -> Expr {
+        self.quantify(parse::Plus)
+    }
+
+    /// Match the expression from `min` to `max` times, or at least `min`
+    /// times if `max` is `None`. Fails if `max` is below `min`, or if
+    /// either is above `code::MAX_OPERAND`.
+    pub fn repeat(self, min: uint, max: Option<uint>) -> Expr {
+        if max.map_default(false, |&max| max < min) {
+            fail!(fmt!("Invalid repetition {%u,%u}", min, max.unwrap()));
+        }
+        if cmp::max(min, max.map_default(0, |&max| max)) > code::MAX_OPERAND {
+            fail!(fmt!("Repetition count above %u", code::MAX_OPERAND));
+        }
+        self.quantify(parse::Range(min, max))
+    }
+
+    fn quantify(self, modifier: parse::Modifier) -> Expr {
+        let Expr { ast: ast, names: names } = self;
+        let alone = ast.len() == 1 && match ast[0] {
+            parse::Fragment(_, parse::No, _, _) => true,
+            _ => false,
+        };
+        let one = if alone {
+            let mut ast = ast;
+            match ast.pop() {
+                parse::Fragment(one, _, _, _) => one,
+                _ => fail!("Expected a fragment."),
+            }
+        } else {
+            parse::Group(None, ast, NO_SPAN)
+        };
+        let repeatable = match one {
+            parse::Assert(_) | parse::LookAhead(*) | parse::LookBehind(*) => false,
+            parse::Group(None, ref body, _) => !body.is_empty(),
+            _ => true,
+        };
+        if repeatable {
+            return Expr {
+                ast: ~[parse::Fragment(one, modifier, parse::Greedy, NO_SPAN)],
+                names: names,
+            };
+        }
+        // repeating what matches no chars can at most make it optional
+        match modifier {
+            parse::QMark | parse::Star | parse::Range(0, _) => Expr::empty(),
+            _ => Expr {
+                ast: ~[fragment(one)],
+                names: names,
+            },
+        }
+    }
+
+    /// Make the quantifier just applied prefer fewer repetitions
+    pub fn lazy(self) -> Expr {
+        self.greed(parse::Lazy)
+    }
+
+    /// Make the quantifier just applied never give back what it matched
+    pub fn possessive(self) -> Expr {
+        self.greed(parse::Possessive)
+    }
+
+    fn greed(self, greed: parse::Greed) -> Expr {
+        let quantified = self.ast.len() == 1 && match self.ast[0] {
+            parse::Fragment(_, parse::No, _, _) => false,
+            parse::Fragment(*) => true,
+            _ => false,
+        };
+        if !quantified {
+            return self;
+        }
+        let Expr { ast: ast, names: names } = self;
+        let mut ast = ast;
+        match ast.pop() {
+            parse::Fragment(one, modifier, _, span) => Expr {
+                ast: ~[parse::Fragment(one, modifier, greed, span)],
+                names: names,
+            },
+            _ => fail!("Expected a quantified fragment."),
+        }
+    }
+
+    /// Compile the expression; of the options, only the ones listed by
+    /// `compile::compile_ast` apply. Group names that are empty, are not
+    /// made of word chars, or are used twice are reported without a position.
+    pub fn compile(self, options: &Options) -> Result<compile::CompiledRegexp, ParseError> {
+        let Expr { ast: ast, names: group_names } = self;
+        let mut ast = ast;
+        let mut count = 0;
+        number(&mut ast, &mut count);
+        let mut names = HashMap::new();
+        for (i, name) in group_names.iter().enumerate() {
+            match *name {
+                Some(ref name) => {
+                    if name.is_empty() || !name.iter().all(|c| inst::is_word_char(c)) {
+                        return Err(ParseError::without_position(error::InvalidGroupName));
+                    }
+                    if names.contains_key(name) {
+                        let kind = error::DuplicateGroupName(name.clone());
+                        return Err(ParseError::without_position(kind));
+                    }
+                    names.insert(name.clone(), i + 1);
+                },
+                None => (),
+            }
+        }
+        compile::compile_ast(ast, names, options)
+    }
+}
+
+impl ToStr for Expr {
+    /// Write the expression as an equivalent pattern, without group names
+    fn to_str(&self) -> ~str {
+        parse::to_pattern(self.ast)
+    }
+}
+
+fn fragment(one: parse::One) -> parse::Ast {
+    parse::Fragment(one, parse::No, parse::Greedy, NO_SPAN)
+}
+
+/// Alternatives making up `ast`, which are its own branches if it is a
+/// lone alternation, so that chained alternatives do not nest
+fn branches(ast: ~[parse::Ast]) -> ~[~[parse::Ast]] {
+    let alone = ast.len() == 1 && match ast[0] {
+        parse::Or(*) => true,
+        _ => false,
+    };
+    if !alone {
+        return ~[ast];
+    }
+    let mut ast = ast;
+    match ast.pop() {
+        parse::Or(branches, _) => branches,
+        _ => fail!("Expected an alternation."),
+    }
+}
+
+/// Fail on a range ending before it starts, which no class may hold
+fn check_ranges(ranges: &[(char, char)]) {
+    for &(lo, hi) in ranges.iter() {
+        if lo > hi {
+            fail!(fmt!("Invalid class range %s-%s", inst::escape_char(lo), inst::escape_char(hi)));
+        }
+    }
+}
+
+/// Number the capture groups of `ast` in the order they open, following
+/// the `count` groups before it
+fn number(ast: &mut ~[parse::Ast], count: &mut uint) {
+    let old = util::replace(ast, ~[]);
+    *ast = parse::rebuild(old, |node| match *node {
+        parse::Fragment(parse::Group(ref mut idx, _, _), _, _, _) => if idx.is_some() {
+            *count += 1;
+            *idx = Some(*count);
+        },
+        _ => (),
+    }, |node| node);
+}
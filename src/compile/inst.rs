@@ -1,3 +1,7 @@
+use std::str;
+
+use compile::unicode;
+
 /// All the instructions that the virtual machine understands
 #[deriving(Clone)]
 pub enum Instruction {
@@ -7,8 +11,67 @@ pub enum Instruction {
     Jmp(uint),
     /// successful match
     Succeed,
-    /// split current virtual thread into two
+    /// split current virtual thread into two,
+    /// the first one having the higher priority
     Split(uint, uint),
+    /// zero-width test on the current position
+    Assert(Assertion),
+    /// record the current position in a capture slot; group `i`
+    /// spans slots `2 * i` and `2 * i + 1`, group 0 being the whole match
+    Save(uint),
+    /// zero-width test that the sub-program at the address, which ends
+    /// with its own `Succeed`, matches at the current position (or does
+    /// not, if the flag is set); groups inside it do not capture
+    LookAhead(uint, bool),
+    /// like `LookAhead`, but the sub-program matches exactly the given
+    /// number of chars and has to end at the current position
+    LookBehind(uint, bool, uint),
+    /// match the text last captured by a group, ignoring case if the
+    /// flag is set; only supported by the backtracking engine
+    Backref(uint, bool),
+    /// continue if the group took part in the match so far, otherwise
+    /// jump to the address; only supported by the backtracking engine
+    IfGroup(uint, uint),
+    /// run the sub-program at the address, which ends with its own
+    /// `Succeed`, and continue with its first match only, never
+    /// backtracking into it; only supported by the backtracking engine
+    Atomic(uint),
+    /// set a loop counter of the thread to zero
+    Reset(uint),
+    /// add one to a loop counter
+    Increment(uint),
+    /// continue if the loop counter is below the number, otherwise fail
+    CountBelow(uint, uint),
+    /// continue if the loop counter is at least the number, otherwise fail
+    CountAtLeast(uint, uint),
+}
+
+impl ToStr for Instruction {
+    /// Disassemble the instruction, writing addresses as in
+    /// `CompiledRegexp::to_str`
+    fn to_str(&self) -> ~str {
+        match *self {
+            Match(ref m) => m.to_str(),
+            Jmp(a) => fmt!("Jmp -> %04u", a),
+            Succeed => ~"Succeed",
+            Split(a, b) => fmt!("Split -> %04u, %04u", a, b),
+            Assert(ref a) => fmt!("Assert %s", a.to_str()),
+            Save(slot) => fmt!("Save %u", slot),
+            LookAhead(body, negated) =>
+                fmt!("%s -> %04u", if negated { "NotLookAhead" } else { "LookAhead" }, body),
+            LookBehind(body, negated, width) =>
+                fmt!("%s %u -> %04u", if negated { "NotLookBehind" } else { "LookBehind" },
+                     width, body),
+            Backref(group, fold) =>
+                fmt!("%s %u", if fold { "BackrefFold" } else { "Backref" }, group),
+            IfGroup(group, no) => fmt!("IfGroup %u else -> %04u", group, no),
+            Atomic(body) => fmt!("Atomic -> %04u", body),
+            Reset(counter) => fmt!("Reset %u", counter),
+            Increment(counter) => fmt!("Increment %u", counter),
+            CountBelow(counter, n) => fmt!("CountBelow %u %u", counter, n),
+            CountAtLeast(counter, n) => fmt!("CountAtLeast %u %u", counter, n),
+        }
+    }
 }
 
 /// Instructions denoting simple matches
@@ -16,6 +79,167 @@ pub enum Instruction {
 pub enum Match {
     /// match one character
     Char(char),
-    /// match any char
+    /// match any char except '\n', as in Perl
     Dot,
+    /// match any char, used for `.` in dot-all mode
+    Any,
+    /// match any char falling in one of the inclusive ranges,
+    /// or outside all of them if the flag is set
+    Class(~[(char, char)], bool),
+    /// match any char falling in one of the sorted, disjoint inclusive
+    /// ranges, found by binary search; classes compile to this
+    Ranges(~[(char, char)]),
+}
+
+impl Match {
+    /// Check whether a character is accepted by this match
+    pub fn matches(&self, c: char) -> bool {
+        match *self {
+            Char(ch) => ch == c,
+            Dot => c != '\n',
+            Any => true,
+            Class(ref ranges, negated) =>
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated,
+            Ranges(ref ranges) => {
+                let (mut low, mut high) = (0, ranges.len());
+                while low < high {
+                    let mid = (low + high) / 2;
+                    let (lo, hi) = ranges[mid];
+                    if c < lo {
+                        high = mid;
+                    } else if c > hi {
+                        low = mid + 1;
+                    } else {
+                        return true;
+                    }
+                }
+                false
+            },
+        }
+    }
+}
+
+impl ToStr for Match {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Char(c) => fmt!("Char %s", escape_char(c)),
+            Dot => ~"Dot",
+            Any => ~"Any",
+            Class(ref ranges, negated) => fmt!("Class %s", class_to_str(*ranges, negated)),
+            Ranges(ref ranges) => fmt!("Ranges %s", class_to_str(*ranges, false)),
+        }
+    }
+}
+
+/// Write `ranges` as a bracketed class, as in a pattern
+pub fn class_to_str(ranges: &[(char, char)], negated: bool) -> ~str {
+    let mut class = if negated { ~"[^" } else { ~"[" };
+    for &(lo, hi) in ranges.iter() {
+        class.push_str(escape_char(lo));
+        if hi != lo {
+            class.push_char('-');
+            class.push_str(escape_char(hi));
+        }
+    }
+    class.push_char(']');
+    class
+}
+
+/// Zero-width assertions, checked without consuming input
+#[deriving(Clone)]
+pub enum Assertion {
+    /// start of input
+    Begin,
+    /// end of input
+    End,
+    /// end of input, or right before a '\n' ending it
+    EndBeforeNewline,
+    /// start of input or right after a '\n'
+    BeginLine,
+    /// end of input or right before a '\n'
+    EndLine,
+    /// between a word char and a non-word char (or either end of input)
+    WordBoundary,
+    /// anywhere `WordBoundary` does not hold
+    NotWordBoundary,
+}
+
+impl Assertion {
+    /// Whether the assertion holding at byte offset `pos` of `string`
+    /// depends on the text that may follow `string`
+    pub fn needs_more(&self, string: &str, pos: uint) -> bool {
+        match *self {
+            Begin | BeginLine => false,
+            EndBeforeNewline =>
+                pos == string.len() || (pos + 1 == string.len() && string[pos] == '\n' as u8),
+            End | EndLine | WordBoundary | NotWordBoundary => pos == string.len(),
+        }
+    }
+
+    /// Check whether the assertion holds at byte offset `pos` of `string`
+    pub fn holds(&self, string: &str, pos: uint) -> bool {
+        match *self {
+            Begin => pos == 0,
+            End => pos == string.len(),
+            EndBeforeNewline =>
+                pos == string.len() || (pos + 1 == string.len() && string[pos] == '\n' as u8),
+            BeginLine => pos == 0 || string[pos - 1] == '\n' as u8,
+            EndLine => pos == string.len() || string[pos] == '\n' as u8,
+            WordBoundary => is_word_boundary(string, pos),
+            NotWordBoundary => !is_word_boundary(string, pos),
+        }
+    }
+}
+
+impl ToStr for Assertion {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Begin => ~"Begin",
+            End => ~"End",
+            EndBeforeNewline => ~"EndBeforeNewline",
+            BeginLine => ~"BeginLine",
+            EndLine => ~"EndLine",
+            WordBoundary => ~"WordBoundary",
+            NotWordBoundary => ~"NotWordBoundary",
+        }
+    }
+}
+
+/// Byte offset `n` chars before `pos` in `string`, if there are enough
+pub fn rewind(string: &str, pos: uint, n: uint) -> Option<uint> {
+    let mut pos = pos;
+    for _ in range(0, n) {
+        if pos == 0 {
+            return None;
+        }
+        pos = string.char_range_at_reverse(pos).next;
+    }
+    Some(pos)
+}
+
+/// Word chars as understood by `\w` and `\b`
+pub fn is_word_char(c: char) -> bool {
+    (c >= '0' && c <= '9') || (c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || c == '_'
+}
+
+/// Whether `a` and `b` are the same char once their case is folded, as
+/// backreferences compare them under `(?i)`
+pub fn same_ignoring_case(a: char, b: char) -> bool {
+    a == b || unicode::case_pairs(a, a).iter().any(|&(_, other)| other == b)
+}
+
+fn is_word_boundary(string: &str, pos: uint) -> bool {
+    let before = pos > 0 && is_word_char(string.char_range_at_reverse(pos).ch);
+    let after = pos < string.len() && is_word_char(string.char_range_at(pos).ch);
+    before != after
+}
+
+/// Write `c` as itself if it is printable ASCII with no special meaning
+/// in a class, or as a `\x{...}` escape otherwise
+pub fn escape_char(c: char) -> ~str {
+    if c > ' ' && c <= '~' && c != '\\' && c != '[' && c != ']' && c != '-' && c != '^' {
+        str::from_char(c)
+    } else {
+        fmt!("\\x{%x}", c as uint)
+    }
 }
@@ -1,102 +1,696 @@
+use std::cmp;
+use std::hashmap::HashMap;
+use std::uint;
+use std::util;
 use std::vec;
 
+pub mod charset;
+pub mod code;
+pub mod error;
+pub mod expr;
 pub mod inst;
-mod parse;
+mod optimize;
+pub mod parse;
+mod unicode;
+
+/// most literals `CompiledRegexp::literal_prefixes` collects
+static MAX_PREFIXES: uint = 64;
+
+/// Options controlling compilation
+pub struct Options {
+    /// allow features like backreferences that need the backtracking
+    /// engine; disable to guarantee matching in linear time
+    backtrack: bool,
+    /// match letters regardless of case, like starting the pattern with `(?i)`
+    case_insensitive: bool,
+    /// let `^` and `$` match at line boundaries, like starting the pattern with `(?m)`
+    multi_line: bool,
+    /// let `.` match '\n' too, like starting the pattern with `(?s)`;
+    /// off by default, as in Perl
+    dot_all: bool,
+    /// ignore whitespace and `#` comments in the pattern, like starting
+    /// it with `(?x)`
+    extended: bool,
+    /// accept octal escapes like `\012`; `\1` to `\9` always are
+    /// backreferences
+    octal: bool,
+    /// accept Unicode property escapes like `\p{Greek}`, `\X` and escapes of
+    /// chars above `\xFF`, like starting the pattern with `(?u)`; off, as
+    /// with `(?-u)`, the pattern describes bytes, for searching them with
+    /// `re::Regexp::find_bytes`: escapes name bytes, classes and `.` only
+    /// match bytes, and a char beyond ASCII stands for its UTF-8 bytes
+    unicode: bool,
+    /// let classes nest, as in `[a-f[x-z]]`, and be combined with `&&`
+    /// and `--`, as in `[a-z&&[^aeiou]]` or `[\w--\d]`; off by default,
+    /// as it changes the meaning of `[`, `&&` and `--` inside brackets
+    class_sets: bool,
+    /// make quantifiers lazy by default, and greedy when followed by '?'
+    swap_greed: bool,
+    /// of the matches starting leftmost, prefer the longest, as POSIX
+    /// does, to the first one in priority order; the groups are those of
+    /// the first way in priority order to match it. Patterns needing the
+    /// backtracking engine then fail to compile.
+    longest: bool,
+    /// report the first match found, the one ending earliest, starting as
+    /// far left as it can, without going on to extend it: `a+` matches
+    /// only the first 'a' of "aaa". Takes over from `longest`, and
+    /// patterns needing the backtracking engine then fail to compile.
+    earliest: bool,
+    /// most groups that may nest inside one another; the parser counts
+    /// parentheses and the classes nested with `class_sets`, `compile_ast`
+    /// also the groups escapes like `\X` stand for. Parsing and compiling
+    /// do not recurse into groups, but `parse::walk` does.
+    nest_limit: uint,
+    /// largest count up to which counted repetitions are unrolled into
+    /// copies of the repeated item; above it, as in `a{1,1000}`, they
+    /// compile to a single copy in a loop that counts its iterations
+    unroll_limit: uint,
+    /// most instructions the compiled program may have, so that patterns
+    /// like `(a{1000}){1000}` fail to compile rather than exhaust memory
+    size_limit: uint,
+    /// most states a DFA built ahead of time by `dfa::Dfa` may have
+    dfa_state_limit: uint,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            backtrack: true,
+            case_insensitive: false,
+            multi_line: false,
+            dot_all: false,
+            extended: false,
+            octal: true,
+            unicode: true,
+            class_sets: false,
+            swap_greed: false,
+            longest: false,
+            earliest: false,
+            nest_limit: 250,
+            unroll_limit: 32,
+            size_limit: 100000,
+            dfa_state_limit: 10000,
+        }
+    }
+}
 
 /// Compiled version of a regular expression,
 /// to be executed by a virtual machine
-pub type CompiledRegexp = ~[inst::Instruction];
+pub struct CompiledRegexp {
+    code: code::Code,
+    /// index of every named capture group
+    names: HashMap<~str, uint>,
+    /// whether to find leftmost-longest matches, see `Options::longest`
+    longest: bool,
+    /// whether to stop at the first match found, see `Options::earliest`
+    earliest: bool,
+}
 
-pub fn compile(pattern: &str) -> Result<CompiledRegexp, ~str> {
-    let mut parser = parse::Parser::new(pattern);
-    let mut compiler = Compiler::new();
+/// Compile `pattern` in two stages: `parse::Parser` turns it into an AST,
+/// from which `Compiler` generates the program run by `re::Regexp`
+pub fn compile(pattern: &str, options: &Options) -> Result<CompiledRegexp, error::ParseError> {
+    let mut parser = parse::Parser::new(pattern, options);
     match parser.parse() {
-        Ok(ast) => {
-            compiler.compile(ast);
-            match compiler {
-                Compiler(r) => Ok(r),
+        Ok(ast) => compile_ast(ast, parser.names, options),
+        Err(e) => Err(e),
+    }
+}
+
+/// Generate the program for an AST built by the parser or by `expr::Expr`,
+/// given the index of every named group, factoring alternatives with
+/// `optimize::factor` first and shrinking the program with
+/// `optimize::optimize` last. The options that change how a pattern reads
+/// have already been applied to the AST, so only `backtrack`, `swap_greed`,
+/// `longest`, `earliest`, `nest_limit`, `unroll_limit` and `size_limit` are
+/// left to act here.
+pub fn compile_ast(ast: ~[parse::Ast], names: HashMap<~str, uint>, options: &Options)
+                   -> Result<CompiledRegexp, error::ParseError> {
+    // the parser checks the nesting too, but not that of ASTs from `expr::Expr`
+    if depth(ast) > options.nest_limit {
+        parse::dismantle(ast);
+        return Err(error::ParseError::without_position(error::NestTooDeep));
+    }
+    let mut ast = ast;
+    optimize::factor(&mut ast);
+    // check before compiling, as counted repetitions can make the
+    // program much larger than the pattern
+    let size = size(ast, options);
+    if size > options.size_limit || size > code::MAX_OPERAND {
+        parse::dismantle(ast);
+        return Err(error::ParseError::without_position(error::ProgramTooBig(size)));
+    }
+    let mut compiler = Compiler::new(options);
+    compiler.compile(ast);
+    parse::dismantle(ast);
+    let program = CompiledRegexp {
+        code: code::Code::new(optimize::optimize(compiler.code)),
+        names: names,
+        longest: options.longest,
+        earliest: options.earliest,
+    };
+    if !options.backtrack && program.needs_backtracking() {
+        Err(error::ParseError::without_position(error::BacktrackingDisabled))
+    } else if options.longest && program.needs_backtracking() {
+        Err(error::ParseError::without_position(error::LongestNeedsNfa))
+    } else if options.earliest && program.needs_backtracking() {
+        Err(error::ParseError::without_position(error::EarliestNeedsNfa))
+    } else {
+        Ok(program)
+    }
+}
+
+impl ToStr for CompiledRegexp {
+    /// Disassemble the program, one numbered instruction per line:
+    ///
+    ///     0000: Save 0
+    ///     0001: Char a
+    ///     0002: Split -> 0001, 0003
+    ///     0003: Save 1
+    ///     0004: Succeed
+    fn to_str(&self) -> ~str {
+        let mut listing = ~"";
+        for pc in range(0, self.code.len()) {
+            listing.push_str(fmt!("%04u: %s\n", pc, self.code.get(pc).to_str()));
+        }
+        listing
+    }
+}
+
+impl CompiledRegexp {
+    /// Whether the program can only be run by the backtracking engine
+    pub fn needs_backtracking(&self) -> bool {
+        range(0, self.code.len()).any(|pc| match self.code.op(pc) {
+            code::Backref(*) | code::Atomic(_) | code::IfGroup(*) => true,
+            _ => false,
+        })
+    }
+
+    /// Program matching the reverse of what this one matches, for finding
+    /// where a match starts by reading the text backwards from where it
+    /// ends. Captures are dropped and priorities lost, so it is only fit
+    /// to tell where the longest match ends. `None` if the program has
+    /// instructions other than those `dfa::supports`, or if the reversed
+    /// one would have more instructions than `code::MAX_OPERAND`.
+    pub fn reverse(&self) -> Option<CompiledRegexp> {
+        let n = self.code.len();
+        // edges of the program reversed: for every address, the addresses
+        // leading to it with the instruction to run on the way back, if any
+        let mut preds = vec::from_elem(n, ~[]);
+        let mut ends = ~[];
+        for pc in range(0, n) {
+            match self.code.op(pc) {
+                code::Match(m) => preds[pc + 1].push((pc, Some(inst::Match(m.clone())))),
+                code::Jmp(a) => preds[a].push((pc, None)),
+                code::Split(a, b) => {
+                    preds[a].push((pc, None));
+                    preds[b].push((pc, None));
+                },
+                code::Save(_) => preds[pc + 1].push((pc, None)),
+                code::Assert(a) => {
+                    let reversed = match a {
+                        inst::Begin => inst::End,
+                        inst::End => inst::Begin,
+                        inst::BeginLine => inst::EndLine,
+                        inst::EndLine => inst::BeginLine,
+                        inst::EndBeforeNewline => return None,
+                        boundary => boundary,
+                    };
+                    preds[pc + 1].push((pc, Some(inst::Assert(reversed))));
+                },
+                code::Succeed => ends.push(pc),
+                _ => return None,
             }
         }
-        Err(e) => Err(e),
+        // the program starts with jumps to every `Succeed`, then has a node
+        // per address branching to its predecessors, which for address 0
+        // include the end of the reversed program
+        let mut nodes = ~[];
+        let mut size = ends.len() * 2 - 1;
+        for pc in range(0, n) {
+            nodes.push(size);
+            let before = preds[pc].iter().count(|&(_, ref before)| before.is_some());
+            size += match preds[pc].len() + if pc == 0 { 1 } else { 0 } {
+                // no way back from here, as for an unreachable address
+                0 => 1,
+                branches => branches * 2 - 1 + before,
+            };
+        }
+        if size > code::MAX_OPERAND {
+            return None;
+        }
+        let mut code = ~[];
+        for (i, &end) in ends.iter().enumerate() {
+            if i + 1 < ends.len() {
+                code.push(inst::Split(code.len() + 1, code.len() + 2));
+            }
+            code.push(inst::Jmp(nodes[end]));
+        }
+        for pc in range(0, n) {
+            let mut branches = ~[];
+            for &(from, ref before) in preds[pc].iter() {
+                let mut branch = ~[];
+                match *before {
+                    Some(ref i) => branch.push(i.clone()),
+                    None => (),
+                }
+                branch.push(inst::Jmp(nodes[from]));
+                branches.push(branch);
+            }
+            if pc == 0 {
+                branches.push(~[inst::Succeed]);
+            }
+            if branches.is_empty() {
+                code.push(inst::Match(inst::Ranges(~[])));
+            }
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 < branches.len() {
+                    code.push(inst::Split(code.len() + 1, code.len() + 1 + branch.len()));
+                }
+                code.push_all(*branch);
+            }
+        }
+        Some(CompiledRegexp {
+            code: code::Code::new(optimize::optimize(code)),
+            names: HashMap::new(),
+            longest: true,
+            earliest: false,
+        })
+    }
+
+    /// Whether the program matches a literal string and nothing else,
+    /// with no group but group 0
+    pub fn is_literal(&self) -> bool {
+        let n = self.code.len();
+        range(0, n).all(|pc| match self.code.op(pc) {
+            code::Save(0) => pc == 0,
+            code::Save(1) => pc + 2 == n,
+            code::Succeed => pc + 1 == n,
+            code::Match(&inst::Char(_)) => true,
+            _ => false,
+        })
+    }
+
+    /// Literal that every match starts with, read from the start of the
+    /// program up to the first instruction that branches
+    pub fn literal_prefix(&self) -> ~str {
+        let mut prefix = ~"";
+        let mut pc = 0;
+        loop {
+            pc = match self.code.op(pc) {
+                code::Match(&inst::Char(c)) => {
+                    prefix.push_char(c);
+                    pc + 1
+                },
+                // zero-width, so they leave the literal where it is
+                code::Save(_) | code::Assert(_) | code::LookAhead(*)
+                | code::LookBehind(*) => pc + 1,
+                code::Jmp(a) if a > pc => a,
+                _ => return prefix,
+            };
+        }
     }
+
+    /// Literals one of which starts every match, read along every path from
+    /// the start of the program up to an instruction that is neither a
+    /// literal char nor a forward jump. Empty if some path reads no char
+    /// or there are more than `MAX_PREFIXES` paths.
+    pub fn literal_prefixes(&self) -> ~[~str] {
+        let mut prefixes = ~[];
+        let mut paths = ~[(0u, ~"")];
+        while !paths.is_empty() {
+            let (pc, literal) = paths.pop();
+            match self.code.op(pc) {
+                code::Match(&inst::Char(c)) => {
+                    let mut literal = literal;
+                    literal.push_char(c);
+                    paths.push((pc + 1, literal));
+                },
+                code::Save(_) | code::Assert(_) | code::LookAhead(*)
+                | code::LookBehind(*) => paths.push((pc + 1, literal)),
+                code::Jmp(a) if a > pc => paths.push((a, literal)),
+                // branching back would loop, so loops end a path
+                code::Split(a, b) if a > pc && b > pc => {
+                    if paths.len() + prefixes.len() >= MAX_PREFIXES {
+                        return ~[];
+                    }
+                    paths.push((b, literal.clone()));
+                    paths.push((a, literal));
+                },
+                _ if literal.is_empty() => return ~[],
+                _ => if !prefixes.contains(&literal) {
+                    prefixes.push(literal);
+                },
+            }
+        }
+        prefixes
+    }
+}
+
+/// Quantifier of the greedy form a possessive one is compiled from
+static GREEDY: parse::Greed = parse::Greedy;
+
+/// Part of the program still to compile
+enum Task<'self> {
+    /// the code of a sequence
+    Seq(&'self [parse::Ast]),
+    /// the code of an item with its quantifier
+    Item(&'self parse::One, &'self parse::Modifier, &'self parse::Greed),
+    /// the code of an item matching once
+    Once(&'self parse::One),
+    /// an instruction as it is
+    Emit(inst::Instruction),
+    /// placing a label at the next instruction
+    Place(uint),
 }
 
-struct Compiler(CompiledRegexp);
+/// Code generator emitting the whole program into a single buffer, each
+/// construct appended where it runs. Jumps name labels rather than
+/// addresses, so a jump can be emitted before its target is; `compile`
+/// resolves them all once the program is complete.
+struct Compiler<'self> {
+    code: ~[inst::Instruction],
+    /// address of every label, once it is placed
+    labels: ~[Option<uint>],
+    /// number of loop counters used so far
+    ncounters: uint,
+    options: &'self Options,
+}
 
-impl Compiler {
-    pub fn new() -> Compiler {
-        Compiler(~[])
+impl<'self> Compiler<'self> {
+    pub fn new<'a>(options: &'a Options) -> Compiler<'a> {
+        Compiler {
+            code: ~[],
+            labels: ~[],
+            ncounters: 0,
+            options: options,
+        }
     }
 
     pub fn compile(&mut self, ast: &[parse::Ast]) {
+        self.code.push(inst::Save(0));
         self.compile_internal(ast);
-        self.push(inst::Succeed);
+        self.code.push(inst::Save(1));
+        self.code.push(inst::Succeed);
+        let mut addresses = ~[];
+        for label in self.labels.iter() {
+            match *label {
+                Some(address) => addresses.push(address),
+                None => fail!("Label never placed."),
+            }
+        }
+        let code = util::replace(&mut self.code, ~[]);
+        self.code = code.iter().map(|i| optimize::relocate(i, addresses)).collect();
+    }
+
+    /// New label, to be placed later
+    fn label(&mut self) -> uint {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    /// Make `label` stand for the address of the next instruction
+    fn place(&mut self, label: uint) {
+        self.labels[label] = Some(self.code.len());
     }
 
+    /// Emit the code of `ast`, keeping the parts still to compile on a
+    /// stack rather than recursing, so that deeply nested patterns do not
+    /// use up the native stack
     fn compile_internal(&mut self, ast: &[parse::Ast]) {
+        let mut tasks = ~[Seq(ast)];
+        while !tasks.is_empty() {
+            let mut next = ~[];
+            match tasks.pop() {
+                Seq(ast) => self.compile_seq(ast, &mut next),
+                Item(one, modifier, greed) =>
+                    self.compile_fragment(one, modifier, greed, &mut next),
+                Once(one) => self.compile_one(one, &mut next),
+                Emit(instruction) => self.code.push(instruction),
+                Place(label) => self.place(label),
+            }
+            // the first of the new tasks goes on top
+            while !next.is_empty() {
+                tasks.push(next.pop());
+            }
+        }
+    }
+
+    fn compile_seq<'a>(&mut self, ast: &'a [parse::Ast], tasks: &mut ~[Task<'a>]) {
         for fragment in ast.iter() {
             match fragment {
-                &parse::Fragment(ref one, ref modifier) => self.compile_fragment(one, modifier),
-                &parse::Or(ref asts) => {
-                    let mut jmps = vec::from_elem(asts.len(), 0u);
-                    let mut i = 0;
-                    for a in asts.iter() {
-                        let idx = self.len();
-                        self.push(inst::Jmp(-1));
-                        self.compile_internal(*a);
-                        self.push(inst::Jmp(-1));
-                        let l1 = idx + 1;
-                        let l2 = self.len();
-                        self[idx] = inst::Split(l1, l2);
-                        jmps[i] = l2 - 1;
-                        i += 1;
-                    }
-                    let len = self.len();
-                    for jmp in jmps.iter() {
-                        self[*jmp] = inst::Jmp(len);
+                &parse::Fragment(ref one, ref modifier, ref greed, _) =>
+                    tasks.push(Item(one, modifier, greed)),
+                &parse::Or(ref asts, _) => {
+                    let end = self.label();
+                    for (i, a) in asts.iter().enumerate() {
+                        if i + 1 == asts.len() {
+                            // the last alternative has nothing to fall back to
+                            tasks.push(Seq(*a));
+                        } else {
+                            let (this, next) = (self.label(), self.label());
+                            tasks.push(Emit(inst::Split(this, next)));
+                            tasks.push(Place(this));
+                            tasks.push(Seq(*a));
+                            tasks.push(Emit(inst::Jmp(end)));
+                            tasks.push(Place(next));
+                        }
                     }
+                    tasks.push(Place(end));
                 },
             }
         }
     }
 
-    fn compile_fragment(&mut self, one: &parse::One, modifier: &parse::Modifier) {
-        match modifier {
-            &parse::No => self.compile_one(one),
-            &parse::QMark => {
-                let idx = self.len();
-                let l1 = idx + 1;
-                self.push(inst::Jmp(-1));
-                self.compile_one(one);
-                let l2 = self.len();
-                self[idx] = inst::Split(l1, l2);
-            },
-            &parse::Star => {
-                let idx = self.len();
-                let l1 = idx;
-                let l2 = idx + 1;
-                self.push(inst::Jmp(-1));
-                self.compile_one(one);
-                let l3 = self.len() + 1;
-                self[idx] = inst::Split(l2, l3);
-                self.push(inst::Jmp(l1));
+    fn compile_fragment<'a>(&mut self, one: &'a parse::One, modifier: &'a parse::Modifier,
+                            greed: &'a parse::Greed, tasks: &mut ~[Task<'a>]) {
+        match *greed {
+            parse::Possessive => {
+                // same as wrapping the greedy form in an atomic group
+                self.compile_sub(|body| inst::Atomic(body), Item(one, modifier, &GREEDY), tasks);
+                return;
             },
+            _ => (),
+        }
+        match modifier {
+            &parse::No => tasks.push(Once(one)),
+            &parse::QMark => self.compile_qmark(one, greed, tasks),
+            &parse::Star => self.compile_star(one, greed, tasks),
             &parse::Plus => {
-                let l1 = self.len();
-                self.compile_one(one);
-                let l2 = self.len() + 1;
-                self.push(inst::Split(l1, l2));
+                let (start, end) = (self.label(), self.label());
+                tasks.push(Place(start));
+                tasks.push(Once(one));
+                tasks.push(Emit(self.split(greed, start, end)));
+                tasks.push(Place(end));
+            },
+            &parse::Range(min, max)
+                    if max.map_default(min, |&max| max) > self.options.unroll_limit =>
+                match max {
+                    Some(max) => self.compile_counted(one, greed, min, max, tasks),
+                    None => {
+                        self.compile_counted(one, greed, min, min, tasks);
+                        self.compile_star(one, greed, tasks);
+                    },
+                },
+            &parse::Range(min, max) => {
+                for _ in range(0, min) {
+                    tasks.push(Once(one));
+                }
+                match max {
+                    None => self.compile_star(one, greed, tasks),
+                    Some(max) => {
+                        // nest the optional copies so a failed one skips the rest
+                        let end = self.label();
+                        for _ in range(min, max) {
+                            let body = self.label();
+                            tasks.push(Emit(self.split(greed, body, end)));
+                            tasks.push(Place(body));
+                            tasks.push(Once(one));
+                        }
+                        tasks.push(Place(end));
+                    },
+                }
             },
         }
     }
 
-    fn compile_one(&mut self, one: &parse::One) {
+    fn compile_qmark<'a>(&mut self, one: &'a parse::One, greed: &parse::Greed,
+                         tasks: &mut ~[Task<'a>]) {
+        let (body, end) = (self.label(), self.label());
+        tasks.push(Emit(self.split(greed, body, end)));
+        tasks.push(Place(body));
+        tasks.push(Once(one));
+        tasks.push(Place(end));
+    }
+
+    fn compile_star<'a>(&mut self, one: &'a parse::One, greed: &parse::Greed,
+                        tasks: &mut ~[Task<'a>]) {
+        let (start, body, end) = (self.label(), self.label(), self.label());
+        tasks.push(Place(start));
+        tasks.push(Emit(self.split(greed, body, end)));
+        tasks.push(Place(body));
+        tasks.push(Once(one));
+        tasks.push(Emit(inst::Jmp(start)));
+        tasks.push(Place(end));
+    }
+
+    /// Compile `one` repeated from `min` to `max` times as a single copy
+    /// in a loop, with a counter of its own for the iterations done
+    fn compile_counted<'a>(&mut self, one: &'a parse::One, greed: &parse::Greed, min: uint,
+                           max: uint, tasks: &mut ~[Task<'a>]) {
+        let counter = self.ncounters;
+        self.ncounters += 1;
+        let (start, body, end) = (self.label(), self.label(), self.label());
+        tasks.push(Emit(inst::Reset(counter)));
+        tasks.push(Place(start));
+        tasks.push(Emit(self.split(greed, body, end)));
+        tasks.push(Place(body));
+        tasks.push(Emit(inst::CountBelow(counter, max)));
+        tasks.push(Once(one));
+        tasks.push(Emit(inst::Increment(counter)));
+        tasks.push(Emit(inst::Jmp(start)));
+        tasks.push(Place(end));
+        tasks.push(Emit(inst::CountAtLeast(counter, min)));
+    }
+
+    fn compile_one<'a>(&mut self, one: &'a parse::One, tasks: &mut ~[Task<'a>]) {
         match one {
-            &parse::Match(m) => match m {
-                inst::Char(c) => self.push(inst::Match(inst::Char(c))),
-                inst::Dot => self.push(inst::Match(inst::Dot)),
+            &parse::Match(inst::Class(ref ranges, negated)) => {
+                let set = charset::CharSet::from_ranges(*ranges);
+                let set = if negated { set.negate() } else { set };
+                tasks.push(Emit(inst::Match(inst::Ranges(set.ranges().to_owned()))));
+            },
+            &parse::Match(ref m) => tasks.push(Emit(inst::Match(m.clone()))),
+            &parse::Group(Some(idx), ref ast, _) => {
+                tasks.push(Emit(inst::Save(2 * idx)));
+                tasks.push(Seq(*ast));
+                tasks.push(Emit(inst::Save(2 * idx + 1)));
+            },
+            &parse::Group(None, ref ast, _) => tasks.push(Seq(*ast)),
+            &parse::Backref(idx, fold) => tasks.push(Emit(inst::Backref(idx, fold))),
+            &parse::Conditional(group, ref yes, ref no) => {
+                let (no_label, end) = (self.label(), self.label());
+                tasks.push(Emit(inst::IfGroup(group, no_label)));
+                tasks.push(Seq(*yes));
+                tasks.push(Emit(inst::Jmp(end)));
+                tasks.push(Place(no_label));
+                tasks.push(Seq(*no));
+                tasks.push(Place(end));
             },
-            &parse::Group(ref ast) => self.compile_internal(*ast),
+            &parse::Atomic(ref ast) =>
+                self.compile_sub(|body| inst::Atomic(body), Seq(*ast), tasks),
+            &parse::LookAhead(ref ast, negated) =>
+                self.compile_sub(|body| inst::LookAhead(body, negated), Seq(*ast), tasks),
+            &parse::LookBehind(ref ast, negated, width) =>
+                self.compile_sub(|body| inst::LookBehind(body, negated, width), Seq(*ast),
+                                 tasks),
+            &parse::Assert(ref a) => tasks.push(Emit(inst::Assert(a.clone()))),
         }
     }
+
+    /// Build a split whose first (preferred) branch is `more`, the branch
+    /// that repeats, unless the quantifier is lazy, or greedy while
+    /// `swap_greed` is set
+    fn split(&self, greed: &parse::Greed, more: uint, less: uint) -> inst::Instruction {
+        let lazy = match *greed {
+            parse::Greedy => self.options.swap_greed,
+            parse::Lazy => !self.options.swap_greed,
+            parse::Possessive => fail!("Possessive quantifiers are compiled as atomic groups."),
+        };
+        if lazy {
+            inst::Split(less, more)
+        } else {
+            inst::Split(more, less)
+        }
+    }
+
+    /// Emit the instruction made by `head` from the label of a sub-program,
+    /// followed by a jump over that sub-program, the code of `body`. The
+    /// sub-program ends with its own `Succeed` and is only reachable
+    /// through the instruction.
+    fn compile_sub<'a>(&mut self, head: &fn(uint) -> inst::Instruction, body: Task<'a>,
+                       tasks: &mut ~[Task<'a>]) {
+        let (start, end) = (self.label(), self.label());
+        tasks.push(Emit(head(start)));
+        tasks.push(Emit(inst::Jmp(end)));
+        tasks.push(Place(start));
+        tasks.push(body);
+        tasks.push(Emit(inst::Succeed));
+        tasks.push(Place(end));
+    }
+}
+
+/// Number of instructions `Compiler::compile` emits for `ast`, or
+/// `uint::max_value` if that overflows
+fn size(ast: &[parse::Ast], options: &Options) -> uint {
+    let body = parse::fold(ast, |_, sizes| sum(sizes), |node, sizes| match *node {
+        parse::Or(ref branches, _) => add(sum(sizes), 2 * (branches.len() - 1)),
+        parse::Fragment(ref one, ref modifier, ref greed, _) =>
+            size_fragment(size_one(one, sum(sizes)), modifier, greed, options),
+    });
+    add(body, 3)
+}
+
+/// Size of a fragment whose item alone has size `n`
+fn size_fragment(n: uint, modifier: &parse::Modifier, greed: &parse::Greed,
+                 options: &Options) -> uint {
+    match *greed {
+        parse::Possessive => return add(size_fragment(n, modifier, &parse::Greedy, options), 3),
+        _ => (),
+    }
+    match modifier {
+        &parse::No => n,
+        &parse::QMark | &parse::Plus => add(n, 1),
+        &parse::Star => add(n, 2),
+        // see `Compiler::compile_counted`
+        &parse::Range(min, max) if max.map_default(min, |&max| max) > options.unroll_limit =>
+            if max.is_some() { add(n, 6) } else { add(add(n, 6), add(n, 2)) },
+        &parse::Range(min, None) => add(mul(min, n), add(n, 2)),
+        &parse::Range(min, Some(max)) => add(mul(min, n), mul(max - min, add(n, 1))),
+    }
+}
+
+/// Size of `one` alone, the sequences inside it having size `inner`
+fn size_one(one: &parse::One, inner: uint) -> uint {
+    match one {
+        &parse::Match(_) | &parse::Backref(*) | &parse::Assert(_) => 1,
+        &parse::Group(Some(_), _, _) | &parse::Conditional(*) => add(inner, 2),
+        &parse::Group(None, _, _) => inner,
+        &parse::Atomic(_) | &parse::LookAhead(*) | &parse::LookBehind(*) => add(inner, 3),
+    }
+}
+
+/// Most groups nested inside one another in `ast`, counting the ones
+/// escapes like `\X` stand for
+fn depth(ast: &[parse::Ast]) -> uint {
+    parse::fold(ast, |_, depths| deepest(depths), |node, depths| match *node {
+        parse::Fragment(*) if !depths.is_empty() => deepest(depths) + 1,
+        _ => deepest(depths),
+    })
+}
+
+fn deepest(depths: &[uint]) -> uint {
+    let mut most = 0;
+    for &d in depths.iter() {
+        most = cmp::max(most, d);
+    }
+    most
+}
+
+/// Sum saturating at `uint::max_value`
+fn sum(sizes: &[uint]) -> uint {
+    let mut total = 0;
+    for &n in sizes.iter() {
+        total = add(total, n);
+    }
+    total
+}
+
+/// Addition saturating at `uint::max_value`
+fn add(a: uint, b: uint) -> uint {
+    if a > uint::max_value - b { uint::max_value } else { a + b }
+}
+
+/// Multiplication saturating at `uint::max_value`
+fn mul(a: uint, b: uint) -> uint {
+    if a != 0 && b > uint::max_value / a { uint::max_value } else { a * b }
 }
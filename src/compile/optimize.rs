@@ -0,0 +1,333 @@
+use std::util;
+use std::vec;
+
+use compile::inst;
+use compile::parse;
+
+/// Shrink a program without changing what it matches: jumps to jumps are
+/// threaded to their final target, `Split`s with both branches alike
+/// become jumps, and jumps to the next instruction as well as
+/// instructions that cannot be reached are removed
+pub fn optimize(code: ~[inst::Instruction]) -> ~[inst::Instruction] {
+    let mut code = code;
+    let mut changed = true;
+    // collapsing a split makes a new jump to thread through
+    while changed {
+        changed = false;
+        for pc in range(0, code.len()) {
+            let threaded = match code[pc] {
+                inst::Jmp(a) => inst::Jmp(resolve(code, a)),
+                inst::Split(a, b) => {
+                    let (a, b) = (resolve(code, a), resolve(code, b));
+                    if a == b {
+                        changed = true;
+                        inst::Jmp(a)
+                    } else {
+                        inst::Split(a, b)
+                    }
+                },
+                inst::LookAhead(body, negated) => inst::LookAhead(resolve(code, body), negated),
+                inst::LookBehind(body, negated, width) =>
+                    inst::LookBehind(resolve(code, body), negated, width),
+                inst::IfGroup(group, no) => inst::IfGroup(group, resolve(code, no)),
+                inst::Atomic(body) => inst::Atomic(resolve(code, body)),
+                _ => code[pc].clone(),
+            };
+            code[pc] = threaded;
+        }
+    }
+
+    let mut reachable = vec::from_elem(code.len(), false);
+    let mut stack = ~[0u];
+    while !stack.is_empty() {
+        let pc = stack.pop();
+        if !reachable[pc] {
+            reachable[pc] = true;
+            stack.push_all(successors(&code[pc], pc));
+        }
+    }
+    // every address maps to the first instruction kept from it on, which
+    // for a removed jump to the next instruction is where it jumps to
+    let mut remap = vec::from_elem(code.len() + 1, 0u);
+    let mut kept = 0;
+    for pc in range(0, code.len()) {
+        remap[pc] = kept;
+        if keep(&code[pc], pc, reachable[pc]) {
+            kept += 1;
+        }
+    }
+    remap[code.len()] = kept;
+    let mut optimized = ~[];
+    for (pc, instruction) in code.iter().enumerate() {
+        if keep(instruction, pc, reachable[pc]) {
+            optimized.push(relocate(instruction, remap));
+        }
+    }
+    optimized
+}
+
+/// Address of the first instruction from `pc` on that is not a `Jmp`,
+/// following at most as many jumps as there are instructions
+fn resolve(code: &[inst::Instruction], pc: uint) -> uint {
+    let mut pc = pc;
+    for _ in range(0, code.len()) {
+        match code[pc] {
+            inst::Jmp(a) => pc = a,
+            _ => return pc,
+        }
+    }
+    pc
+}
+
+/// Addresses the instruction at `pc` can go on to, including the
+/// sub-programs it runs
+fn successors(instruction: &inst::Instruction, pc: uint) -> ~[uint] {
+    match *instruction {
+        inst::Match(_) | inst::Assert(_) | inst::Save(_) | inst::Backref(*) | inst::Reset(_)
+        | inst::Increment(_) | inst::CountBelow(*) | inst::CountAtLeast(*) => ~[pc + 1],
+        inst::Jmp(a) => ~[a],
+        inst::Split(a, b) => ~[a, b],
+        inst::LookAhead(body, _) | inst::LookBehind(body, _, _) | inst::Atomic(body) =>
+            ~[pc + 1, body],
+        inst::IfGroup(_, no) => ~[pc + 1, no],
+        inst::Succeed => ~[],
+    }
+}
+
+fn keep(instruction: &inst::Instruction, pc: uint, reachable: bool) -> bool {
+    match *instruction {
+        inst::Jmp(a) if a == pc + 1 => false,
+        _ => reachable,
+    }
+}
+
+/// Copy of `instruction` with every address `a` it holds replaced
+/// by `remap[a]`
+pub fn relocate(instruction: &inst::Instruction, remap: &[uint]) -> inst::Instruction {
+    match *instruction {
+        inst::Jmp(a) => inst::Jmp(remap[a]),
+        inst::Split(a, b) => inst::Split(remap[a], remap[b]),
+        inst::LookAhead(body, negated) => inst::LookAhead(remap[body], negated),
+        inst::LookBehind(body, negated, width) => inst::LookBehind(remap[body], negated, width),
+        inst::IfGroup(group, no) => inst::IfGroup(group, remap[no]),
+        inst::Atomic(body) => inst::Atomic(remap[body]),
+        _ => instruction.clone(),
+    }
+}
+
+/// Factor the literal chars that neighbouring alternatives start with out
+/// of them, everywhere in `ast`, so that `abc|abd|x` compiles as
+/// `ab(?:c|d)|x` and the common chars are only matched once. Alternatives
+/// keep their order, and so their priority, except in alternations of
+/// literals only, which become a trie when that keeps the priority of the
+/// literals that can match together.
+pub fn factor(ast: &mut ~[parse::Ast]) {
+    let old = util::replace(ast, ~[]);
+    *ast = parse::rebuild(old, |_| (), |node| match node {
+        parse::Or(branches, span) => parse::Or(alternatives(branches, span), span),
+        node => node,
+    });
+}
+
+/// Branches matching the same as `branches`, as a trie if they are all
+/// literals and that keeps their priority, or with neighbours merged
+fn alternatives(branches: ~[~[parse::Ast]], span: parse::Span) -> ~[~[parse::Ast]] {
+    match literal_words(branches) {
+        Some(words) => {
+            match trie(words, span) {
+                Some(branch) => return ~[branch],
+                None => (),
+            }
+        },
+        None => (),
+    }
+    merge(branches)
+}
+
+/// Chars of every branch, if all of them are literals
+fn literal_words(branches: &[~[parse::Ast]]) -> Option<~[~[char]]> {
+    let mut words = ~[];
+    for branch in branches.iter() {
+        let mut word = ~[];
+        for fragment in branch.iter() {
+            match literal_char(fragment) {
+                Some(c) => word.push(c),
+                None => return None,
+            }
+        }
+        words.push(word);
+    }
+    Some(words)
+}
+
+/// Node of the trie `trie` builds
+struct Node {
+    /// first word ending at the node
+    ended: Option<uint>,
+    /// char and node every branch goes on with, in the order the words
+    /// take them
+    children: ~[(char, uint)],
+    /// lowest and highest index of the words going on past the node
+    longer: Option<(uint, uint)>,
+}
+
+/// Trie matching `words`, giving a word priority over the others as it
+/// had in the alternation. That is only possible if every word comes
+/// either before all the longer words it starts, or after all of them.
+/// The words are inserted one char at a time and the branches built from
+/// the last node back, so that neither depends on the stack or copies a
+/// long word at every char of it.
+fn trie(words: &[~[char]], span: parse::Span) -> Option<~[parse::Ast]> {
+    let mut nodes = ~[Node { ended: None, children: ~[], longer: None }];
+    for (i, word) in words.iter().enumerate() {
+        let mut n = 0;
+        for &c in word.iter() {
+            nodes[n].longer = match nodes[n].longer {
+                Some((lo, _)) => Some((lo, i)),
+                None => Some((i, i)),
+            };
+            let mut next = None;
+            for &(d, k) in nodes[n].children.iter() {
+                if d == c {
+                    next = Some(k);
+                    break;
+                }
+            }
+            n = match next {
+                Some(k) => k,
+                None => {
+                    nodes.push(Node { ended: None, children: ~[], longer: None });
+                    let k = nodes.len() - 1;
+                    nodes[n].children.push((c, k));
+                    k
+                },
+            };
+        }
+        if nodes[n].ended.is_none() {
+            nodes[n].ended = Some(i);
+        }
+    }
+    // the branches of every node, built after those of the nodes below it,
+    // which come after it, and kept reversed while they are a single chain
+    // of chars, so that a char is added to them without moving the others
+    let mut reversed: ~[~[parse::Ast]] = vec::from_fn(nodes.len(), |_| ~[]);
+    let mut n = nodes.len();
+    while n > 0 {
+        n -= 1;
+        // a word ending here is tried before the longer ones if it comes first
+        let end_first = match nodes[n].ended {
+            Some(e) => match nodes[n].longer {
+                Some((lo, hi)) if lo < e && hi > e => return None,
+                Some((lo, _)) => lo > e,
+                None => true,
+            },
+            None => false,
+        };
+        let mut branches = ~[];
+        for &(c, k) in nodes[n].children.iter() {
+            let mut branch = util::replace(&mut reversed[k], ~[]);
+            branch.push(parse::Fragment(parse::Match(inst::Char(c)), parse::No, parse::Greedy,
+                                        span));
+            branches.push(branch);
+        }
+        reversed[n] = if nodes[n].ended.is_none() && branches.len() == 1 {
+            branches.pop()
+        } else if branches.is_empty() {
+            ~[]
+        } else {
+            for branch in branches.mut_iter() {
+                branch.reverse();
+            }
+            if nodes[n].ended.is_some() {
+                if end_first {
+                    branches.unshift(~[]);
+                } else {
+                    branches.push(~[]);
+                }
+            }
+            ~[parse::Or(branches, span)]
+        };
+    }
+    let mut root = util::replace(&mut reversed[0], ~[]);
+    root.reverse();
+    Some(root)
+}
+
+/// Merge every run of neighbouring branches starting with the same char
+/// into a single branch
+fn merge(branches: ~[~[parse::Ast]]) -> ~[~[parse::Ast]] {
+    let mut rest = branches;
+    rest.reverse();
+    let mut merged = ~[];
+    while !rest.is_empty() {
+        let mut run = ~[rest.pop()];
+        let c = first_char(run[0]);
+        while c.is_some() && !rest.is_empty() && first_char(rest[rest.len() - 1]) == c {
+            run.push(rest.pop());
+        }
+        if run.len() == 1 {
+            merged.push(run.pop());
+        } else {
+            merged.push(merge_run(run));
+        }
+    }
+    merged
+}
+
+/// Single branch matching the common chars the branches start with, then
+/// an alternation of what is left of each of them
+fn merge_run(run: ~[~[parse::Ast]]) -> ~[parse::Ast] {
+    // number of chars in common, all of them being at least that long
+    let mut n = 1;
+    loop {
+        let c = first_char(run[0].slice_from(n));
+        if c.is_none() || !run.iter().all(|b| first_char(b.slice_from(n)) == c) {
+            break;
+        }
+        n += 1;
+    }
+    let (start, _) = run[0][0].span();
+    let (_, end) = run[run.len() - 1][run[run.len() - 1].len() - 1].span();
+    let mut branch = ~[];
+    for fragment in run[0].slice_to(n).iter() {
+        match *fragment {
+            parse::Fragment(parse::Match(inst::Char(c)), _, _, span) =>
+                branch.push(parse::Fragment(parse::Match(inst::Char(c)), parse::No,
+                                            parse::Greedy, span)),
+            _ => fail!("Expected a literal char."),
+        }
+    }
+    let mut suffixes = ~[];
+    let mut run = run;
+    for suffix in run.mut_iter() {
+        // dropping the common chars from the back rather than shifting
+        // them out one at a time
+        suffix.reverse();
+        for _ in range(0, n) {
+            suffix.pop();
+        }
+        suffix.reverse();
+        suffixes.push(util::replace(suffix, ~[]));
+    }
+    let alternation = ~[parse::Or(merge(suffixes), (start, end))];
+    branch.push(parse::Fragment(parse::Group(None, alternation, (start, end)), parse::No,
+                                parse::Greedy, (start, end)));
+    branch
+}
+
+/// Char matched by the first fragment of `branch`, if it is a lone literal
+fn first_char(branch: &[parse::Ast]) -> Option<char> {
+    if branch.is_empty() {
+        None
+    } else {
+        literal_char(&branch[0])
+    }
+}
+
+/// Char matched by `fragment`, if it is a lone literal
+fn literal_char(fragment: &parse::Ast) -> Option<char> {
+    match *fragment {
+        parse::Fragment(parse::Match(inst::Char(c)), parse::No, _, _) => Some(c),
+        _ => None,
+    }
+}
@@ -1,13 +1,74 @@
+use std::cmp;
+use std::hashmap::HashMap;
 use std::iterator;
 use std::str;
+use std::uint;
+use std::util;
 
+use compile::Options;
+use compile::charset;
+use compile::charset::CharSet;
+use compile::code;
+use compile::error;
+use compile::error::ParseError;
 use compile::inst;
+use compile::unicode;
 
-pub static UNEXPECTED_EOS: &'static str = "Unexpected end of stream.";
+static DIGIT: &'static [(char, char)] = &[('0', '9')];
+static WORD: &'static [(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+static SPACE: &'static [(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+static LINE_BREAK: &'static [(char, char)] =
+    &[('\n', '\r'), ('\x85', '\x85'), ('\u2028', '\u2029')];
+
+static ALNUM: &'static [(char, char)] = &[('0', '9'), ('A', 'Z'), ('a', 'z')];
+static ALPHA: &'static [(char, char)] = &[('A', 'Z'), ('a', 'z')];
+static BLANK: &'static [(char, char)] = &[('\t', '\t'), (' ', ' ')];
+static CNTRL: &'static [(char, char)] = &[('\x00', '\x1f'), ('\x7f', '\x7f')];
+static GRAPH: &'static [(char, char)] = &[('!', '~')];
+static LOWER: &'static [(char, char)] = &[('a', 'z')];
+static PRINT: &'static [(char, char)] = &[(' ', '~')];
+static PUNCT: &'static [(char, char)] = &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')];
+static UPPER: &'static [(char, char)] = &[('A', 'Z')];
+static XDIGIT: &'static [(char, char)] = &[('0', '9'), ('A', 'F'), ('a', 'f')];
+
+/// chars standing for bytes outside of Unicode mode
+static BYTES: &'static [(char, char)] = &[('\x00', '\xff')];
+
+/// Hangul leading, vowel and trailing jamo, and all precomposed syllables
+static HANGUL_L: &'static [(char, char)] = &[('\u1100', '\u115f'), ('\ua960', '\ua97c')];
+static HANGUL_V: &'static [(char, char)] = &[('\u1160', '\u11a7'), ('\ud7b0', '\ud7c6')];
+static HANGUL_T: &'static [(char, char)] = &[('\u11a8', '\u11ff'), ('\ud7cb', '\ud7fb')];
+static HANGUL_SYLLABLES: &'static [(char, char)] = &[('\uac00', '\ud7a3')];
+/// format chars that attach to the cluster after them, like the Arabic number signs
+static PREPEND: &'static [(char, char)] =
+    &[('\u0600', '\u0605'), ('\u06dd', '\u06dd'), ('\u070f', '\u070f'), ('\u0890', '\u0891'),
+      ('\u08e2', '\u08e2'), ('\U000110bd', '\U000110bd'), ('\U000110cd', '\U000110cd')];
+/// chars extending a cluster besides the marks: the zero-width
+/// (non-)joiners, the emoji skin tone modifiers and the tags
+static EXTEND: &'static [(char, char)] =
+    &[('\u200c', '\u200d'), ('\U0001f3fb', '\U0001f3ff'), ('\U000e0020', '\U000e007f')];
+static REGIONAL_INDICATOR: &'static [(char, char)] = &[('\U0001f1e6', '\U0001f1ff')];
 
 pub enum One {
     Match(inst::Match),
-    Group(~[Ast]),
+    /// group with its capture index counting from 1,
+    /// or `None` if it does not capture
+    Group(Option<uint>, ~[Ast], Span),
+    Assert(inst::Assertion),
+    /// text previously matched by a capture group, and whether
+    /// it is compared ignoring case
+    Backref(uint, bool),
+    /// `(?>...)`, which never gives back what it matched
+    Atomic(~[Ast]),
+    /// `(?(group)yes|no)`: the first branch if the group took part in
+    /// the match so far, the second one otherwise
+    Conditional(uint, ~[Ast], ~[Ast]),
+    /// `(?=...)`, or `(?!...)` if negated
+    LookAhead(~[Ast], bool),
+    /// `(?<=...)`, or `(?<!...)` if negated, with the number of chars
+    /// matched by its body
+    LookBehind(~[Ast], bool, uint),
 }
 
 pub enum Modifier {
@@ -15,92 +76,354 @@ pub enum Modifier {
     Plus,
     QMark,
     Star,
+    /// `{n}`, `{n,}` or `{n,m}`
+    Range(uint, Option<uint>),
+}
+
+/// Whether a quantifier prefers more or fewer repetitions
+pub enum Greed {
+    Greedy,
+    Lazy,
+    /// greedy, and never gives back what it matched
+    Possessive,
 }
 
+/// Byte range of the pattern text a node was parsed from,
+/// from its first char up to its end
+pub type Span = (uint, uint);
+
 pub enum Ast {
-    Or(~[~[Ast]]),
-    Fragment(One, Modifier),
+    Or(~[~[Ast]], Span),
+    /// an item with its quantifier, spanning both
+    Fragment(One, Modifier, Greed, Span),
+}
+
+impl Ast {
+    pub fn span(&self) -> Span {
+        match *self {
+            Or(_, span) | Fragment(_, _, _, span) => span,
+        }
+    }
+}
+
+/// Matching flags that can be toggled from inside a pattern
+#[deriving(Clone)]
+pub struct Flags {
+    /// `i`: letters match regardless of case
+    case_insensitive: bool,
+    /// `m`: `^` and `$` also match at line boundaries
+    multi_line: bool,
+    /// `s`: `.` also matches '\n'
+    dot_all: bool,
+    /// `x`: whitespace is ignored and `#` starts a comment running to the
+    /// end of the line, unless escaped or inside a bracket expression
+    extended: bool,
+    /// `u`: the pattern describes chars; off, it describes bytes, which
+    /// escapes, classes and `.` stick to, and a char beyond ASCII stands
+    /// for its UTF-8 bytes
+    unicode: bool,
+}
+
+impl Flags {
+    pub fn new() -> Flags {
+        Flags {
+            case_insensitive: false,
+            multi_line: false,
+            dot_all: false,
+            extended: false,
+            unicode: true,
+        }
+    }
 }
 
 pub type Iter<'self> = iterator::Peekable<(uint, char), str::CharOffsetIterator<'self>>;
 
+/// Parser turning a pattern into the AST that `compile::compile` generates
+/// code from. It reads the same syntax under the same options, so tools
+/// working on the syntax alone can use it without compiling:
+///
+///     let mut parser = Parser::new("a(b|c)", &Options::new());
+///     match parser.parse() {
+///         Ok(ast) => assert_eq!(to_pattern(ast), ~"a(b|c)"),
+///         Err(e) => fail!(e.to_str()),
+///     }
 pub struct Parser<'self> {
-    iter: Iter<'self>,
+    priv pattern: &'self str,
+    priv iter: Iter<'self>,
+    /// length of the pattern, where an unexpected end is reported
+    priv end: uint,
+    /// number of capturing groups opened so far
+    ngroups: uint,
+    /// index of every named group
+    names: HashMap<~str, uint>,
+    /// flags in effect at the current position
+    priv flags: Flags,
+    /// chars of a `\Q...\E` run still to be parsed with their offsets,
+    /// the next one last
+    priv quoted: ~[(uint, char)],
+    /// whether octal escapes are accepted
+    priv octal: bool,
+    /// whether classes may nest and be combined with `&&` and `--`
+    priv class_sets: bool,
+    /// number of groups around the current position
+    priv depth: uint,
+    /// most groups allowed around any position
+    priv nest_limit: uint,
+    /// whether the alternatives of the next fragment share group numbers,
+    /// as in a `(?|...)` group
+    priv branch_reset: bool,
+}
+
+/// Alternation being parsed, at the top of the pattern or in a group
+struct Level {
+    /// alternatives before the current one
+    branches: ~[~[Ast]],
+    /// items of the current alternative
+    ast: ~[Ast],
+    /// offset where the alternation starts
+    start: uint,
+    /// whether the alternatives share group numbers, as in `(?|...)`
+    reset: bool,
+    /// number of capturing groups opened before the alternation
+    first_group: uint,
+    /// most groups opened by the alternatives so far, if they are reset
+    last_group: uint,
+}
+
+/// Group whose body is being parsed
+struct Frame {
+    kind: GroupKind,
+    /// offset of its '('
+    start: uint,
+    /// flags to restore once it closes
+    flags: Flags,
+    /// alternation it is an item of
+    outer: Level,
+}
+
+/// What a group makes of its body once it closes
+enum GroupKind {
+    /// capturing group with its index
+    Capture(uint),
+    NonCapture,
+    AtomicGroup,
+    /// lookahead, or negative lookahead if set
+    Ahead(bool),
+    /// lookbehind, or negative lookbehind if set
+    Behind(bool),
+    /// conditional group on the group with the index
+    Condition(uint),
+}
+
+/// What the start of a group parses to
+enum Opening {
+    /// a group with no body to parse, like `(?i)` or a comment
+    Whole(One),
+    /// a group whose body comes next, with the flags to restore after it
+    Open(GroupKind, Flags),
 }
 
 impl<'self> Parser<'self> {
-    pub fn new<'a>(pattern: &'a str) -> Parser<'a> {
+    pub fn new<'a>(pattern: &'a str, options: &Options) -> Parser<'a> {
         Parser {
+            pattern: pattern,
             iter: pattern.char_offset_iter().peekable(),
+            end: pattern.len(),
+            ngroups: 0,
+            names: HashMap::new(),
+            flags: Flags {
+                case_insensitive: options.case_insensitive,
+                multi_line: options.multi_line,
+                dot_all: options.dot_all,
+                extended: options.extended,
+                unicode: options.unicode,
+            },
+            quoted: ~[],
+            octal: options.octal,
+            class_sets: options.class_sets,
+            depth: 0,
+            nest_limit: options.nest_limit,
+            branch_reset: false,
         }
     }
 
-    pub fn parse(&mut self) -> Result<~[Ast], ~str> {
-        match self.parse_fragment(None) {
-            Ok((ast, _)) => Ok(ast),
-            Err(e) => Err(e),
-        }
-    }
-
-    pub fn parse_fragment(&mut self, delimiter: Option<char>) -> Result<(~[Ast], bool), ~str> {
-        let mut fragment = ~[];
-        let mut ast = ~[];
-        let mut found_delimiter = false;
+    /// Parse the whole pattern, after which `ngroups` and `names` describe
+    /// its capture groups
+    pub fn parse(&mut self) -> Result<~[Ast], ParseError> {
+        // open groups wait on a stack rather than in recursive calls, so
+        // that deeply nested patterns do not use up the native stack
+        let mut frames: ~[Frame] = ~[];
+        let mut level = self.level();
         loop {
-            match self.parse_one() {
-                Ok(o) => match o {
-                    Some(p) => ast.push(p),
-                    None => break,
-                },
-                Err(e) => return Err(e),
+            // an alternative may be empty, so look for what ends it first
+            let separator = if self.quoted.is_empty() {
+                self.skip_ignored();
+                match self.iter.peek() {
+                    Some(&(i, c)) if c == '|' || (c == ')' && !frames.is_empty()) =>
+                        Some((i, c)),
+                    _ => None,
+                }
+            } else {
+                None
             };
-            match self.iter.peek() {
-                Some(&(_, c)) => match c {
-                    '|' => {
-                        self.iter.next();
-                        fragment.push(ast);
-                        ast = ~[];
+            match separator {
+                Some((_, '|')) => {
+                    self.iter.next();
+                    level.branches.push(util::replace(&mut level.ast, ~[]));
+                    if level.reset {
+                        level.last_group = cmp::max(level.last_group, self.ngroups);
+                        self.ngroups = level.first_group;
+                    }
+                },
+                Some((i, _)) => {
+                    self.iter.next();
+                    let body = self.finish(level, i);
+                    let Frame { kind: kind, start: start, flags: flags, outer: outer } =
+                        frames.pop();
+                    // flags set inside the group do not leak out of it
+                    self.flags = flags;
+                    level = outer;
+                    let group = match self.close_group(kind, start, body) {
+                        Ok(g) => g,
+                        Err(e) => return Err(e),
+                    };
+                    match self.quantify(group, start) {
+                        Ok(fragment) => level.ast.push(fragment),
+                        Err(e) => return Err(e),
+                    }
+                },
+                None => match self.parse_item() {
+                    Ok(Some((start, Whole(one)))) => match self.quantify(one, start) {
+                        Ok(fragment) => level.ast.push(fragment),
+                        Err(e) => return Err(e),
                     },
-                    _ if delimiter.map_default(false, |&dc| dc == c) => {
-                        self.iter.next();
-                        found_delimiter = true;
-                        break;
+                    Ok(Some((start, Open(kind, flags)))) => {
+                        frames.push(Frame {
+                            kind: kind,
+                            start: start,
+                            flags: flags,
+                            outer: level,
+                        });
+                        level = self.level();
                     },
-                    _ => (),
+                    Ok(None) if frames.is_empty() => return Ok(self.finish(level, self.end)),
+                    Ok(None) => return self.unexpected_end(),
+                    Err(e) => return Err(e),
                 },
-                None => break,
-            };
+            }
+        }
+    }
+
+    /// New alternation starting at the current position
+    fn level(&mut self) -> Level {
+        Level {
+            branches: ~[],
+            ast: ~[],
+            start: self.offset(),
+            reset: util::replace(&mut self.branch_reset, false),
+            first_group: self.ngroups,
+            last_group: self.ngroups,
         }
+    }
 
-        if fragment.is_empty() {
-            Ok((ast, found_delimiter))
+    /// AST of an alternation ending at `end`
+    fn finish(&mut self, level: Level, end: uint) -> ~[Ast] {
+        let Level { branches: branches, ast: ast, start: start, last_group: last_group, _ } =
+            level;
+        self.ngroups = cmp::max(last_group, self.ngroups);
+        if branches.is_empty() {
+            ast
         } else {
-            Ok((~[Or(fragment)], found_delimiter))
+            let mut branches = branches;
+            branches.push(ast);
+            ~[Or(branches, (start, end))]
         }
     }
 
-    fn parse_one(&mut self) -> Result<Option<Ast>, ~str> {
-        let mut one: One;
-        let mut modifier: Modifier;
-        match self.iter.next() {
+    /// Parse the next item up to its quantifier, if any, returning its
+    /// offset with the item, or the start of a group, or `None` at the end
+    /// of the pattern
+    fn parse_item(&mut self) -> Result<Option<(uint, Opening)>, ParseError> {
+        if !self.quoted.is_empty() {
+            let (i, c) = self.quoted.pop();
+            return Ok(Some((i, Whole(self.literal(c, i)))));
+        }
+        self.skip_ignored();
+        let start = self.offset();
+        let one = match self.iter.next() {
             Some((i, c)) => match c {
-                '?' | '*' | '+' | ')' | '|' =>
-                    return Err(fmt!("Unexpected char '%c' at %u", c, i)),
-                '(' => match self.parse_group() {
-                    Ok(p) => one = Group(p),
+                ')' => return err(error::UnbalancedParen, i),
+                '?' | '*' | '+' | '{' | '|' => return err(error::UnexpectedChar(c), i),
+                '(' => return match self.parse_group(i) {
+                    Ok(opening) => Ok(Some((start, opening))),
+                    Err(e) => Err(e),
+                },
+                '[' => match self.parse_class() {
+                    Ok(m) => Match(m),
                     Err(e) => return Err(e),
                 },
-                '.' => one = Match(inst::Dot),
-                '\\' => match self.iter.next() {
-                    Some((_, c)) => one = Match(inst::Char(c)),
-                    None => return Err(UNEXPECTED_EOS.to_owned()),
+                '.' => if self.flags.dot_all {
+                    Match(inst::Any)
+                } else {
+                    Match(inst::Dot)
                 },
-                _ => one = Match(inst::Char(c)),
+                '^' => if self.flags.multi_line {
+                    Assert(inst::BeginLine)
+                } else {
+                    Assert(inst::Begin)
+                },
+                '$' => if self.flags.multi_line {
+                    Assert(inst::EndLine)
+                } else {
+                    Assert(inst::End)
+                },
+                '\\' => match self.parse_escape(i) {
+                    Ok(o) => o,
+                    Err(e) => return Err(e),
+                },
+                _ => self.literal(c, i),
             },
             None => return Ok(None),
         };
+        Ok(Some((start, Whole(one))))
+    }
+
+    /// Fragment made of `one`, parsed from `start`, and the quantifier
+    /// following it, if any
+    fn quantify(&mut self, one: One, start: uint) -> Result<Ast, ParseError> {
+        let mut one = one;
+        let modifier;
+        // a quoted char runs up to the next one, the last one taking the `\E`
+        let mut end = match self.quoted.last_opt() {
+            Some(&(i, _)) => i,
+            None => self.offset(),
+        };
+        if self.flags.case_insensitive {
+            one = match one {
+                Match(m) => Match(fold_case(m, self.flags.unicode)),
+                o => o,
+            };
+        }
+        // after folding, which must come before negation
+        if !self.flags.unicode {
+            one = match one {
+                Match(m) => Match(bytes_only(m)),
+                o => o,
+            };
+        }
+        let repeatable = match one {
+            Assert(_) | LookAhead(*) | LookBehind(*) => false,
+            Group(None, ref body, _) => !body.is_empty(),
+            _ => true,
+        };
+        if !repeatable || !self.quoted.is_empty() {
+            // there is nothing to repeat in zero-width items, and
+            // a quantifier after `\Q...\E` only applies to its last char
+            return Ok(Fragment(one, No, Greedy, (start, end)));
+        }
+        self.skip_ignored();
         match self.iter.peek() {
-            Some(&(_, ch)) => {
+            Some(&(j, ch)) => {
                 match ch {
                     '?' => {
                         modifier = QMark;
@@ -114,22 +437,1255 @@ impl<'self> Parser<'self> {
                         modifier = Plus;
                         self.iter.next();
                     },
+                    '{' => {
+                        self.iter.next();
+                        match self.parse_range(j) {
+                            Ok(m) => modifier = m,
+                            Err(e) => return Err(e),
+                        }
+                    },
                     _ => modifier = No,
                 }
             },
             None => modifier = No,
         };
-        Ok(Some(Fragment(one, modifier)))
+        let greed = match (&modifier, self.iter.peek()) {
+            (&No, _) => Greedy,
+            (_, Some(&(_, '?'))) => {
+                self.iter.next();
+                Lazy
+            },
+            (_, Some(&(_, '+'))) => {
+                self.iter.next();
+                Possessive
+            },
+            _ => Greedy,
+        };
+        match modifier {
+            No => (),
+            _ => end = self.offset(),
+        }
+        Ok(Fragment(one, modifier, greed, (start, end)))
+    }
+
+    /// Item matching `c`, written as itself at `start`: outside of Unicode
+    /// mode, a char beyond ASCII stands for its UTF-8 bytes in a row
+    fn literal(&self, c: char, start: uint) -> One {
+        if self.flags.unicode || c <= '\x7f' {
+            return Match(inst::Char(c));
+        }
+        let encoded = str::from_char(c);
+        let span = (start, start + encoded.len());
+        let body = encoded.as_bytes().iter().map(|&b| {
+            Fragment(Match(inst::Char(b as char)), No, Greedy, span)
+        }).collect();
+        Group(None, body, span)
+    }
+
+    /// Byte offset of the next char, or the end of the pattern
+    fn offset(&mut self) -> uint {
+        match self.iter.peek() {
+            Some(&(i, _)) => i,
+            None => self.end,
+        }
+    }
+
+    /// Whether the pattern goes on with `s` from the current position
+    fn looking_at(&mut self, s: &str) -> bool {
+        let i = self.offset();
+        self.pattern.slice_from(i).starts_with(s)
+    }
+
+    /// Span from `start` up to the current position
+    fn span_from(&mut self, start: uint) -> Span {
+        (start, self.offset())
+    }
+
+    /// Skip whitespace and comments when in extended mode
+    fn skip_ignored(&mut self) {
+        if !self.flags.extended {
+            return;
+        }
+        loop {
+            match self.iter.peek() {
+                Some(&(_, '#')) => loop {
+                    match self.iter.next() {
+                        Some((_, '\n')) | None => break,
+                        _ => (),
+                    }
+                },
+                Some(&(_, c)) if c.is_whitespace() => {
+                    self.iter.next();
+                },
+                _ => break,
+            }
+        }
     }
 
-    fn parse_group(&mut self) -> Result<~[Ast], ~str> {
-        match self.parse_fragment(Some(')')) {
-            Ok((p, found_delimiter)) => if found_delimiter {
-                Ok(p)
+    /// Parse what follows a backslash, at `start`, outside of a bracket expression
+    fn parse_escape(&mut self, start: uint) -> Result<One, ParseError> {
+        match self.iter.next() {
+            Some((_, 'Q')) => {
+                let mut chars = ~[];
+                loop {
+                    match self.iter.next() {
+                        Some((i, '\\')) => match self.iter.peek() {
+                            Some(&(_, 'E')) => {
+                                self.iter.next();
+                                break;
+                            },
+                            _ => chars.push((i, '\\')),
+                        },
+                        Some((i, c)) => chars.push((i, c)),
+                        // the quote runs to the end of the pattern if not closed
+                        None => break,
+                    }
+                }
+                if chars.is_empty() {
+                    Ok(Group(None, ~[], self.span_from(start)))
+                } else {
+                    chars.reverse();
+                    let (i, c) = chars.pop();
+                    self.quoted = chars;
+                    Ok(self.literal(c, i))
+                }
+            },
+            // a stray `\E` has nothing to end
+            Some((_, 'E')) => Ok(Group(None, ~[], self.span_from(start))),
+            // unlike `^` and `$`, these ignore multi-line mode
+            Some((_, 'A')) => Ok(Assert(inst::Begin)),
+            Some((_, 'z')) => Ok(Assert(inst::End)),
+            Some((_, 'Z')) => Ok(Assert(inst::EndBeforeNewline)),
+            Some((_, 'R')) => {
+                // "\r\n" is tried first, but unlike in PCRE a lone '\r'
+                // is still available to backtracking; every node spans the `\R`
+                let span = self.span_from(start);
+                let crlf = ~[Fragment(Match(inst::Char('\r')), No, Greedy, span),
+                             Fragment(Match(inst::Char('\n')), No, Greedy, span)];
+                let class = inst::Class(LINE_BREAK.to_owned(), false);
+                let single = ~[Fragment(Match(class), No, Greedy, span)];
+                Ok(Group(None, ~[Or(~[crlf, single], span)], span))
+            },
+            Some((i, 'X')) => if self.flags.unicode {
+                Ok(grapheme_cluster(self.span_from(start)))
             } else {
-                Err(UNEXPECTED_EOS.to_owned())
+                err(error::UnicodeDisabled, i)
+            },
+            Some((_, 'b')) => Ok(Assert(inst::WordBoundary)),
+            Some((_, 'B')) => Ok(Assert(inst::NotWordBoundary)),
+            Some((i, 'p')) => match self.parse_property(i) {
+                Ok(ranges) => Ok(Match(inst::Class(ranges, false))),
+                Err(e) => Err(e),
+            },
+            Some((i, 'P')) => match self.parse_property(i) {
+                Ok(ranges) => Ok(Match(inst::Class(ranges, true))),
+                Err(e) => Err(e),
+            },
+            Some((i, c)) if c >= '1' && c <= '9' => {
+                // take as many digits as still name an existing group
+                let mut n = c as uint - '0' as uint;
+                loop {
+                    match self.iter.peek() {
+                        Some(&(_, d)) if d >= '0' && d <= '9'
+                                && n * 10 + (d as uint - '0' as uint) <= self.ngroups => {
+                            self.iter.next();
+                            n = n * 10 + (d as uint - '0' as uint);
+                        },
+                        _ => break,
+                    }
+                }
+                if n > self.ngroups {
+                    return err(error::InvalidBackreference, i);
+                }
+                Ok(Backref(n, self.flags.case_insensitive))
+            },
+            Some((i, c)) => match perl_class(c) {
+                Some((ranges, negated)) => Ok(Match(inst::Class(ranges, negated))),
+                None => match self.parse_char_escape(i, c) {
+                    Ok(ch) => Ok(Match(inst::Char(ch))),
+                    Err(e) => Err(e),
+                },
+            },
+            None => self.unexpected_end(),
+        }
+    }
+
+    /// Decode the escape of a single char whose letter `c` is at `start`,
+    /// reading the rest of escapes like `\x41`, `\u{1F600}`, `\012` or `\cA`
+    fn parse_char_escape(&mut self, start: uint, c: char) -> Result<char, ParseError> {
+        match c {
+            'x' => self.parse_hex(start, 2),
+            'u' => self.parse_hex(start, 4),
+            '0' if self.octal => Ok(self.parse_octal()),
+            '0' => err(error::OctalDisabled, start),
+            'c' => match self.iter.next() {
+                Some((_, x)) => {
+                    let upper = if x >= 'a' && x <= 'z' { x as u32 - 32 } else { x as u32 };
+                    if upper == '?' as u32 || (upper >= '@' as u32 && upper <= '_' as u32) {
+                        Ok((upper ^ 0x40) as char)
+                    } else {
+                        err(error::InvalidEscape, start)
+                    }
+                },
+                None => self.unexpected_end(),
+            },
+            _ => Ok(c),
+        }
+    }
+
+    /// Parse up to two more octal digits after a `\0`
+    fn parse_octal(&mut self) -> char {
+        let mut n = 0u32;
+        for _ in range(0, 2) {
+            match self.iter.peek() {
+                Some(&(_, d)) if d >= '0' && d <= '7' => {
+                    self.iter.next();
+                    n = n * 8 + (d as u32 - '0' as u32);
+                },
+                _ => break,
+            }
+        }
+        n as char
+    }
+
+    /// Parse exactly `digits` hex digits, or up to 8 of them in braces,
+    /// into the char they encode
+    fn parse_hex(&mut self, start: uint, digits: uint) -> Result<char, ParseError> {
+        let braced = match self.iter.peek() {
+            Some(&(_, '{')) => {
+                self.iter.next();
+                true
+            },
+            _ => false,
+        };
+        let mut n = 0u32;
+        let mut count = 0;
+        while braced || count < digits {
+            match self.iter.next() {
+                Some((_, '}')) if braced && count > 0 => break,
+                Some((_, d)) => match d.to_digit(16) {
+                    Some(v) if count < 8 => {
+                        n = n * 16 + v as u32;
+                        count += 1;
+                    },
+                    _ => return err(error::InvalidEscape, start),
+                },
+                None => return self.unexpected_end(),
+            }
+        }
+        if n > charset::MAX_CHAR as u32 || (n >= 0xd800 && n <= 0xdfff) {
+            return err(error::InvalidEscape, start);
+        }
+        if n > 0xff && !self.flags.unicode {
+            return err(error::NotAByte, start);
+        }
+        Ok(n as char)
+    }
+
+    /// Parse the name of the Unicode property following a `\p` at `start`,
+    /// either a single letter or a braced name like `{Greek}`
+    fn parse_property(&mut self, start: uint) -> Result<~[(char, char)], ParseError> {
+        if !self.flags.unicode {
+            return err(error::UnicodeDisabled, start);
+        }
+        let name = match self.iter.next() {
+            Some((_, '{')) => {
+                let mut name = ~"";
+                loop {
+                    match self.iter.next() {
+                        Some((_, '}')) => break,
+                        Some((_, c)) => name.push_char(c),
+                        None => return self.unexpected_end(),
+                    }
+                }
+                name
             },
+            Some((_, c)) => str::from_char(c),
+            None => return self.unexpected_end(),
+        };
+        match unicode::property(name) {
+            Some(ranges) => Ok(ranges),
+            None => err(error::UnknownProperty(name), start),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<inst::Match, ParseError> {
+        let negated = match self.iter.peek() {
+            Some(&(_, '^')) => {
+                self.iter.next();
+                true
+            },
+            _ => false,
+        };
+        match self.parse_class_set() {
+            Ok(set) => Ok(inst::Class(set.ranges().to_owned(), negated)),
             Err(e) => Err(e),
         }
     }
+
+    /// Parse the members of a bracket expression up to its closing ']'.
+    /// With class set operations, the members read so far are combined
+    /// from left to right with the union of the members up to the next
+    /// `&&` (intersection) or `--` (difference), and `[` opens a nested class.
+    fn parse_class_set(&mut self) -> Result<CharSet, ParseError> {
+        let mut set = CharSet::new();
+        let mut operand = CharSet::new();
+        let mut operator = '|';
+        let mut first = true;
+        loop {
+            match self.iter.peek() {
+                Some(&(_, ']')) if !first => {
+                    self.iter.next();
+                    return Ok(self.combine(set, operand, operator));
+                },
+                _ => (),
+            }
+            let next_operator = if self.class_sets && self.looking_at("&&") {
+                Some('&')
+            } else if self.class_sets && self.looking_at("--") {
+                Some('-')
+            } else {
+                None
+            };
+            match next_operator {
+                Some(o) => {
+                    set = self.combine(set, operand, operator);
+                    operand = CharSet::new();
+                    operator = o;
+                    self.iter.next();
+                    self.iter.next();
+                },
+                None => match self.parse_class_item() {
+                    Ok(item) => operand = operand.union(&item),
+                    Err(e) => return Err(e),
+                },
+            }
+            first = false;
+        }
+    }
+
+    /// Apply a class set operator, '|' standing for the implicit union
+    fn combine(&self, set: CharSet, operand: CharSet, operator: char) -> CharSet {
+        // fold each operand, so that the case of a char removed from a
+        // class cannot come back when the whole class is folded
+        let operand = if !self.flags.case_insensitive {
+            operand
+        } else if self.flags.unicode {
+            operand.fold_case()
+        } else {
+            operand.fold_ascii_case()
+        };
+        match operator {
+            '&' => set.intersect(&operand),
+            '-' => set.intersect(&operand.negate()),
+            _ => set.union(&operand),
+        }
+    }
+
+    /// Parse one member of a bracket expression: a char, a range
+    /// or an escaped class like `\d`
+    fn parse_class_item(&mut self) -> Result<CharSet, ParseError> {
+        let (i, lo) = match self.iter.next() {
+            Some((i, '\\')) => match self.iter.next() {
+                Some((j, 'p')) => match self.parse_property(j) {
+                    Ok(ranges) => return Ok(CharSet::from_ranges(ranges)),
+                    Err(e) => return Err(e),
+                },
+                Some((j, 'P')) => match self.parse_property(j) {
+                    Ok(ranges) => return Ok(CharSet::from_ranges(ranges).negate()),
+                    Err(e) => return Err(e),
+                },
+                Some((j, c)) => match perl_class(c) {
+                    Some((class, false)) => return Ok(CharSet::from_ranges(class)),
+                    Some((class, true)) => return Ok(CharSet::from_ranges(class).negate()),
+                    None => match self.parse_char_escape(j, c) {
+                        Ok(ch) => (i, ch),
+                        Err(e) => return Err(e),
+                    },
+                },
+                None => return self.unexpected_end(),
+            },
+            Some((i, '[')) => match self.iter.peek() {
+                Some(&(_, ':')) => {
+                    self.iter.next();
+                    return self.parse_posix_class(i);
+                },
+                _ if self.class_sets => {
+                    // nested classes are parsed recursively, so they count
+                    // against the nesting limit as groups do
+                    if self.depth == self.nest_limit {
+                        return err(error::NestTooDeep, i);
+                    }
+                    self.depth += 1;
+                    let class = self.parse_class();
+                    self.depth -= 1;
+                    return match class {
+                        Ok(inst::Class(ranges, true)) =>
+                            Ok(CharSet::from_ranges(ranges).negate()),
+                        Ok(inst::Class(ranges, false)) => Ok(CharSet::from_ranges(ranges)),
+                        Ok(_) => fail!("Expected a class."),
+                        Err(e) => Err(e),
+                    };
+                },
+                _ => (i, '['),
+            },
+            // a byte class cannot hold the several bytes of such a char
+            Some((i, c)) if c > '\x7f' && !self.flags.unicode => return err(error::NotAByte, i),
+            Some((i, c)) => (i, c),
+            None => return self.unexpected_end(),
+        };
+        match self.iter.peek() {
+            // unless it starts a difference
+            Some(&(_, '-')) if !(self.class_sets && self.looking_at("--")) => {
+                self.iter.next();
+            },
+            _ => return Ok(CharSet::from_ranges([(lo, lo)])),
+        }
+        let hi = match self.iter.next() {
+            // a trailing '-' stands for itself
+            Some((_, ']')) => return Ok(CharSet::from_ranges([(lo, lo), ('-', '-')])),
+            Some((_, '\\')) => match self.iter.next() {
+                Some((j, c)) => match self.parse_char_escape(j, c) {
+                    Ok(ch) => ch,
+                    Err(e) => return Err(e),
+                },
+                None => return self.unexpected_end(),
+            },
+            Some((j, c)) if c > '\x7f' && !self.flags.unicode => return err(error::NotAByte, j),
+            Some((_, c)) => c,
+            None => return self.unexpected_end(),
+        };
+        if hi < lo {
+            return err(error::InvalidClassRange(lo, hi), i);
+        }
+        Ok(CharSet::from_ranges([(lo, hi)]))
+    }
+
+    /// Parse the rest of a POSIX class like `[:alpha:]` or `[:^digit:]`
+    /// whose '[' is at `start`
+    fn parse_posix_class(&mut self, start: uint) -> Result<CharSet, ParseError> {
+        let negated = match self.iter.peek() {
+            Some(&(_, '^')) => {
+                self.iter.next();
+                true
+            },
+            _ => false,
+        };
+        let mut name = ~"";
+        loop {
+            match self.iter.next() {
+                Some((_, ':')) => break,
+                Some((_, c)) => name.push_char(c),
+                None => return self.unexpected_end(),
+            }
+        }
+        match self.iter.next() {
+            Some((_, ']')) => (),
+            Some((i, c)) => return err(error::UnexpectedChar(c), i),
+            None => return self.unexpected_end(),
+        }
+        match posix_class(name) {
+            Some(ranges) if negated => Ok(CharSet::from_ranges(ranges).negate()),
+            Some(ranges) => Ok(CharSet::from_ranges(ranges)),
+            None => err(error::UnknownPosixClass(name), start),
+        }
+    }
+
+    /// Parse the rest of a counted repetition whose '{' is at `start`
+    fn parse_range(&mut self, start: uint) -> Result<Modifier, ParseError> {
+        let min = match self.parse_number() {
+            Some(n) => n,
+            None => return err(error::InvalidRepetition, start),
+        };
+        let max = match self.iter.next() {
+            Some((_, '}')) => return Ok(Range(min, Some(min))),
+            Some((_, ',')) => self.parse_number(),
+            Some(_) => return err(error::InvalidRepetition, start),
+            None => return self.unexpected_end(),
+        };
+        match self.iter.next() {
+            Some((_, '}')) => (),
+            Some(_) => return err(error::InvalidRepetition, start),
+            None => return self.unexpected_end(),
+        }
+        match max {
+            Some(m) if m < min =>
+                err(error::InvalidRepetitionRange(min, m), start),
+            // counted loops keep their counts in instruction operands
+            _ if cmp::max(min, max.map_default(0, |&m| m)) > code::MAX_OPERAND =>
+                err(error::InvalidRepetition, start),
+            _ => Ok(Range(min, max)),
+        }
+    }
+
+    /// Parse a decimal number, saturating at `uint::max_value`
+    fn parse_number(&mut self) -> Option<uint> {
+        let mut n = None;
+        loop {
+            match self.iter.peek() {
+                Some(&(_, c)) => match c.to_digit(10) {
+                    Some(d) => {
+                        self.iter.next();
+                        let v = n.map_default(0, |&v| v);
+                        n = Some(if v > (uint::max_value - d) / 10 {
+                            uint::max_value
+                        } else {
+                            v * 10 + d
+                        });
+                    },
+                    None => break,
+                },
+                None => break,
+            }
+        }
+        n
+    }
+
+    /// Parse the start of a group whose '(' is at `start`
+    fn parse_group(&mut self, start: uint) -> Result<Opening, ParseError> {
+        // parsing keeps the open groups on a stack of its own, but
+        // `walk` and dropping the AST still recurse into groups
+        if self.depth == self.nest_limit {
+            return err(error::NestTooDeep, start);
+        }
+        match self.iter.peek() {
+            Some(&(_, '?')) => {
+                self.iter.next();
+                self.parse_extension(start)
+            },
+            _ => Ok(self.open_capture(None)),
+        }
+    }
+
+    /// Parse the rest of the start of a group starting with `(?`
+    fn parse_extension(&mut self, start: uint) -> Result<Opening, ParseError> {
+        match self.iter.next() {
+            Some((_, ':')) => Ok(self.open(NonCapture)),
+            Some((_, '#')) => loop {
+                // comments end at the first ')', whatever precedes it
+                match self.iter.next() {
+                    Some((_, ')')) => return Ok(Whole(Group(None, ~[], self.span_from(start)))),
+                    Some(_) => (),
+                    None => return self.unexpected_end(),
+                }
+            },
+            Some((_, '>')) => Ok(self.open(AtomicGroup)),
+            Some((_, '|')) => {
+                self.branch_reset = true;
+                Ok(self.open(NonCapture))
+            },
+            Some((_, '(')) => self.parse_condition(start),
+            Some((_, '=')) => Ok(self.open(Ahead(false))),
+            Some((_, '!')) => Ok(self.open(Ahead(true))),
+            Some((_, '<')) => match self.iter.next() {
+                Some((_, '=')) => Ok(self.open(Behind(false))),
+                Some((_, '!')) => Ok(self.open(Behind(true))),
+                Some((i, c)) => err(error::UnexpectedChar(c), i),
+                None => self.unexpected_end(),
+            },
+            Some((_, 'P')) => match self.iter.next() {
+                Some((_, '<')) => match self.parse_group_name(start) {
+                    Ok(name) => if self.names.contains_key(&name) {
+                        err(error::DuplicateGroupName(name), start)
+                    } else {
+                        Ok(self.open_capture(Some(name)))
+                    },
+                    Err(e) => Err(e),
+                },
+                Some((i, c)) => err(error::UnexpectedChar(c), i),
+                None => self.unexpected_end(),
+            },
+            Some((i, c)) if c == '-' || is_flag(c) => self.parse_flags(start, i, c),
+            Some((i, c)) => err(error::UnknownGroupExtension(c), i),
+            None => self.unexpected_end(),
+        }
+    }
+
+    /// Parse a flag group like `(?i-m)`, which sets flags until the end of
+    /// the enclosing group, or the start of `(?s:...)`, which only sets
+    /// them for its body. The group starts at `start`, and `first` is the
+    /// char right after `(?`, at offset `i`.
+    fn parse_flags(&mut self, start: uint, i: uint, first: char)
+                   -> Result<Opening, ParseError> {
+        let mut flags = self.flags.clone();
+        let mut enable = true;
+        // offset of a '-' not followed by any flag yet
+        let mut dangling = None;
+        let (mut i, mut c) = (i, first);
+        loop {
+            match c {
+                '-' if enable => {
+                    enable = false;
+                    dangling = Some(i);
+                },
+                'i' => flags.case_insensitive = enable,
+                'm' => flags.multi_line = enable,
+                's' => flags.dot_all = enable,
+                'x' => flags.extended = enable,
+                'u' => flags.unicode = enable,
+                ')' | ':' if dangling.is_some() =>
+                    return err(error::MissingFlags, dangling.unwrap()),
+                ')' => {
+                    self.flags = flags;
+                    return Ok(Whole(Group(None, ~[], self.span_from(start))));
+                },
+                ':' => {
+                    let saved = util::replace(&mut self.flags, flags);
+                    self.depth += 1;
+                    return Ok(Open(NonCapture, saved));
+                },
+                _ => return err(error::UnknownFlag(c), i),
+            }
+            if is_flag(c) {
+                dangling = None;
+            }
+            match self.iter.next() {
+                Some((j, d)) => {
+                    i = j;
+                    c = d;
+                },
+                None => return self.unexpected_end(),
+            }
+        }
+    }
+
+    /// Parse a group name up to the closing '>'
+    fn parse_group_name(&mut self, start: uint) -> Result<~str, ParseError> {
+        let mut name = ~"";
+        loop {
+            match self.iter.next() {
+                Some((_, '>')) if !name.is_empty() => break,
+                Some((_, c)) if inst::is_word_char(c) => name.push_char(c),
+                Some(_) => return err(error::InvalidGroupName, start),
+                None => return self.unexpected_end(),
+            }
+        }
+        Ok(name)
+    }
+
+    /// Parse the condition of a conditional group like `(?(1)yes|no)` or
+    /// `(?(<name>)yes)` whose '(' is at `start`, up to the start of its body
+    fn parse_condition(&mut self, start: uint) -> Result<Opening, ParseError> {
+        let group = match self.iter.peek() {
+            Some(&(_, '<')) => {
+                self.iter.next();
+                match self.parse_group_name(start) {
+                    Ok(name) => match self.names.find(&name) {
+                        Some(&idx) => idx,
+                        None => return err(error::UnknownGroupName(name), start),
+                    },
+                    Err(e) => return Err(e),
+                }
+            },
+            _ => match self.parse_number() {
+                Some(n) if n > 0 && n <= self.ngroups => n,
+                _ => return err(error::InvalidCondition, start),
+            },
+        };
+        match self.iter.next() {
+            Some((_, ')')) => Ok(self.open(Condition(group))),
+            Some((i, c)) => err(error::UnexpectedChar(c), i),
+            None => self.unexpected_end(),
+        }
+    }
+
+    /// Start of a capturing group, registering its name if any
+    fn open_capture(&mut self, name: Option<~str>) -> Opening {
+        self.ngroups += 1;
+        let idx = self.ngroups;
+        match name {
+            Some(n) => {
+                self.names.insert(n, idx);
+            },
+            None => (),
+        }
+        self.open(Capture(idx))
+    }
+
+    /// Start of a group whose body comes next, restoring the current
+    /// flags once it closes
+    fn open(&mut self, kind: GroupKind) -> Opening {
+        self.depth += 1;
+        Open(kind, self.flags.clone())
+    }
+
+    /// Item made of the `body` of a group of `kind` whose '(' is at
+    /// `start`, its ')' just parsed
+    fn close_group(&mut self, kind: GroupKind, start: uint,
+                   body: ~[Ast]) -> Result<One, ParseError> {
+        self.depth -= 1;
+        match kind {
+            Capture(idx) => Ok(Group(Some(idx), body, self.span_from(start))),
+            NonCapture => Ok(Group(None, body, self.span_from(start))),
+            AtomicGroup => Ok(Atomic(body)),
+            Ahead(negated) => Ok(LookAhead(body, negated)),
+            Behind(negated) => match width(body) {
+                Some(w) if w <= code::MAX_OPERAND => Ok(LookBehind(body, negated, w)),
+                // only counted repetitions make a lookbehind that long
+                Some(_) => err(error::InvalidRepetition, start),
+                None => err(error::VariableLookbehind, start),
+            },
+            Condition(group) => {
+                let is_or = body.len() == 1 && match body[0] {
+                    Or(*) => true,
+                    _ => false,
+                };
+                if !is_or {
+                    return Ok(Conditional(group, body, ~[]));
+                }
+                let mut body = body;
+                match body.pop() {
+                    Or(branches, _) => {
+                        if branches.len() > 2 {
+                            return err(error::TooManyBranches, start);
+                        }
+                        let mut branches = branches;
+                        let no = branches.pop();
+                        let yes = branches.pop();
+                        Ok(Conditional(group, yes, no))
+                    },
+                    _ => fail!("Expected an alternation."),
+                }
+            },
+        }
+    }
+
+    /// Error for a pattern ending in the middle of a construct
+    fn unexpected_end<T>(&self) -> Result<T, ParseError> {
+        err(error::UnexpectedEnd, self.end)
+    }
+}
+
+/// Fail with an error of `kind` at byte offset `position`
+fn err<T>(kind: error::ErrorKind, position: uint) -> Result<T, ParseError> {
+    Err(ParseError::new(kind, position))
+}
+
+/// Number of chars matched by `ast`, saturating at `uint::max_value`,
+/// or `None` if it can vary
+pub fn width(ast: &[Ast]) -> Option<uint> {
+    fold(ast, |_, widths| {
+        let mut total = 0;
+        for &w in widths.iter() {
+            match w {
+                Some(w) => total = if w > uint::max_value - total {
+                    uint::max_value
+                } else {
+                    total + w
+                },
+                None => return None,
+            }
+        }
+        Some(total)
+    }, |node, widths| match *node {
+        Or(*) => {
+            let mut w = None;
+            for &x in widths.iter() {
+                match (w, x) {
+                    (_, None) => return None,
+                    (None, Some(x)) => w = Some(x),
+                    (Some(y), Some(x)) if x != y => return None,
+                    _ => (),
+                }
+            }
+            Some(w.map_default(0, |&x| x))
+        },
+        Fragment(ref one, ref modifier, _, _) => {
+            let w = match *one {
+                Match(_) => 1,
+                Group(*) | Atomic(_) => match widths[0] {
+                    Some(x) => x,
+                    None => return None,
+                },
+                Backref(*) => return None,
+                Conditional(*) => match (widths[0], widths[1]) {
+                    (Some(x), Some(y)) if x == y => x,
+                    _ => return None,
+                },
+                Assert(_) | LookAhead(*) | LookBehind(*) => 0,
+            };
+            match *modifier {
+                No => Some(w),
+                Range(min, Some(max)) if min == max =>
+                    Some(if w != 0 && min > uint::max_value / w {
+                        uint::max_value
+                    } else {
+                        min * w
+                    }),
+                _ if w == 0 => Some(0),
+                _ => None,
+            }
+        },
+    })
+}
+
+/// Sequences directly inside `ast`, in order
+fn children<'a>(ast: &'a Ast) -> ~[&'a [Ast]] {
+    match *ast {
+        Or(ref branches, _) => branches.iter().map(|b| b.as_slice()).collect(),
+        Fragment(ref one, _, _, _) => inner(one),
+    }
+}
+
+/// Sequences inside `one`, in order
+fn inner<'a>(one: &'a One) -> ~[&'a [Ast]] {
+    match *one {
+        Group(_, ref body, _) | Atomic(ref body) | LookAhead(ref body, _)
+        | LookBehind(ref body, _, _) => ~[body.as_slice()],
+        Conditional(_, ref yes, ref no) => ~[yes.as_slice(), no.as_slice()],
+        Match(_) | Assert(_) | Backref(*) => ~[],
+    }
+}
+
+/// Sequence being folded, with the values of its nodes worked out so far
+/// and those of the sequences inside its next node
+struct FoldFrame<'self, T> {
+    seq: &'self [Ast],
+    values: ~[T],
+    inner: ~[T],
+}
+
+/// Value of `ast` worked out bottom up: `node` makes the value of a node
+/// from those of the sequences inside it, in order, and `seq` the value
+/// of a sequence from those of its nodes. The nodes still to finish are
+/// kept on a stack rather than in recursive calls, so that deeply nested
+/// ASTs do not use up the native stack.
+pub fn fold<T>(ast: &[Ast], seq: &fn(&[Ast], ~[T]) -> T, node: &fn(&Ast, ~[T]) -> T) -> T {
+    let mut stack = ~[FoldFrame { seq: ast, values: ~[], inner: ~[] }];
+    loop {
+        let top = stack.len() - 1;
+        let sequence = stack[top].seq;
+        let i = stack[top].values.len();
+        if i == sequence.len() {
+            let FoldFrame { values: values, _ } = stack.pop();
+            let value = seq(sequence, values);
+            if stack.is_empty() {
+                return value;
+            }
+            let parent = stack.len() - 1;
+            stack[parent].inner.push(value);
+        } else {
+            let nested = children(&sequence[i]);
+            let j = stack[top].inner.len();
+            if j < nested.len() {
+                stack.push(FoldFrame { seq: nested[j], values: ~[], inner: ~[] });
+            } else {
+                let values = util::replace(&mut stack[top].inner, ~[]);
+                let value = node(&sequence[i], values);
+                stack[top].values.push(value);
+            }
+        }
+    }
+}
+
+/// Part of an AST being rebuilt: a sequence with its nodes left to do, in
+/// reverse order, and those done, or a node taken out of its sequence with
+/// the sequences inside it left to do, in reverse order, and those done
+enum RebuildFrame {
+    SeqFrame(~[Ast], ~[Ast]),
+    NodeFrame(Ast, ~[~[Ast]], ~[~[Ast]]),
+}
+
+/// `ast` rebuilt node by node: `enter` sees every node, in the order of the
+/// pattern, before the nodes inside it, and `leave` makes the new node
+/// once those are rebuilt. Like `fold`, this does not recurse.
+pub fn rebuild(ast: ~[Ast], enter: &fn(&mut Ast), leave: &fn(Ast) -> Ast) -> ~[Ast] {
+    let mut ast = ast;
+    ast.reverse();
+    let mut stack = ~[SeqFrame(ast, ~[])];
+    loop {
+        match stack.pop() {
+            SeqFrame(left, done) => {
+                let mut left = left;
+                if left.is_empty() {
+                    if stack.is_empty() {
+                        return done;
+                    }
+                    match stack.pop() {
+                        NodeFrame(node, nested, finished) => {
+                            let mut finished = finished;
+                            finished.push(done);
+                            stack.push(NodeFrame(node, nested, finished));
+                        },
+                        SeqFrame(*) => fail!("Expected a node."),
+                    }
+                } else {
+                    let mut node = left.pop();
+                    enter(&mut node);
+                    let mut nested = take_children(&mut node);
+                    nested.reverse();
+                    stack.push(SeqFrame(left, done));
+                    stack.push(NodeFrame(node, nested, ~[]));
+                }
+            },
+            NodeFrame(node, nested, finished) => {
+                let mut nested = nested;
+                if nested.is_empty() {
+                    let mut node = node;
+                    put_children(&mut node, finished);
+                    let node = leave(node);
+                    match stack.pop() {
+                        SeqFrame(left, done) => {
+                            let mut done = done;
+                            done.push(node);
+                            stack.push(SeqFrame(left, done));
+                        },
+                        NodeFrame(*) => fail!("Expected a sequence."),
+                    }
+                } else {
+                    let mut seq = nested.pop();
+                    seq.reverse();
+                    stack.push(NodeFrame(node, nested, finished));
+                    stack.push(SeqFrame(seq, ~[]));
+                }
+            },
+        }
+    }
+}
+
+/// Take the sequences inside `ast` out of it, in order
+fn take_children(ast: &mut Ast) -> ~[~[Ast]] {
+    match *ast {
+        Or(ref mut branches, _) => util::replace(branches, ~[]),
+        Fragment(ref mut one, _, _, _) => match *one {
+            Group(_, ref mut body, _) | Atomic(ref mut body) | LookAhead(ref mut body, _)
+            | LookBehind(ref mut body, _, _) => ~[util::replace(body, ~[])],
+            Conditional(_, ref mut yes, ref mut no) =>
+                ~[util::replace(yes, ~[]), util::replace(no, ~[])],
+            Match(_) | Assert(_) | Backref(*) => ~[],
+        },
+    }
+}
+
+/// Put back the sequences `take_children` took out of `ast`
+fn put_children(ast: &mut Ast, sequences: ~[~[Ast]]) {
+    let mut sequences = sequences;
+    match *ast {
+        Or(ref mut branches, _) => *branches = sequences,
+        Fragment(ref mut one, _, _, _) => match *one {
+            Group(_, ref mut body, _) | Atomic(ref mut body) | LookAhead(ref mut body, _)
+            | LookBehind(ref mut body, _, _) => *body = sequences.pop(),
+            Conditional(_, ref mut yes, ref mut no) => {
+                *no = sequences.pop();
+                *yes = sequences.pop();
+            },
+            Match(_) | Assert(_) | Backref(*) => (),
+        },
+    }
+}
+
+/// Drop `ast` from the innermost nodes out, as dropping it at once
+/// recurses as deep as its groups nest
+pub fn dismantle(ast: ~[Ast]) {
+    rebuild(ast, |_| (), |node| {
+        let mut node = node;
+        take_children(&mut node);
+        node
+    });
+}
+
+/// Traversal of an AST. Every method defaults to visiting the children of
+/// its node, so an implementation only overrides the nodes it cares about,
+/// calling the matching `walk_*` function to keep going down:
+///
+///     struct GroupCounter { count: uint }
+///
+///     impl Visitor for GroupCounter {
+///         fn visit_one(&mut self, one: &One) {
+///             match *one {
+///                 Group(Some(_), _, _) => self.count += 1,
+///                 _ => (),
+///             }
+///             walk_one(self, one);
+///         }
+///     }
+pub trait Visitor {
+    fn visit_ast(&mut self, ast: &Ast) {
+        walk_ast(self, ast);
+    }
+
+    fn visit_one(&mut self, one: &One) {
+        walk_one(self, one);
+    }
+
+    fn visit_modifier(&mut self, _modifier: &Modifier, _greed: &Greed) {}
+
+    fn visit_match(&mut self, _m: &inst::Match) {}
+
+    fn visit_assertion(&mut self, _assertion: &inst::Assertion) {}
+
+    fn visit_backref(&mut self, _group: uint) {}
+}
+
+/// Visit every fragment of a sequence in order. As the visitor decides
+/// how to go down, the traversal recurses into groups, unlike `fold` and
+/// `rebuild`.
+pub fn walk<V: Visitor>(visitor: &mut V, ast: &[Ast]) {
+    for fragment in ast.iter() {
+        visitor.visit_ast(fragment);
+    }
+}
+
+/// Visit the branches of an alternation, or the repeated item of a
+/// fragment followed by its quantifier
+pub fn walk_ast<V: Visitor>(visitor: &mut V, ast: &Ast) {
+    match *ast {
+        Or(ref branches, _) => for branch in branches.iter() {
+            walk(visitor, *branch);
+        },
+        Fragment(ref one, ref modifier, ref greed, _) => {
+            visitor.visit_one(one);
+            visitor.visit_modifier(modifier, greed);
+        },
+    }
+}
+
+/// Visit what a single item is made of
+pub fn walk_one<V: Visitor>(visitor: &mut V, one: &One) {
+    match *one {
+        Match(ref m) => visitor.visit_match(m),
+        Assert(ref a) => visitor.visit_assertion(a),
+        Backref(group, _) => visitor.visit_backref(group),
+        Group(_, ref ast, _) | Atomic(ref ast) | LookAhead(ref ast, _)
+        | LookBehind(ref ast, _, _) => walk(visitor, *ast),
+        Conditional(_, ref yes, ref no) => {
+            walk(visitor, *yes);
+            walk(visitor, *no);
+        },
+    }
+}
+
+/// Write `ast` back as a pattern that parses, with the default options,
+/// into an equivalent AST. Group names and the flags that were in effect
+/// are not kept, as the AST only records their effect.
+pub fn to_pattern(ast: &[Ast]) -> ~str {
+    fold(ast, |ast, parts| {
+        let mut pattern = ~"";
+        for (fragment, part) in ast.iter().zip(parts.iter()) {
+            match *fragment {
+                // alternatives need a group to bind tighter than their neighbours
+                Or(*) if ast.len() > 1 => pattern.push_str(fmt!("(?:%s)", *part)),
+                _ => pattern.push_str(*part),
+            }
+        }
+        pattern
+    }, |node, parts| match *node {
+        Or(*) => parts.connect("|"),
+        Fragment(ref one, ref modifier, ref greed, _) =>
+            write_fragment(write_one(one, parts), modifier, greed),
+    })
+}
+
+/// `one` written as a pattern, given the sequences inside it written out
+fn write_one(one: &One, parts: &[~str]) -> ~str {
+    match *one {
+        Match(inst::Char(c)) => match c {
+            '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}'
+            | '^' | '$' | '#' | '-' | ' ' => fmt!("\\%c", c),
+            _ if c > ' ' && c <= '~' => str::from_char(c),
+            _ => fmt!("\\x{%x}", c as uint),
+        },
+        Match(inst::Dot) => ~".",
+        Match(inst::Any) => ~"(?s:.)",
+        Match(inst::Class(ref ranges, negated)) => inst::class_to_str(*ranges, negated),
+        Match(inst::Ranges(ref ranges)) => inst::class_to_str(*ranges, false),
+        Group(Some(_), _, _) => fmt!("(%s)", parts[0]),
+        Group(None, _, _) => fmt!("(?:%s)", parts[0]),
+        Assert(ref a) => match *a {
+            inst::Begin => ~"\\A",
+            inst::End => ~"\\z",
+            inst::EndBeforeNewline => ~"\\Z",
+            inst::BeginLine => ~"(?m:^)",
+            inst::EndLine => ~"(?m:$)",
+            inst::WordBoundary => ~"\\b",
+            inst::NotWordBoundary => ~"\\B",
+        },
+        // grouped so that a digit after it is not read as part of it
+        Backref(group, false) => fmt!("(?:\\%u)", group),
+        Backref(group, true) => fmt!("(?i:\\%u)", group),
+        Atomic(_) => fmt!("(?>%s)", parts[0]),
+        Conditional(group, _, ref no) if no.is_empty() => fmt!("(?(%u)%s)", group, parts[0]),
+        Conditional(group, _, _) => fmt!("(?(%u)%s|%s)", group, parts[0], parts[1]),
+        LookAhead(_, negated) => fmt!("(?%s%s)", if negated { "!" } else { "=" }, parts[0]),
+        LookBehind(_, negated, _) =>
+            fmt!("(?<%s%s)", if negated { "!" } else { "=" }, parts[0]),
+    }
+}
+
+/// `one`, already written as `pattern`, followed by its quantifier
+fn write_fragment(pattern: ~str, modifier: &Modifier, greed: &Greed) -> ~str {
+    let mut pattern = pattern;
+    pattern.push_str(match *modifier {
+        No => ~"",
+        Plus => ~"+",
+        QMark => ~"?",
+        Star => ~"*",
+        Range(min, Some(max)) if min == max => fmt!("{%u}", min),
+        Range(min, Some(max)) => fmt!("{%u,%u}", min, max),
+        Range(min, None) => fmt!("{%u,}", min),
+    });
+    match (modifier, greed) {
+        (&No, _) | (_, &Greedy) => (),
+        (_, &Lazy) => pattern.push_char('?'),
+        (_, &Possessive) => pattern.push_char('+'),
+    }
+    pattern
+}
+
+impl ToStr for Ast {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Or(ref branches, _) => {
+                let branches: ~[~str] = branches.iter().map(|b| to_pattern(*b)).collect();
+                branches.connect("|")
+            },
+            Fragment(ref one, ref modifier, ref greed, _) =>
+                write_fragment(one.to_str(), modifier, greed),
+        }
+    }
+}
+
+impl ToStr for One {
+    fn to_str(&self) -> ~str {
+        let parts: ~[~str] = inner(self).iter().map(|&s| to_pattern(s)).collect();
+        write_one(self, parts)
+    }
+}
+
+fn is_flag(c: char) -> bool {
+    c == 'i' || c == 'm' || c == 's' || c == 'x' || c == 'u'
+}
+
+/// Look up the ranges of a Perl-style class escape such as `\d`,
+/// along with whether the escape is the negated form
+fn perl_class(c: char) -> Option<(~[(char, char)], bool)> {
+    match c {
+        'd' => Some((DIGIT.to_owned(), false)),
+        'D' => Some((DIGIT.to_owned(), true)),
+        'w' => Some((WORD.to_owned(), false)),
+        'W' => Some((WORD.to_owned(), true)),
+        's' => Some((SPACE.to_owned(), false)),
+        'S' => Some((SPACE.to_owned(), true)),
+        _ => None,
+    }
+}
+
+/// Make a match accept every case of the chars it accepts, by simple case
+/// folding in Unicode mode, and of the ASCII letters only outside of it
+fn fold_case(m: inst::Match, unicode: bool) -> inst::Match {
+    let fold = |set: CharSet| if unicode { set.fold_case() } else { set.fold_ascii_case() };
+    match m {
+        inst::Char(c) => {
+            let folded = fold(CharSet::from_ranges([(c, c)]));
+            if folded.ranges().len() == 1 {
+                inst::Char(c)
+            } else {
+                inst::Class(folded.ranges().to_owned(), false)
+            }
+        },
+        // folding before negation keeps `[^a]` from matching 'A'
+        inst::Class(ranges, negated) =>
+            inst::Class(fold(CharSet::from_ranges(ranges)).ranges().to_owned(), negated),
+        m => m,
+    }
+}
+
+/// `m` made to match bytes only, as outside of Unicode mode, where a
+/// negated class or `.` matches any other byte but no other char
+fn bytes_only(m: inst::Match) -> inst::Match {
+    let bytes = CharSet::from_ranges(BYTES);
+    let set = match m {
+        inst::Dot => bytes.intersect(&CharSet::from_ranges([('\n', '\n')]).negate()),
+        inst::Any => bytes,
+        inst::Class(ranges, negated) => {
+            let set = CharSet::from_ranges(ranges);
+            let set = if negated { set.negate() } else { set };
+            set.intersect(&bytes)
+        },
+        m => return m,
+    };
+    inst::Class(set.ranges().to_owned(), false)
+}
+
+/// Node matching any char of `set`, repeated as `modifier` says
+fn class_node(set: &CharSet, modifier: Modifier, span: Span) -> Ast {
+    Fragment(Match(inst::Class(set.ranges().to_owned(), false)), modifier, Greedy, span)
+}
+
+/// Node matching one of the branches, as a non-capturing group
+fn alternation(branches: ~[~[Ast]], modifier: Modifier, span: Span) -> Ast {
+    Fragment(Group(None, ~[Or(branches, span)], span), modifier, Greedy, span)
+}
+
+/// The pattern of `\X`, an extended grapheme cluster after UAX #29, every
+/// node spanning the escape: "\r\n", a single control char, or a core
+/// with the prepended chars before it and the marks after it. The core is
+/// a Hangul syllable, a flag made of two regional indicators, emoji joined
+/// by U+200D, or any other char. Other symbols (So) stand in for the
+/// pictographs, and the rules are approximate in a few rare cases.
+///
+/// Like `\R`, the cluster is not atomic: backtracking may take back its
+/// trailing marks.
+fn grapheme_cluster(span: Span) -> One {
+    let property = |name: &str| CharSet::from_ranges(unicode::property(name).unwrap());
+    let zwj = CharSet::from_ranges([('\u200d', '\u200d')]);
+    let extend = property("M").union(&CharSet::from_ranges(EXTEND));
+    let prepend = CharSet::from_ranges(PREPEND);
+    let control = property("Cc").union(&property("Cf")).union(&property("Zl"))
+                                .union(&property("Zp"))
+                                .intersect(&extend.union(&prepend).negate());
+    let (l, v, t) = (CharSet::from_ranges(HANGUL_L), CharSet::from_ranges(HANGUL_V),
+                     CharSet::from_ranges(HANGUL_T));
+    // syllables with no trailing jamo come every 28 code points
+    let mut lv = CharSet::new();
+    let mut c = '\uac00' as uint;
+    while c <= '\ud7a3' as uint {
+        lv.insert(c as char, c as char);
+        c += 28;
+    }
+    let lvt = CharSet::from_ranges(HANGUL_SYLLABLES).intersect(&lv.negate());
+    let regional = CharSet::from_ranges(REGIONAL_INDICATOR);
+    let pictograph = property("So");
+    let extend_no_zwj = extend.intersect(&zwj.negate());
+
+    let hangul = ~[class_node(&l, Star, span),
+                   alternation(~[~[class_node(&v, Plus, span)],
+                                 ~[class_node(&lv, No, span), class_node(&v, Star, span)],
+                                 ~[class_node(&lvt, No, span)]], No, span),
+                   class_node(&t, Star, span)];
+    let joined = ~[class_node(&zwj, No, span), class_node(&pictograph, No, span),
+                   class_node(&extend_no_zwj, Star, span)];
+    let emoji = ~[class_node(&pictograph, No, span), class_node(&extend_no_zwj, Star, span),
+                  Fragment(Group(None, joined, span), Star, Greedy, span)];
+    let core = alternation(~[hangul,
+                             ~[class_node(&l, Plus, span)],
+                             ~[class_node(&t, Plus, span)],
+                             ~[class_node(&regional, No, span), class_node(&regional, No, span)],
+                             emoji,
+                             ~[class_node(&control.negate(), No, span)]], No, span);
+    let cluster = ~[class_node(&prepend, Star, span), core, class_node(&extend, Star, span)];
+    let crlf = ~[Fragment(Match(inst::Char('\r')), No, Greedy, span),
+                 Fragment(Match(inst::Char('\n')), No, Greedy, span)];
+    Group(None, ~[Or(~[crlf, ~[class_node(&control, No, span)], cluster], span)], span)
+}
+
+/// Look up the ranges of a POSIX class name such as `alpha`
+fn posix_class(name: &str) -> Option<~[(char, char)]> {
+    let ranges = match name {
+        "alnum" => ALNUM,
+        "alpha" => ALPHA,
+        "blank" => BLANK,
+        "cntrl" => CNTRL,
+        "digit" => DIGIT,
+        "graph" => GRAPH,
+        "lower" => LOWER,
+        "print" => PRINT,
+        "punct" => PUNCT,
+        "space" => SPACE,
+        "upper" => UPPER,
+        "word" => WORD,
+        "xdigit" => XDIGIT,
+        _ => return None,
+    };
+    Some(ranges.to_owned())
 }
@@ -0,0 +1,739 @@
+use std::cmp;
+use std::hashmap::HashMap;
+use std::str::CharRange;
+use std::uint;
+use std::util;
+use std::vec;
+
+use extra::sort;
+
+use compile;
+use compile::charset;
+use compile::code;
+use compile::error;
+use compile::error::ParseError;
+use compile::inst;
+
+// classes of the chars on either side of a position, as far as the
+// assertions are concerned
+static EDGE: uint = 0;
+static NEWLINE: uint = 1;
+static WORD: uint = 2;
+static OTHER: uint = 3;
+
+/// chars `inst::is_word_char` accepts
+static WORD_RANGES: &'static [(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+
+/// chars below this have their transitions kept in a table, the others
+/// in a map
+static TABLE_SIZE: uint = 128;
+
+/// most states cached before the cache is cleared
+static MAX_STATES: uint = 4096;
+
+/// Whether the DFA can run `code`: it has no lookaround, backreference,
+/// conditional, atomic group, loop counter or `\Z`
+pub fn supports(code: &code::Code) -> bool {
+    range(0, code.len()).all(|pc| match code.op(pc) {
+        code::Match(_) | code::Jmp(_) | code::Split(*) | code::Save(_) | code::Succeed => true,
+        code::Assert(inst::EndBeforeNewline) => false,
+        code::Assert(_) => true,
+        _ => false,
+    })
+}
+
+struct State {
+    /// addresses the threads are at, highest priority first, before
+    /// following any jump from them
+    pcs: ~[uint],
+    /// class of the char before the position
+    before: uint,
+    /// whether a new thread still starts at every position, which stops
+    /// once a match is found as it would have a lower priority
+    searching: bool,
+    /// next state, and whether a match ends before the char, for every
+    /// char below `TABLE_SIZE` already seen
+    table: ~[Option<(uint, bool)>],
+    /// same for the other chars
+    others: HashMap<char, (uint, bool)>,
+    /// whether a match ends at the end of the text, once known
+    at_end: Option<bool>,
+}
+
+/// Lazy DFA, whose states are the sets of threads of the NFA simulation
+/// at a position, built as the text needs them and then cached. It finds
+/// where the leftmost-first match ends, but not the spans of its groups;
+/// a second one running the reversed program backwards from there finds
+/// where it starts. It keeps no program of its own, but caches the states
+/// of the one it is given, so it must always be given the same one.
+pub struct LazyDfa {
+    /// keep the threads of a lower priority than a match, to find the
+    /// longest match rather than the leftmost-first one
+    longest: bool,
+    states: ~[State],
+    /// state with every key, the key being the class of the char before,
+    /// whether it is searching, then its addresses
+    index: HashMap<~[uint], uint>,
+}
+
+impl LazyDfa {
+    /// DFA with no state cached yet, for a program it supports
+    pub fn new() -> LazyDfa {
+        LazyDfa {
+            longest: false,
+            states: ~[],
+            index: HashMap::new(),
+        }
+    }
+
+    /// DFA finding the longest match, to be run by `find_longest_end`, or
+    /// by `find_start` on a program reversed by `CompiledRegexp::reverse`
+    pub fn longest() -> LazyDfa {
+        let mut dfa = LazyDfa::new();
+        dfa.longest = true;
+        dfa
+    }
+
+    /// Byte offset where the longest match starting exactly at `start`
+    /// ends, the text before `start` being seen by the assertions
+    pub fn find_longest_end(&mut self, code: &code::Code, string: &str, start: uint)
+                            -> Option<uint> {
+        let before = if start == 0 {
+            EDGE
+        } else {
+            class(string.char_range_at_reverse(start).ch)
+        };
+        let mut s = self.state(~[0], before, false);
+        let mut end = None;
+        let mut i = start;
+        while i < string.len() {
+            if self.states.len() >= MAX_STATES {
+                s = self.flush(s);
+            }
+            let CharRange {ch, next} = string.char_range_at(i);
+            let (t, matched) = self.step(code, s, ch);
+            if matched {
+                end = Some(i);
+            }
+            s = t;
+            if self.states[s].pcs.is_empty() {
+                return end;
+            }
+            i = next;
+        }
+        if self.at_end(code, s) {
+            end = Some(string.len());
+        }
+        end
+    }
+
+    /// Byte offset where the match ending at `end` starts, found by reading
+    /// the text backwards from `end` down to `start` at most. As the
+    /// reversed program matches the longest way back, that is the leftmost
+    /// start, which is where the leftmost-first match ending at `end` that
+    /// `find_end` found from `start` starts.
+    pub fn find_start(&mut self, code: &code::Code, string: &str, start: uint, end: uint)
+                      -> Option<uint> {
+        // the char after the match is the one read before it
+        let before = if end == string.len() {
+            EDGE
+        } else {
+            class(string.char_at(end))
+        };
+        let mut s = self.state(~[0], before, false);
+        let mut found = None;
+        let mut i = end;
+        while i > start {
+            if self.states.len() >= MAX_STATES {
+                s = self.flush(s);
+            }
+            let CharRange {ch, next} = string.char_range_at_reverse(i);
+            let (t, matched) = self.step(code, s, ch);
+            if matched {
+                found = Some(i);
+            }
+            s = t;
+            if self.states[s].pcs.is_empty() {
+                return found;
+            }
+            i = next;
+        }
+        let matched = if start == 0 {
+            self.at_end(code, s)
+        } else {
+            let (_, matched) = self.step(code, s, string.char_range_at_reverse(start).ch);
+            matched
+        };
+        if matched {
+            found = Some(start);
+        }
+        found
+    }
+
+    /// Byte offset where the leftmost-first match starting at `start` or
+    /// later ends, or where the first match found ends if `stop_early` is
+    /// set, the one before `start` being seen by the assertions
+    pub fn find_end(&mut self, code: &code::Code, string: &str, start: uint, stop_early: bool)
+                    -> Option<uint> {
+        let before = if start == 0 {
+            EDGE
+        } else {
+            class(string.char_range_at_reverse(start).ch)
+        };
+        let mut s = self.state(~[], before, true);
+        let mut end = None;
+        let mut i = start;
+        while i < string.len() {
+            if self.states.len() >= MAX_STATES {
+                s = self.flush(s);
+            }
+            let CharRange {ch, next} = string.char_range_at(i);
+            let (t, matched) = self.step(code, s, ch);
+            if matched {
+                end = Some(i);
+                if stop_early {
+                    return end;
+                }
+            }
+            s = t;
+            if self.states[s].pcs.is_empty() && !self.states[s].searching {
+                return end;
+            }
+            i = next;
+        }
+        if self.at_end(code, s) {
+            end = Some(string.len());
+        }
+        end
+    }
+
+    /// Index of the state with the given threads, adding it if needed
+    fn state(&mut self, pcs: ~[uint], before: uint, searching: bool) -> uint {
+        let mut key = ~[before, searching as uint];
+        key.push_all(pcs);
+        match self.index.find(&key) {
+            Some(&s) => return s,
+            None => (),
+        }
+        self.states.push(State {
+            pcs: pcs,
+            before: before,
+            searching: searching,
+            table: vec::from_elem(TABLE_SIZE, None),
+            others: HashMap::new(),
+            at_end: None,
+        });
+        self.index.insert(key, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    /// Drop every cached state but `s`, returning its new index
+    fn flush(&mut self, s: uint) -> uint {
+        let pcs = self.states[s].pcs.clone();
+        let (before, searching) = (self.states[s].before, self.states[s].searching);
+        self.states = ~[];
+        self.index = HashMap::new();
+        self.state(pcs, before, searching)
+    }
+
+    /// Move from state `s` over `c`, returning the next state and whether
+    /// a match ends before `c`
+    fn step(&mut self, code: &code::Code, s: uint, c: char) -> (uint, bool) {
+        let cached = if (c as uint) < TABLE_SIZE {
+            self.states[s].table[c as uint]
+        } else {
+            self.states[s].others.find(&c).map(|&&t| t)
+        };
+        match cached {
+            Some(t) => return t,
+            None => (),
+        }
+        let (threads, matched) = self.closure(code, s, class(c));
+        let mut pcs = ~[];
+        for &pc in threads.iter() {
+            match code.op(pc) {
+                code::Match(m) if m.matches(c) => pcs.push(pc + 1),
+                _ => (),
+            }
+        }
+        let searching = self.states[s].searching && !matched;
+        let t = (self.state(pcs, class(c), searching), matched);
+        if (c as uint) < TABLE_SIZE {
+            self.states[s].table[c as uint] = Some(t);
+        } else {
+            self.states[s].others.insert(c, t);
+        }
+        t
+    }
+
+    /// Whether a match ends at the end of the text in state `s`
+    fn at_end(&mut self, code: &code::Code, s: uint) -> bool {
+        match self.states[s].at_end {
+            Some(matched) => matched,
+            None => {
+                let (_, matched) = self.closure(code, s, EDGE);
+                self.states[s].at_end = Some(matched);
+                matched
+            },
+        }
+    }
+
+    /// Follow the threads of state `s` through jumps and the assertions
+    /// holding before a char of class `after`, returning the `Match`
+    /// instructions they reach in priority order, and whether one of them
+    /// succeeds first, in which case the ones after it are dropped unless
+    /// looking for the longest match
+    fn closure(&self, code: &code::Code, s: uint, after: uint) -> (~[uint], bool) {
+        let state = &self.states[s];
+        let mut seen = vec::from_elem(code.len(), false);
+        let mut threads = ~[];
+        let mut matched = false;
+        // the thread starting here has the lowest priority
+        let mut stack = if state.searching { ~[0u] } else { ~[] };
+        for &pc in state.pcs.rev_iter() {
+            stack.push(pc);
+        }
+        while !stack.is_empty() {
+            let pc = stack.pop();
+            if !seen[pc] {
+                seen[pc] = true;
+                match code.op(pc) {
+                    code::Match(_) => threads.push(pc),
+                    code::Succeed if self.longest => matched = true,
+                    code::Succeed => return (threads, true),
+                    code::Jmp(a) => stack.push(a),
+                    code::Split(a, b) => {
+                        stack.push(b);
+                        stack.push(a);
+                    },
+                    code::Save(_) => stack.push(pc + 1),
+                    code::Assert(ref a) => if holds(a, state.before, after) {
+                        stack.push(pc + 1);
+                    },
+                    _ => fail!("Instruction not supported by the DFA."),
+                }
+            }
+        }
+        (threads, matched)
+    }
+}
+
+/// DFA built ahead of time with the whole of its transition table, for
+/// patterns searched in so much text that determinizing them up front
+/// pays off. Like `LazyDfa` it finds where matches end:
+///
+///     let dfa = re::RegexpBuilder::new("[a-z]+@").dfa_state_limit(100).build_dfa().unwrap();
+///     assert_eq!(dfa.find_end("mail: x@y", 0), Some(8));
+pub struct Dfa {
+    /// first char of every class of chars the DFA cannot tell apart,
+    /// in increasing order, the first one being '\x00'
+    priv classes: ~[char],
+    /// next state of every state over every class, at index
+    /// `state * classes.len() + class`, shifted left by one and with the
+    /// lowest bit set if a match ends before the char
+    priv table: ~[uint],
+    /// whether a match ends at the end of the text, for every state
+    priv at_end: ~[bool],
+    /// whether no match can be found any more, for every state
+    priv dead: ~[bool],
+    /// state to start in, for every class of the char before the start
+    priv starts: ~[uint],
+}
+
+impl Dfa {
+    /// Determinize `program`, failing if it uses features only the NFA
+    /// simulation or the backtracking engine support, or if it needs more
+    /// than `limit` states, and never more than `code::MAX_OPERAND`, the
+    /// most `to_bytes` encodes
+    pub fn new(program: &compile::CompiledRegexp, limit: uint) -> Result<Dfa, ParseError> {
+        let limit = cmp::min(limit, code::MAX_OPERAND);
+        let code = &program.code;
+        if !supports(code) {
+            return Err(ParseError::without_position(error::UnsupportedByDfa));
+        }
+        let classes = alphabet(code);
+        let mut lazy = LazyDfa::new();
+        let mut starts = ~[];
+        for &before in [EDGE, NEWLINE, WORD, OTHER].iter() {
+            starts.push(lazy.state(~[], before, true));
+        }
+        let (mut table, mut at_end, mut dead) = (~[], ~[], ~[]);
+        // states are numbered as they are found, so this visits them all
+        let mut s = 0;
+        while s < lazy.states.len() {
+            for &c in classes.iter() {
+                let (t, matched) = lazy.step(code, s, c);
+                table.push(t << 1 | matched as uint);
+            }
+            if lazy.states.len() > limit {
+                return Err(ParseError::without_position(error::TooManyStates(limit)));
+            }
+            at_end.push(lazy.at_end(code, s));
+            dead.push(lazy.states[s].pcs.is_empty() && !lazy.states[s].searching);
+            s += 1;
+        }
+        let mut dfa = Dfa {
+            classes: classes,
+            table: table,
+            at_end: at_end,
+            dead: dead,
+            starts: starts,
+        };
+        dfa.minimize();
+        Ok(dfa)
+    }
+
+    /// Number of states
+    pub fn len(&self) -> uint {
+        self.at_end.len()
+    }
+
+    /// Encode the DFA so that it can be built once, when a program is, and
+    /// then loaded with `from_bytes`: the bytes of `MAGIC`, then 32-bit
+    /// little-endian words giving the number of classes and of states, the
+    /// start states, the first char of every class and the table, and at
+    /// last a byte of flags for every state
+    pub fn to_bytes(&self) -> ~[u8] {
+        let mut bytes = MAGIC.to_owned();
+        push_word(&mut bytes, self.classes.len());
+        push_word(&mut bytes, self.len());
+        for &s in self.starts.iter() {
+            push_word(&mut bytes, s);
+        }
+        for &c in self.classes.iter() {
+            push_word(&mut bytes, c as uint);
+        }
+        for &t in self.table.iter() {
+            push_word(&mut bytes, t);
+        }
+        for s in range(0, self.len()) {
+            bytes.push(self.at_end[s] as u8 | (self.dead[s] as u8) << 1);
+        }
+        bytes
+    }
+
+    /// Decode a DFA encoded by `to_bytes`, or return `None` if the bytes
+    /// are not one
+    pub fn from_bytes(bytes: &[u8]) -> Option<Dfa> {
+        if !bytes.starts_with(MAGIC) {
+            return None;
+        }
+        let mut words = ~[];
+        let mut i = MAGIC.len();
+        // the two counts, to know how many more words there are
+        while i + 4 <= bytes.len() && words.len() < 2 {
+            words.push(read_word(bytes, i));
+            i += 4;
+        }
+        if words.len() < 2 {
+            return None;
+        }
+        let (k, n) = (words[0], words[1]);
+        if k == 0 || n == 0 || n > code::MAX_OPERAND || k > code::MAX_OPERAND
+            || encoded_len(k, n) != Some(bytes.len() - i) {
+            return None;
+        }
+        let starts: ~[uint] = range(0, 4).map(|j| read_word(bytes, i + 4 * j)).collect();
+        i += 16;
+        let mut classes: ~[char] = ~[];
+        for j in range(0, k) {
+            let c = read_word(bytes, i + 4 * j);
+            // classes start at '\x00' and go up, never into surrogates
+            let ordered = match classes.last_opt() {
+                Some(&last) => c > last as uint,
+                None => c == 0,
+            };
+            if !ordered || c > charset::MAX_CHAR as uint || (c >= 0xd800 && c < 0xe000) {
+                return None;
+            }
+            classes.push(c as char);
+        }
+        i += 4 * k;
+        let table: ~[uint] = range(0, n * k).map(|j| read_word(bytes, i + 4 * j)).collect();
+        i += 4 * n * k;
+        if starts.iter().any(|&s| s >= n) || table.iter().any(|&t| t >> 1 >= n) {
+            return None;
+        }
+        let flags = bytes.slice_from(i);
+        if flags.iter().any(|&f| f > 3) {
+            return None;
+        }
+        Some(Dfa {
+            classes: classes,
+            table: table,
+            at_end: flags.iter().map(|&f| f & 1 == 1).collect(),
+            dead: flags.iter().map(|&f| f & 2 == 2).collect(),
+            starts: starts,
+        })
+    }
+
+    /// Whether `string` contains a match
+    pub fn is_match(&self, string: &str) -> bool {
+        self.search(string, 0, true).is_some()
+    }
+
+    /// Byte offset where the leftmost-first match starting at `start` or
+    /// later ends, the text before `start` being seen by the assertions
+    pub fn find_end(&self, string: &str, start: uint) -> Option<uint> {
+        self.search(string, start, false)
+    }
+
+    fn search(&self, string: &str, start: uint, stop_early: bool) -> Option<uint> {
+        let mut s = if start == 0 {
+            self.starts[EDGE]
+        } else {
+            self.starts[class(string.char_range_at_reverse(start).ch)]
+        };
+        let mut end = None;
+        let mut i = start;
+        while i < string.len() {
+            let CharRange {ch, next} = string.char_range_at(i);
+            let t = self.table[s * self.classes.len() + self.class_of(ch)];
+            if t & 1 == 1 {
+                end = Some(i);
+                if stop_early {
+                    return end;
+                }
+            }
+            s = t >> 1;
+            if self.dead[s] {
+                return end;
+            }
+            i = next;
+        }
+        if self.at_end[s] {
+            end = Some(string.len());
+        }
+        end
+    }
+
+    /// Merge the states no text tells apart, by Hopcroft's partition
+    /// refinement, then the classes of chars no state tells apart, leaving
+    /// the smallest DFA matching the same
+    fn minimize(&mut self) {
+        let (n, k) = (self.len(), self.classes.len());
+        // states are first told apart by what they match right away
+        let mut blocks: ~[~[uint]] = ~[];
+        let mut block_of = vec::from_elem(n, 0u);
+        let mut signatures = HashMap::new();
+        for s in range(0, n) {
+            let mut signature = ~[self.at_end[s] as uint, self.dead[s] as uint];
+            for c in range(0, k) {
+                signature.push(self.table[s * k + c] & 1);
+            }
+            let b = match signatures.find(&signature) {
+                Some(&b) => b,
+                None => blocks.len(),
+            };
+            if b == blocks.len() {
+                blocks.push(~[]);
+                signatures.insert(signature, b);
+            }
+            blocks[b].push(s);
+            block_of[s] = b;
+        }
+        // states moving to every state over every class
+        let mut inverse = vec::from_elem(n * k, ~[]);
+        for s in range(0, n) {
+            for c in range(0, k) {
+                inverse[(self.table[s * k + c] >> 1) * k + c].push(s);
+            }
+        }
+        let mut pending = ~[];
+        let mut queued = ~[];
+        for b in range(0, blocks.len()) {
+            for c in range(0, k) {
+                pending.push((b, c));
+                queued.push(true);
+            }
+        }
+        // index in `touched` of every block, plus one
+        let mut hit = vec::from_elem(n, 0u);
+        while !pending.is_empty() {
+            let (a, c) = pending.pop();
+            queued[a * k + c] = false;
+            // the states moving into `a` over `c`, by block
+            let mut touched = ~[];
+            let mut members: ~[~[uint]] = ~[];
+            for &t in blocks[a].iter() {
+                for &s in inverse[t * k + c].iter() {
+                    let b = block_of[s];
+                    if hit[b] == 0 {
+                        touched.push(b);
+                        members.push(~[]);
+                        hit[b] = touched.len();
+                    }
+                    members[hit[b] - 1].push(s);
+                }
+            }
+            for (i, &b) in touched.iter().enumerate() {
+                hit[b] = 0;
+                if members[i].len() < blocks[b].len() {
+                    let new = blocks.len();
+                    for &s in members[i].iter() {
+                        block_of[s] = new;
+                    }
+                    let rest = blocks[b].iter().filter(|&&s| block_of[s] == b).map(|&s| s);
+                    blocks[b] = rest.collect();
+                    blocks.push(util::replace(&mut members[i], ~[]));
+                    for _ in range(0, k) {
+                        queued.push(false);
+                    }
+                    // splitting by the smaller half is enough, unless the
+                    // whole block was still to be split by
+                    let smaller = if blocks[new].len() <= blocks[b].len() { new } else { b };
+                    for d in range(0, k) {
+                        let split = if queued[b * k + d] { new } else { smaller };
+                        pending.push((split, d));
+                        queued[split * k + d] = true;
+                    }
+                }
+            }
+        }
+
+        // one state per block, moving as its first state does
+        let mut table = ~[];
+        let (mut at_end, mut dead) = (~[], ~[]);
+        for block in blocks.iter() {
+            let s = block[0];
+            for c in range(0, k) {
+                let t = self.table[s * k + c];
+                table.push(block_of[t >> 1] << 1 | t & 1);
+            }
+            at_end.push(self.at_end[s]);
+            dead.push(self.dead[s]);
+        }
+        let starts = self.starts.iter().map(|&s| block_of[s]).collect();
+        // a class is kept only if some state moves differently over it
+        // than over the one before
+        let n = blocks.len();
+        let kept: ~[uint] = range(0, k).filter(|&c| {
+            c == 0 || range(0, n).any(|s| table[s * k + c] != table[s * k + c - 1])
+        }).collect();
+        self.classes = kept.iter().map(|&c| self.classes[c]).collect();
+        self.table = ~[];
+        for s in range(0, n) {
+            for &c in kept.iter() {
+                self.table.push(table[s * k + c]);
+            }
+        }
+        self.at_end = at_end;
+        self.dead = dead;
+        self.starts = starts;
+    }
+
+    /// Index of the class `c` falls in
+    fn class_of(&self, c: char) -> uint {
+        // last class starting at or before `c`
+        let (mut low, mut high) = (0, self.classes.len());
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if self.classes[mid] <= c {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+/// bytes `Dfa::to_bytes` starts with, ending with the format version
+static MAGIC: &'static [u8] = &['r' as u8, 'e' as u8, '-' as u8, 'd' as u8, 'f' as u8,
+                                'a' as u8, 1];
+
+/// Number of bytes `Dfa::to_bytes` writes after the two counts for `k`
+/// classes and `n` states, or `None` if that overflows a `uint`, as it
+/// may for counts read from bytes that are not a DFA
+fn encoded_len(k: uint, n: uint) -> Option<uint> {
+    // the start states, the classes and the table in words, then the flags
+    let words = match checked_mul(n, k) {
+        Some(table) => checked_add(table, 4 + k),
+        None => None,
+    };
+    match words {
+        Some(words) => match checked_mul(words, 4) {
+            Some(len) => checked_add(len, n),
+            None => None,
+        },
+        None => None,
+    }
+}
+
+/// Addition, or `None` if it overflows
+fn checked_add(a: uint, b: uint) -> Option<uint> {
+    if a > uint::max_value - b { None } else { Some(a + b) }
+}
+
+/// Multiplication, or `None` if it overflows
+fn checked_mul(a: uint, b: uint) -> Option<uint> {
+    if a != 0 && b > uint::max_value / a { None } else { Some(a * b) }
+}
+
+/// Append `word` as 32 bits, enough for any count, state or char of a DFA
+/// as `Dfa::new` builds no more than `code::MAX_OPERAND` states
+fn push_word(bytes: &mut ~[u8], word: uint) {
+    for shift in [0u, 8, 16, 24].iter() {
+        bytes.push((word >> *shift) as u8);
+    }
+}
+
+fn read_word(bytes: &[u8], i: uint) -> uint {
+    range(0u, 4).fold(0u, |word, j| word | (bytes[i + j] as uint) << 8 * j)
+}
+
+/// First char of every class of chars that no instruction of `code` and
+/// no assertion tells apart, in increasing order
+fn alphabet(code: &code::Code) -> ~[char] {
+    let mut bounds = ~[0u, '\n' as uint, '\n' as uint + 1];
+    for &(lo, hi) in WORD_RANGES.iter() {
+        bounds.push(lo as uint);
+        bounds.push(hi as uint + 1);
+    }
+    for pc in range(0, code.len()) {
+        match code.op(pc) {
+            code::Match(&inst::Char(c)) => {
+                bounds.push(c as uint);
+                bounds.push(c as uint + 1);
+            },
+            code::Match(&inst::Class(ref ranges, _)) | code::Match(&inst::Ranges(ref ranges)) =>
+                for &(lo, hi) in ranges.iter() {
+                    bounds.push(lo as uint);
+                    bounds.push(hi as uint + 1);
+                },
+            // '\n' is already a class of its own
+            _ => (),
+        }
+    }
+    // no char is a surrogate, so a class reaching them starts after them
+    let mut bounds: ~[uint] = bounds.iter()
+        .map(|&b| if b >= 0xd800 && b < 0xe000 { 0xe000 } else { b })
+        .filter(|&b| b <= charset::MAX_CHAR as uint)
+        .collect();
+    sort::quick_sort3(bounds);
+    bounds.dedup();
+    bounds.iter().map(|&b| b as char).collect()
+}
+
+fn class(c: char) -> uint {
+    if c == '\n' {
+        NEWLINE
+    } else if inst::is_word_char(c) {
+        WORD
+    } else {
+        OTHER
+    }
+}
+
+/// Whether `assertion` holds between chars of the classes `before` and `after`
+fn holds(assertion: &inst::Assertion, before: uint, after: uint) -> bool {
+    match *assertion {
+        inst::Begin => before == EDGE,
+        inst::End => after == EDGE,
+        inst::BeginLine => before == EDGE || before == NEWLINE,
+        inst::EndLine => after == EDGE || after == NEWLINE,
+        inst::WordBoundary => (before == WORD) != (after == WORD),
+        inst::NotWordBoundary => (before == WORD) == (after == WORD),
+        inst::EndBeforeNewline => fail!("Assertion not supported by the DFA."),
+    }
+}
@@ -0,0 +1,180 @@
+#!/usr/bin/env python3
+# Generates src/compile/unicode.rs, the range tables behind `\p{...}`
+# and the simple case folding behind `(?i)`.
+#
+# General categories come straight from Python's unicodedata module.
+# unicodedata has no Script property, so script tables are built from
+# the prefix of each character's name (e.g. "GREEK SMALL LETTER ALPHA").
+# This covers the letters of each script but misses the few characters
+# whose names do not carry the script name.
+#
+# unicodedata has no CaseFolding table either. str.casefold() gives the
+# full folding, which is the simple one whenever it is a single char;
+# otherwise the simple folding is the lowercase char, if there is a
+# single one, as for U+1E9E, and the char itself, as for U+00DF.
+#
+# Usage: python3 src/etc/unicode.py > src/compile/unicode.rs
+
+import sys
+import unicodedata
+
+CATEGORIES = [
+    "Lu", "Ll", "Lt", "Lm", "Lo",
+    "Mn", "Mc", "Me",
+    "Nd", "Nl", "No",
+    "Pc", "Pd", "Ps", "Pe", "Pi", "Pf", "Po",
+    "Sm", "Sc", "Sk", "So",
+    "Zs", "Zl", "Zp",
+    # surrogates are not chars, and unassigned code points are left out
+    "Cc", "Cf", "Co",
+]
+
+SCRIPTS = [
+    ("Arabic", ["ARABIC "]),
+    ("Armenian", ["ARMENIAN "]),
+    ("Bengali", ["BENGALI "]),
+    ("Cyrillic", ["CYRILLIC "]),
+    ("Devanagari", ["DEVANAGARI "]),
+    ("Georgian", ["GEORGIAN "]),
+    ("Greek", ["GREEK "]),
+    ("Han", ["CJK UNIFIED IDEOGRAPH", "CJK COMPATIBILITY IDEOGRAPH"]),
+    ("Hangul", ["HANGUL "]),
+    ("Hebrew", ["HEBREW "]),
+    ("Hiragana", ["HIRAGANA "]),
+    ("Katakana", ["KATAKANA "]),
+    ("Latin", ["LATIN "]),
+    ("Thai", ["THAI "]),
+]
+
+
+def to_ranges(codepoints):
+    ranges = []
+    for cp in codepoints:
+        if ranges and ranges[-1][1] + 1 == cp:
+            ranges[-1][1] = cp
+        else:
+            ranges.append([cp, cp])
+    return ranges
+
+
+def escape(cp):
+    if cp <= 0xffff:
+        return "'\\u%04x'" % cp
+    return "'\\U%08x'" % cp
+
+
+def table(name):
+    return name.upper()
+
+
+def emit_table(out, name, ranges):
+    out.write("static %s: &'static [(char, char)] = &[\n" % name)
+    for lo, hi in ranges:
+        out.write("    (%s, %s),\n" % (escape(lo), escape(hi)))
+    out.write("];\n\n")
+
+
+def simple_fold(cp):
+    c = chr(cp)
+    for folded in (c.casefold(), c.lower()):
+        if len(folded) == 1:
+            return ord(folded)
+    return cp
+
+
+def case_pairs():
+    classes = {}
+    for cp in range(0x110000):
+        if 0xd800 <= cp < 0xe000:
+            continue
+        classes.setdefault(simple_fold(cp), set([cp])).add(cp)
+    pairs = []
+    for target, members in classes.items():
+        members.add(target)
+        for a in members:
+            for b in members:
+                if a != b:
+                    pairs.append((a, b))
+    return sorted(pairs)
+
+
+def emit_case_pairs(out):
+    out.write("/// Pairs of distinct chars that simple case folding makes the same,\n")
+    out.write("/// both ways round, in increasing order\n")
+    out.write("static CASE_PAIRS: &'static [(char, char)] = &[\n")
+    for a, b in case_pairs():
+        out.write("    (%s, %s),\n" % (escape(a), escape(b)))
+    out.write("];\n\n")
+    out.write("/// Pairs of `CASE_PAIRS` whose first char is from `lo` to `hi`, pairing\n")
+    out.write("/// each of them with the chars that only differ from it in case\n")
+    out.write("pub fn case_pairs(lo: char, hi: char) -> &'static [(char, char)] {\n")
+    out.write("    CASE_PAIRS.slice(first_pair(lo as uint), first_pair(hi as uint + 1))\n")
+    out.write("}\n\n")
+    out.write("/// Index of the first pair of `CASE_PAIRS` whose first char is `c` or\n")
+    out.write("/// above, found by binary search\n")
+    out.write("fn first_pair(c: uint) -> uint {\n")
+    out.write("    let (mut low, mut high) = (0, CASE_PAIRS.len());\n")
+    out.write("    while low < high {\n")
+    out.write("        let mid = (low + high) / 2;\n")
+    out.write("        let (first, _) = CASE_PAIRS[mid];\n")
+    out.write("        if (first as uint) < c {\n")
+    out.write("            low = mid + 1;\n")
+    out.write("        } else {\n")
+    out.write("            high = mid;\n")
+    out.write("        }\n")
+    out.write("    }\n")
+    out.write("    low\n")
+    out.write("}\n\n")
+
+
+def main():
+    out = sys.stdout
+    by_category = dict((c, []) for c in CATEGORIES)
+    by_script = dict((s, []) for s, _ in SCRIPTS)
+    for cp in range(0x110000):
+        cat = unicodedata.category(chr(cp))
+        if cat in by_category:
+            by_category[cat].append(cp)
+        try:
+            name = unicodedata.name(chr(cp))
+        except ValueError:
+            continue
+        for script, prefixes in SCRIPTS:
+            if any(name.startswith(p) for p in prefixes):
+                by_script[script].append(cp)
+
+    out.write("// This file is generated by src/etc/unicode.py from Unicode %s.\n"
+              % unicodedata.unidata_version)
+    out.write("// Do not edit it by hand.\n\n")
+    out.write("use compile::charset::CharSet;\n\n")
+    for c in CATEGORIES:
+        emit_table(out, table(c), to_ranges(by_category[c]))
+    for s, _ in SCRIPTS:
+        emit_table(out, table(s), to_ranges(by_script[s]))
+    emit_case_pairs(out)
+
+    out.write("/// Look up the sorted ranges of a general category (like `Lu`, or `L`\n")
+    out.write("/// for all letters) or of a script (like `Greek`)\n")
+    out.write("pub fn property(name: &str) -> Option<~[(char, char)]> {\n")
+    out.write("    let tables: ~[&'static [(char, char)]] = match name {\n")
+    groups = {}
+    for c in CATEGORIES:
+        groups.setdefault(c[0], []).append(c)
+        out.write("        \"%s\" => ~[%s],\n" % (c, table(c)))
+    for g in sorted(groups):
+        tables = ", ".join(table(c) for c in groups[g])
+        out.write("        \"%s\" => ~[%s],\n" % (g, tables))
+    for s, _ in SCRIPTS:
+        out.write("        \"%s\" => ~[%s],\n" % (s, table(s)))
+    out.write("        _ => return None,\n")
+    out.write("    };\n")
+    out.write("    let mut set = CharSet::new();\n")
+    out.write("    for table in tables.iter() {\n")
+    out.write("        set = set.union(&CharSet::from_ranges(*table));\n")
+    out.write("    }\n")
+    out.write("    Some(set.ranges().to_owned())\n")
+    out.write("}\n")
+
+
+if __name__ == "__main__":
+    main()
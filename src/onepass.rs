@@ -0,0 +1,184 @@
+use std::str::CharRange;
+use std::vec;
+
+use compile::charset::CharSet;
+use compile::code;
+use compile::inst;
+
+/// Way from an address through jumps, saves and assertions to the `Match`
+/// or `Succeed` instruction it ends at
+#[deriving(Clone)]
+struct Path {
+    /// slots saved on the way
+    saves: ~[uint],
+    /// assertions that must hold on the way
+    asserts: ~[inst::Assertion],
+    end: uint,
+}
+
+/// Engine for one-pass programs, in which at every position at most one
+/// thread can go on over any char, so that the match and its groups are
+/// found in a single pass over the text, with no thread list and no
+/// backtracking. Validation patterns like `^(\w+)@(\w+)\.com$` mostly are.
+pub struct OnePass {
+    /// ways on from the start and from every address right after a
+    /// `Match`, in priority order; empty for the other addresses
+    paths: ~[~[Path]],
+}
+
+impl OnePass {
+    /// Engine for `code`, or `None` if it is not one-pass: some position
+    /// has two ways on over the same char, two ways to succeed or two ways
+    /// to the same instruction, or the program needs more than jumps,
+    /// saves and assertions between its chars
+    pub fn new(code: &code::Code) -> Option<OnePass> {
+        let mut paths = vec::from_fn(code.len(), |_| ~[]);
+        let mut nodes = ~[0u];
+        for pc in range(0, code.len()) {
+            match code.op(pc) {
+                code::Match(_) => nodes.push(pc + 1),
+                _ => (),
+            }
+        }
+        for &node in nodes.iter() {
+            match closure(code, node) {
+                Some(ways) => paths[node] = ways,
+                None => return None,
+            }
+        }
+        Some(OnePass {
+            paths: paths,
+        })
+    }
+
+    /// Run `code`, the program the engine was made for, anchored at byte
+    /// offset `start` of `string`, returning the first `nslots` slots of the
+    /// highest-priority match
+    pub fn exec(&self, code: &code::Code, nslots: uint, string: &str,
+                start: uint) -> Option<~[Option<uint>]> {
+        let mut slots = vec::from_elem(nslots, None);
+        // match of a lower priority than the way taken, in case it fails
+        let mut matched = None;
+        let mut node = 0;
+        let mut i = start;
+        loop {
+            let (ch, next) = if i < string.len() {
+                let CharRange {ch, next} = string.char_range_at(i);
+                (Some(ch), next)
+            } else {
+                (None, i)
+            };
+            let mut taken = None;
+            for path in self.paths[node].iter() {
+                if path.asserts.iter().all(|a| a.holds(string, i)) {
+                    match code.op(path.end) {
+                        code::Succeed => {
+                            let mut found = slots.clone();
+                            save(&mut found, path.saves, i);
+                            if taken.is_none() {
+                                return Some(found);
+                            }
+                            matched = Some(found);
+                        },
+                        code::Match(m) => match ch {
+                            // no other way can match the same char
+                            Some(c) if taken.is_none() && m.matches(c) => taken = Some(path),
+                            _ => (),
+                        },
+                        _ => fail!("Unexpected end of a path."),
+                    }
+                }
+            }
+            match taken {
+                Some(path) => {
+                    save(&mut slots, path.saves, i);
+                    node = path.end + 1;
+                    i = next;
+                },
+                None => return matched,
+            }
+        }
+    }
+}
+
+/// Ways on from address `pc`, in priority order, or `None` if the program
+/// is not one-pass there
+fn closure(code: &code::Code, pc: uint) -> Option<~[Path]> {
+    let mut seen = vec::from_elem(code.len(), false);
+    let mut paths: ~[Path] = ~[];
+    let mut stack = ~[Path { saves: ~[], asserts: ~[], end: pc }];
+    while !stack.is_empty() {
+        let mut path = stack.pop();
+        let pc = path.end;
+        if seen[pc] {
+            return None;
+        }
+        seen[pc] = true;
+        match code.op(pc) {
+            code::Match(m) => {
+                let chars = match_chars(m);
+                for other in paths.iter() {
+                    match code.op(other.end) {
+                        code::Match(n) if !chars.intersect(&match_chars(n)).is_empty() =>
+                            return None,
+                        _ => (),
+                    }
+                }
+                paths.push(path);
+            },
+            code::Succeed => {
+                let succeeds = |other: &Path| match code.op(other.end) {
+                    code::Succeed => true,
+                    _ => false,
+                };
+                if paths.iter().any(succeeds) {
+                    return None;
+                }
+                paths.push(path);
+            },
+            code::Jmp(a) => {
+                path.end = a;
+                stack.push(path);
+            },
+            code::Split(a, b) => {
+                stack.push(Path { end: b, .. path.clone() });
+                path.end = a;
+                stack.push(path);
+            },
+            code::Save(slot) => {
+                path.saves.push(slot);
+                path.end += 1;
+                stack.push(path);
+            },
+            code::Assert(a) => {
+                path.asserts.push(a);
+                path.end += 1;
+                stack.push(path);
+            },
+            _ => return None,
+        }
+    }
+    Some(paths)
+}
+
+/// Chars `m` accepts
+fn match_chars(m: &inst::Match) -> CharSet {
+    match *m {
+        inst::Char(c) => CharSet::from_ranges([(c, c)]),
+        inst::Dot => CharSet::from_ranges([('\n', '\n')]).negate(),
+        inst::Any => CharSet::new().negate(),
+        inst::Class(ref ranges, negated) => {
+            let set = CharSet::from_ranges(*ranges);
+            if negated { set.negate() } else { set }
+        },
+        inst::Ranges(ref ranges) => CharSet::from_ranges(*ranges),
+    }
+}
+
+fn save(slots: &mut ~[Option<uint>], saves: &[uint], pos: uint) {
+    for &slot in saves.iter() {
+        if slot < slots.len() {
+            slots[slot] = Some(pos);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::cmp;
+use std::vec;
+
+use compile;
+
+/// shortest prefix worth searching for with `Horspool`
+static LONG_PREFIX: uint = 4;
+
+/// Fast scan for the offsets where a match can start, run ahead of the
+/// matching engines so that they are not started at every offset
+pub enum Prefilter {
+    /// a match can start anywhere
+    Nothing,
+    /// every match starts with the literal
+    Prefix(~str),
+    /// every match starts with a literal at least `LONG_PREFIX` bytes long
+    LongPrefix(Horspool),
+    /// every match starts with one of a set of literals
+    Prefixes(AhoCorasick),
+}
+
+impl Prefilter {
+    pub fn new(program: &compile::CompiledRegexp) -> Prefilter {
+        let mut prefixes = program.literal_prefixes();
+        match prefixes.len() {
+            0 => Nothing,
+            1 => {
+                let prefix = prefixes.pop();
+                if prefix.len() >= LONG_PREFIX {
+                    LongPrefix(Horspool::new(prefix))
+                } else {
+                    Prefix(prefix)
+                }
+            },
+            _ => Prefixes(AhoCorasick::new(prefixes)),
+        }
+    }
+
+    /// First byte offset from `start` on where a match can start
+    pub fn find(&self, string: &str, start: uint) -> Option<uint> {
+        match *self {
+            Nothing => Some(start),
+            Prefix(ref prefix) => {
+                let rest = string.slice_from(start);
+                let found = if prefix.char_len() == 1 {
+                    rest.find(prefix.char_at(0))
+                } else {
+                    rest.find_str(*prefix)
+                };
+                found.map(|&i| start + i)
+            },
+            LongPrefix(ref searcher) => searcher.find(string, start),
+            Prefixes(ref automaton) => automaton.find(string, start),
+        }
+    }
+}
+
+/// Aho-Corasick automaton finding a set of non-empty literals in a single
+/// pass over the bytes of a string
+pub struct AhoCorasick {
+    /// next state for every state and byte, with the failure links already
+    /// followed; state 0 is the root
+    delta: ~[~[uint]],
+    /// length of the longest literal that the text read so far ends with,
+    /// for every state, or 0 if none
+    longest: ~[uint],
+    /// length of the longest literal
+    max_len: uint,
+}
+
+impl AhoCorasick {
+    pub fn new(literals: &[~str]) -> AhoCorasick {
+        // build the trie, where no edge leads back to the root
+        // so 0 can mark a missing one
+        let mut delta = ~[vec::from_elem(256, 0u)];
+        let mut longest = ~[0u];
+        let mut max_len = 0;
+        for literal in literals.iter() {
+            let mut state = 0;
+            for i in range(0, literal.len()) {
+                let b = literal[i] as uint;
+                if delta[state][b] == 0 {
+                    delta.push(vec::from_elem(256, 0u));
+                    longest.push(0);
+                    delta[state][b] = delta.len() - 1;
+                }
+                state = delta[state][b];
+            }
+            longest[state] = literal.len();
+            max_len = cmp::max(max_len, literal.len());
+        }
+        // then fill in the missing edges breadth-first from the failure
+        // links, which always point to a state closer to the root
+        let mut fail = vec::from_elem(delta.len(), 0u);
+        let mut queue = ~[];
+        for b in range(0u, 256) {
+            if delta[0][b] != 0 {
+                queue.push(delta[0][b]);
+            }
+        }
+        let mut i = 0;
+        while i < queue.len() {
+            let state = queue[i];
+            i += 1;
+            if longest[state] == 0 {
+                longest[state] = longest[fail[state]];
+            }
+            for b in range(0u, 256) {
+                let next = delta[state][b];
+                let on_fail = delta[fail[state]][b];
+                if next != 0 {
+                    fail[next] = on_fail;
+                    queue.push(next);
+                } else {
+                    delta[state][b] = on_fail;
+                }
+            }
+        }
+        AhoCorasick {
+            delta: delta,
+            longest: longest,
+            max_len: max_len,
+        }
+    }
+
+    /// Byte offset of the leftmost literal starting from `start` on
+    pub fn find(&self, string: &str, start: uint) -> Option<uint> {
+        let mut state = 0;
+        let mut best: Option<uint> = None;
+        let mut i = start;
+        while i < string.len() {
+            // the first literal to end need not be the leftmost one, but
+            // any literal starting before `best` ends before this
+            match best {
+                Some(b) if i >= b + self.max_len => break,
+                _ => (),
+            }
+            state = self.delta[state][string[i] as uint];
+            i += 1;
+            let len = self.longest[state];
+            if len > 0 {
+                best = Some(best.map_default(i - len, |&b| cmp::min(b, i - len)));
+            }
+        }
+        best
+    }
+}
+
+/// Boyer-Moore-Horspool searcher for a non-empty literal, which moves past
+/// most mismatches by comparing a single byte
+pub struct Horspool {
+    needle: ~str,
+    /// how far the window can move on when its last byte is the index
+    shift: ~[uint],
+}
+
+impl Horspool {
+    pub fn new(needle: ~str) -> Horspool {
+        let n = needle.len();
+        let mut shift = vec::from_elem(256, n);
+        for i in range(0, n - 1) {
+            shift[needle[i] as uint] = n - 1 - i;
+        }
+        Horspool {
+            needle: needle,
+            shift: shift,
+        }
+    }
+
+    /// Byte offset of the first occurrence of the literal from `start` on
+    pub fn find(&self, string: &str, start: uint) -> Option<uint> {
+        let n = self.needle.len();
+        let mut pos = start;
+        while pos + n <= string.len() {
+            let last = string[pos + n - 1];
+            if last == self.needle[n - 1]
+                    && range(0, n - 1).all(|i| string[pos + i] == self.needle[i]) {
+                return Some(pos);
+            }
+            pos += self.shift[last as uint];
+        }
+        None
+    }
+}
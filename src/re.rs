@@ -1,103 +1,1777 @@
+use std::cmp;
+use std::from_str::FromStr;
+use std::hashmap::HashMap;
+use std::hashmap::HashSet;
+use std::io::Reader;
+use std::str;
+use std::str::CharRange;
+use std::util;
 use std::vec;
 
+use extra::arc;
+
+use backtrack;
 use compile;
+use compile::code;
+use compile::error;
+use compile::error::ParseError;
+use compile::expr::Expr;
 use compile::inst;
+use dfa;
+use dfa::Dfa;
+use dfa::LazyDfa;
+use onepass::OnePass;
+use prefilter::Prefilter;
+use shiftor::ShiftOr;
+use sparse::SparseSet;
+
+/// Capture slots of a thread, see `inst::Save`
+type Slots = ~[Option<uint>];
+
+/// most bytes `ReaderMatches` asks its reader for at once
+static READ_SIZE: uint = 64 * 1024;
+
+/// most units `Utf16Matches` decodes at once
+static DECODE_SIZE: uint = 4 * 1024;
+
+#[deriving(Clone)]
+struct Thread {
+    pc: uint,
+    slots: Slots,
+    /// loop counters, see `inst::Reset`
+    counters: ~[uint],
+}
+
+/// How a `Regexp` finds where its matches are, the fastest way the program
+/// allows being chosen once when the regexp is built
+enum Strategy {
+    /// the prefilter alone, for a program matching nothing but a literal
+    /// of this many bytes
+    LiteralScan(uint),
+    /// `ShiftOr` alone, for a program matching a short sequence of classes
+    BitParallel(ShiftOr),
+    /// a lazy DFA to find where the match ends, then another one running
+    /// this reversed program back from there to find where it starts
+    LazyDfas(compile::CompiledRegexp),
+    /// the group engine at every offset the prefilter finds, until it matches
+    Candidates,
+}
+
+/// How a `Regexp` finds the match starting at an offset and its groups,
+/// chosen like its `Strategy`
+enum GroupEngine {
+    SinglePass(OnePass),
+    /// `backtrack::exec_bounded` when the text left is short enough for
+    /// its bitmap, the NFA simulation otherwise
+    Bounded,
+    /// the NFA simulation
+    Simulation,
+    /// the backtracking engine, for programs that need it
+    Backtracking,
+}
+
+/// States of the threads a step of the NFA simulation already has, so
+/// that only the first thread to reach a state, which has the highest
+/// priority, is kept and there are never more threads than states
+struct Visited {
+    /// addresses with a thread, for programs without loop counters, in
+    /// which the address is the whole state
+    addresses: SparseSet,
+    /// address followed by the counters of every thread, for programs with
+    /// loop counters, in which threads at an address still differ by them
+    states: HashSet<~[uint]>,
+}
+
+impl Visited {
+    fn new(size: uint) -> Visited {
+        Visited {
+            addresses: SparseSet::new(size),
+            states: HashSet::new(),
+        }
+    }
+
+    /// Record the state of `t`, returning whether no thread was in it yet
+    fn first(&mut self, t: &Thread) -> bool {
+        if t.counters.is_empty() {
+            self.addresses.insert(t.pc)
+        } else {
+            let mut state = ~[t.pc];
+            state.push_all(t.counters);
+            self.states.insert(state)
+        }
+    }
+
+    fn clear(&mut self) {
+        self.addresses.clear();
+        self.states.clear();
+    }
+}
+
+/// Buffers of the NFA simulation, reused from offset to offset and, when
+/// an iterator runs many searches, from search to search, so that matching
+/// does not allocate them anew as it goes
+struct Scratch {
+    /// threads at the current offset, in priority order
+    threads: ~[Thread],
+    /// threads at the next offset, built while reading the current char
+    next_threads: ~[Thread],
+    /// threads left to follow through jumps, see `Regexp::follow_jump`
+    stack: ~[Thread],
+    /// states of `threads`
+    visited: Visited,
+    /// states of `next_threads`
+    next_visited: Visited,
+    /// whether the text may go on past the string searched, so that
+    /// assertions depending on what follows are left undecided, see
+    /// `Regexp::partial_match`
+    open: bool,
+    /// states of the DFAs of the `LazyDfas` strategy, cached from search
+    /// to search: the one finding where the match ends, the one running
+    /// the reversed program to find where it starts, and the one finding
+    /// the longest match from there
+    forward: LazyDfa,
+    reverse: LazyDfa,
+    forward_longest: LazyDfa,
+    /// buffers for running the bodies of lookarounds, made the first time
+    /// one is run, see `Regexp::look`
+    lookaround: Option<~Scratch>,
+}
 
-enum IterResult {
-    Matched,
-    Continue,
-    Halt,
+impl Scratch {
+    /// Buffers for a program of `size` instructions
+    fn new(size: uint) -> Scratch {
+        Scratch {
+            threads: ~[],
+            next_threads: ~[],
+            stack: ~[],
+            visited: Visited::new(size),
+            next_visited: Visited::new(size),
+            open: false,
+            forward: LazyDfa::new(),
+            reverse: LazyDfa::longest(),
+            forward_longest: LazyDfa::longest(),
+            lookaround: None,
+        }
+    }
 }
 
-pub struct Engine {
+/// A compiled regular expression, ready to search strings. Searching does
+/// not change the regexp: the thread lists and capture slots of a search
+/// are scratch state owned by that search, so one regexp can serve many
+/// searches, and one-shot searches like `find` hand theirs back for the
+/// next one to take. Each of them runs the fastest engines the program
+/// allows, which are picked when the regexp is built.
+///
+/// Matches are leftmost-first, as in Perl: of the matches starting at the
+/// leftmost offset, the one found first when trying alternatives from left
+/// to right and quantifiers greedily, or lazily for lazy ones, wins. So
+/// `a|ab` finds "a" in "ab" while `ab|a` finds "ab". Every engine runs its
+/// threads or paths in that priority order and drops those of a lower
+/// priority once one matches, so the spans of the groups always belong to
+/// the match reported.
+///
+/// A regexp owns all its data, with no managed box and nothing mutable but
+/// the scratch state it keeps behind a mutex, so it is `Send` and `Freeze`:
+/// compile it once, wrap it in an `extra::arc::ARC` and search with it from
+/// as many tasks as needed, each search keeping its scratch state to itself.
+pub struct Regexp {
     program: compile::CompiledRegexp,
-    ips: ~[uint],
+    nslots: uint,
+    ncounters: uint,
+    strategy: Strategy,
+    engine: GroupEngine,
+    /// scan for where matches can start
+    prefilter: Prefilter,
+    /// scratch state of the last one-shot search, for the next one to
+    /// take rather than allocate its own, see `Regexp::with_scratch`
+    cache: arc::MutexARC<Option<Scratch>>,
 }
 
-impl Engine {
-    pub fn new(program: compile::CompiledRegexp) -> Engine {
-        Engine {
-            program: program,
-            ips: ~[],
+/// Byte spans of every group of one match in `text`, group 0 being the
+/// whole match
+pub struct Captures<'self> {
+    text: &'self str,
+    spans: ~[Option<(uint, uint)>],
+    names: HashMap<~str, uint>,
+}
+
+impl<'self> Captures<'self> {
+    fn new(text: &'self str, slots: &[Option<uint>], names: &HashMap<~str, uint>)
+           -> Captures<'self> {
+        let mut spans = ~[];
+        for i in range(0, slots.len() / 2) {
+            spans.push(match (slots[2 * i], slots[2 * i + 1]) {
+                (Some(s), Some(e)) => Some((s, e)),
+                _ => None,
+            });
+        }
+        Captures {
+            text: text,
+            spans: spans,
+            names: names.clone(),
         }
     }
 
-    pub fn matches(&mut self, string: &str) -> bool {
-        let mut iter = string.char_offset_iter();
-        for _ in range(0, string.char_len()) {
-            self.init();
-            for (_, c) in iter.clone() {
-                match self.iterate(c) {
-                    Matched => return true,
-                    Halt => break,
-                    _ => {},
+    /// Number of groups, including the whole match
+    pub fn len(&self) -> uint {
+        self.spans.len()
+    }
+
+    /// Span of group `i`, or `None` if the group did not take part in the match
+    pub fn pos(&self, i: uint) -> Option<(uint, uint)> {
+        if i < self.spans.len() {
+            self.spans[i]
+        } else {
+            None
+        }
+    }
+
+    /// Text matched by group `i`, or `None` if the group did not take part
+    /// in the match
+    pub fn get(&self, i: uint) -> Option<&'self str> {
+        match self.pos(i) {
+            Some((s, e)) => Some(self.text.slice(s, e)),
+            None => None,
+        }
+    }
+
+    /// Text matched by group `i`, or "" if the group did not take part in
+    /// the match
+    pub fn at(&self, i: uint) -> &'self str {
+        match self.get(i) {
+            Some(t) => t,
+            None => "",
+        }
+    }
+
+    /// Span of the group named `name`, or `None` if there is no such group
+    /// or it did not take part in the match
+    pub fn name(&self, name: &str) -> Option<(uint, uint)> {
+        match self.names.find_equiv(&name) {
+            Some(&i) => self.pos(i),
+            None => None,
+        }
+    }
+
+    /// Text matched by the group named or numbered `name`, or "" if there
+    /// is no such group or it did not take part in the match
+    fn at_ref(&self, name: &str) -> &'self str {
+        let mut n = 0;
+        for c in name.iter() {
+            match c.to_digit(10) {
+                Some(d) => n = n * 10 + d,
+                None => return match self.names.find_equiv(&name) {
+                    Some(&i) => self.at(i),
+                    None => "",
+                },
+            }
+        }
+        if name.is_empty() { "" } else { self.at(n) }
+    }
+
+    /// Append `template` to `dst`, replacing `$n` and `${name}` with the text
+    /// of the corresponding group, and `$$` with a single '$'
+    pub fn expand(&self, template: &str, dst: &mut ~str) {
+        let mut i = 0;
+        while i < template.len() {
+            let CharRange {ch, next} = template.char_range_at(i);
+            i = next;
+            if ch != '$' {
+                dst.push_char(ch);
+            } else {
+                let rest = template.slice_from(i);
+                let digits = rest.find(|c: char| c < '0' || c > '9')
+                                 .map_default(rest.len(), |&n| n);
+                if rest.starts_with("$") {
+                    dst.push_char('$');
+                    i += 1;
+                } else if rest.starts_with("{") && rest.find('}').is_some() {
+                    let end = rest.find('}').unwrap();
+                    dst.push_str(self.at_ref(rest.slice(1, end)));
+                    i += end + 1;
+                } else if digits > 0 {
+                    dst.push_str(self.at_ref(rest.slice_to(digits)));
+                    i += digits;
+                } else {
+                    dst.push_char('$');
                 }
             }
-            for addr in self.ips.iter() {
-                match self.program[*addr] {
-                    inst::Succeed => return true,
-                    _ => {},
+        }
+    }
+
+    /// Iterate over the text matched by every group, in order
+    pub fn iter<'a>(&'a self) -> Groups<'a> {
+        Groups {
+            captures: self,
+            idx: 0,
+        }
+    }
+}
+
+/// Anything a match can be replaced with: a template string, see
+/// `Captures::expand`, or a closure computing the replacement from the groups
+pub trait Replacer {
+    /// Append the replacement for the match described by `caps` to `dst`
+    fn replace_append(&self, caps: &Captures, dst: &mut ~str);
+}
+
+impl<'self> Replacer for &'self str {
+    fn replace_append(&self, caps: &Captures, dst: &mut ~str) {
+        caps.expand(*self, dst);
+    }
+}
+
+impl<'self> Replacer for &'self fn(&Captures) -> ~str {
+    fn replace_append(&self, caps: &Captures, dst: &mut ~str) {
+        dst.push_str((*self)(caps));
+    }
+}
+
+/// Iterator over the groups of a match, see `Captures::iter`
+pub struct Groups<'self> {
+    captures: &'self Captures<'self>,
+    idx: uint,
+}
+
+impl<'self> Iterator<Option<&'self str>> for Groups<'self> {
+    fn next(&mut self) -> Option<Option<&'self str>> {
+        if self.idx < self.captures.len() {
+            self.idx += 1;
+            Some(self.captures.get(self.idx - 1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Iterator over the non-overlapping matches of a regexp in a string,
+/// see `Regexp::find_iter`
+pub struct Matches<'self> {
+    regexp: &'self Regexp,
+    string: &'self str,
+    /// byte offset to resume the search at
+    pos: uint,
+    /// end of the previous match
+    last_end: Option<uint>,
+    /// whether to record the spans of groups besides the whole match
+    groups: bool,
+    /// buffers shared by the searches
+    scratch: Scratch,
+}
+
+impl<'self> Matches<'self> {
+    /// Find the next match, returning the slots of its groups. An empty
+    /// match is skipped if it is right where the previous match ended, and
+    /// the search goes on one char after it, so that every round moves
+    /// forward and the iteration ends.
+    fn next_slots(&mut self) -> Option<Slots> {
+        while self.pos <= self.string.len() {
+            let slots = match self.regexp.search(&mut self.scratch, self.string, self.pos,
+                                                 self.groups, false) {
+                Some(slots) => slots,
+                None => break,
+            };
+            let (s, e) = (slots[0].unwrap(), slots[1].unwrap());
+            if s == e {
+                // the same empty match would be found again and again
+                self.pos = self.after(e);
+                if self.last_end != Some(e) {
+                    self.last_end = Some(e);
+                    return Some(slots);
                 }
+            } else {
+                self.pos = e;
+                self.last_end = Some(e);
+                return Some(slots);
             }
-            iter.next();
         }
-        false
+        self.pos = self.string.len() + 1;
+        None
+    }
+
+    /// Byte offset of the char after the one at `pos`, or past the end of
+    /// the string if there is none
+    fn after(&self, pos: uint) -> uint {
+        if pos < self.string.len() {
+            self.string.char_range_at(pos).next
+        } else {
+            pos + 1
+        }
+    }
+}
+
+impl<'self> Iterator<(uint, uint)> for Matches<'self> {
+    fn next(&mut self) -> Option<(uint, uint)> {
+        match self.next_slots() {
+            Some(slots) => Some((slots[0].unwrap(), slots[1].unwrap())),
+            None => None,
+        }
+    }
+}
+
+/// Iterator over the text between the matches of a regexp in a string,
+/// see `Regexp::split`
+pub struct Splits<'self> {
+    matches: Matches<'self>,
+    /// end of the previous match
+    last: uint,
+    /// whether the piece after the last match was returned
+    done: bool,
+}
+
+impl<'self> Iterator<&'self str> for Splits<'self> {
+    fn next(&mut self) -> Option<&'self str> {
+        if self.done {
+            return None;
+        }
+        let string = self.matches.string;
+        match self.matches.next() {
+            Some((s, e)) => {
+                let piece = string.slice(self.last, s);
+                self.last = e;
+                Some(piece)
+            },
+            None => {
+                self.done = true;
+                Some(string.slice_from(self.last))
+            },
+        }
+    }
+}
+
+/// Iterator over at most `n` pieces of a string, the last one holding the
+/// rest of the string unsplit, see `Regexp::splitn`
+pub struct SplitsN<'self> {
+    splits: Splits<'self>,
+    /// number of pieces still to be returned
+    n: uint,
+}
+
+impl<'self> Iterator<&'self str> for SplitsN<'self> {
+    fn next(&mut self) -> Option<&'self str> {
+        if self.n == 0 || self.splits.done {
+            return None;
+        }
+        self.n -= 1;
+        if self.n == 0 {
+            self.splits.done = true;
+            Some(self.splits.matches.string.slice_from(self.splits.last))
+        } else {
+            self.splits.next()
+        }
+    }
+}
+
+/// Iterator over the match starting at every position of a string,
+/// see `Regexp::find_overlapping_iter`
+pub struct OverlappingMatches<'self> {
+    regexp: &'self Regexp,
+    string: &'self str,
+    /// byte offset to try next
+    pos: uint,
+    /// buffers shared by the searches
+    scratch: Scratch,
+}
+
+impl<'self> Iterator<(uint, uint)> for OverlappingMatches<'self> {
+    fn next(&mut self) -> Option<(uint, uint)> {
+        while self.pos <= self.string.len() {
+            let found = self.regexp.search_at(&mut self.scratch, self.string, self.pos, false,
+                                              false);
+            self.pos = if self.pos < self.string.len() {
+                self.string.char_range_at(self.pos).next
+            } else {
+                self.pos + 1
+            };
+            match found {
+                Some(slots) => return Some((slots[0].unwrap(), slots[1].unwrap())),
+                None => {},
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the groups of the non-overlapping matches of a regexp
+/// in a string, see `Regexp::captures_iter`
+pub struct CaptureMatches<'self> {
+    matches: Matches<'self>,
+}
+
+impl<'self> Iterator<Captures<'self>> for CaptureMatches<'self> {
+    fn next(&mut self) -> Option<Captures<'self>> {
+        match self.matches.next_slots() {
+            Some(slots) => Some(Captures::new(self.matches.string, slots,
+                                              &self.matches.regexp.program.names)),
+            None => None,
+        }
+    }
+}
+
+/// Whether a text matches when more text may follow it, see
+/// `Regexp::partial_match`
+#[deriving(Eq)]
+pub enum PartialMatch {
+    /// no text to come can make it match
+    NoMatch,
+    /// the span of a match that no text to come can change
+    Complete(uint, uint),
+    /// the text to come decides whether and where it matches
+    Incomplete,
+}
+
+/// Search fed a text in chunks, as it is read from a file or a socket,
+/// finding the same matches as `Regexp::find_iter` would in the whole text
+/// at once, see `Regexp::stream`. The threads of the NFA simulation are
+/// carried from chunk to chunk, and of the text only what the search may
+/// still read is kept: from where the earliest live thread started, or
+/// where the next search starts after a match.
+pub struct StreamMatcher<'self> {
+    regexp: &'self Regexp,
+    /// text from byte offset `base` of the stream on
+    buffer: ~str,
+    base: uint,
+    /// offset in `buffer` of the next char to run the threads over
+    pos: uint,
+    /// offset in `buffer` of the end of the previous match
+    last_end: Option<uint>,
+    /// match found by the current search, which threads of a higher
+    /// priority may still replace
+    matched: Option<Slots>,
+    /// start of the match found, when looking for the longest one
+    leftmost: Option<uint>,
+    /// whether the end of the text was reached
+    finished: bool,
+    scratch: Scratch,
+}
+
+impl<'self> StreamMatcher<'self> {
+    /// Add `chunk` to the text, returning the spans of the matches known
+    /// to be complete, in byte offsets from the start of the stream. A
+    /// match ending near the end of the text read so far is only reported
+    /// once the chars after it are, as they may extend it or decide
+    /// assertions like `$` or `\b`.
+    pub fn feed(&mut self, chunk: &str) -> ~[(uint, uint)] {
+        if self.finished {
+            fail!("Text fed to a finished stream.");
+        }
+        self.buffer.push_str(chunk);
+        self.run()
+    }
+
+    /// End the text, returning the spans of the matches left
+    pub fn finish(&mut self) -> ~[(uint, uint)] {
+        self.finished = true;
+        self.run()
+    }
+
+    /// Run the threads over as much of the text as is known well enough
+    fn run(&mut self) -> ~[(uint, uint)] {
+        let mut found = ~[];
+        let regexp = self.regexp;
+        // running the threads over a char needs two more chars to check
+        // the assertions where it ends
+        while self.pos <= self.buffer.len() && (self.finished || self.has_chars(3)) {
+            let i = self.pos;
+            if self.matched.is_none() {
+                let first = Thread {
+                    pc: 0,
+                    slots: ~[None, None],
+                    counters: vec::from_elem(self.regexp.ncounters, 0u),
+                };
+                regexp.follow_jump(first, self.buffer, i, false, &mut self.scratch.stack,
+                                   &mut self.scratch.visited, &mut self.scratch.threads,
+                                   &mut self.scratch.lookaround);
+            }
+            if self.scratch.threads.is_empty() && self.matched.is_some() {
+                self.settle(&mut found);
+            } else {
+                let (ch, next) = if i < self.buffer.len() {
+                    let CharRange {ch, next} = self.buffer.char_range_at(i);
+                    (Some(ch), next)
+                } else {
+                    (None, i)
+                };
+                if regexp.step(&mut self.scratch, self.buffer, ch, next, false,
+                               regexp.program.longest, regexp.program.earliest,
+                               &mut self.leftmost, &mut self.matched) {
+                    self.settle(&mut found);
+                } else if ch.is_none() {
+                    if self.matched.is_some() {
+                        self.settle(&mut found);
+                    } else {
+                        self.pos = self.buffer.len() + 1;
+                    }
+                } else {
+                    util::swap(&mut self.scratch.threads, &mut self.scratch.next_threads);
+                    util::swap(&mut self.scratch.visited, &mut self.scratch.next_visited);
+                    self.pos = next;
+                }
+            }
+        }
+        self.trim();
+        found
+    }
+
+    /// Whether `buffer` has `n` chars from `pos` on
+    fn has_chars(&self, n: uint) -> bool {
+        let mut i = self.pos;
+        for _ in range(0, n) {
+            if i >= self.buffer.len() {
+                return false;
+            }
+            i = self.buffer.char_range_at(i).next;
+        }
+        true
+    }
+
+    /// Report the match found, unless it is an empty one right where the
+    /// previous one ended, and start the next search after it, following
+    /// the same rule as `Matches`
+    fn settle(&mut self, found: &mut ~[(uint, uint)]) {
+        let slots = util::replace(&mut self.matched, None).unwrap();
+        let (s, e) = (slots[0].unwrap(), slots[1].unwrap());
+        if s != e || self.last_end != Some(e) {
+            found.push((self.base + s, self.base + e));
+        }
+        self.last_end = Some(e);
+        self.pos = if s < e {
+            e
+        } else if e < self.buffer.len() {
+            self.buffer.char_range_at(e).next
+        } else {
+            e + 1
+        };
+        self.leftmost = None;
+        self.scratch.threads.clear();
+        self.scratch.visited.clear();
+    }
+
+    /// Drop the text before the char preceding the earliest offset still
+    /// needed, which assertions may look at, moving every offset kept
+    fn trim(&mut self) {
+        let mut keep = cmp::min(self.pos, self.buffer.len());
+        for t in self.scratch.threads.iter() {
+            keep = cmp::min(keep, t.slots[0].unwrap());
+        }
+        match self.matched {
+            Some(ref slots) => keep = cmp::min(keep, slots[0].unwrap()),
+            None => (),
+        }
+        let keep = match inst::rewind(self.buffer, keep, 1) {
+            Some(k) => k,
+            None => return,
+        };
+        if keep == 0 {
+            return;
+        }
+        for t in self.scratch.threads.mut_iter() {
+            shift(&mut t.slots, keep);
+        }
+        match self.matched {
+            Some(ref mut slots) => shift(slots, keep),
+            None => (),
+        }
+        self.leftmost = self.leftmost.map(|&n| n - keep);
+        self.last_end = match self.last_end {
+            Some(n) if n >= keep => Some(n - keep),
+            _ => None,
+        };
+        self.buffer = self.buffer.slice_from(keep).to_owned();
+        self.base += keep;
+        self.pos -= keep;
+    }
+}
+
+/// Why `ReaderMatches` stopped before the end of the text, with the byte
+/// offset in the text of the char at fault
+#[deriving(Clone, Eq)]
+pub enum ReadError {
+    /// bytes that are not valid UTF-8
+    InvalidUtf8(uint),
+    /// a char cut off by the end of the text
+    TruncatedChar(uint),
+}
+
+/// Iterator over the spans of the matches of a regexp in the text a
+/// `Reader` gives, in byte offsets from the start of it, see
+/// `Regexp::find_iter_reader`
+pub struct ReaderMatches<'self, R> {
+    stream: StreamMatcher<'self>,
+    reader: R,
+    /// bytes read but not fed to the stream yet, the start of a char cut
+    /// by the end of the last read
+    partial: ~[u8],
+    /// matches found but not returned yet, the last one first
+    found: ~[(uint, uint)],
+    /// number of bytes fed to the stream
+    read: uint,
+    /// whether the reader was read to the end, or reading stopped at an error
+    done: bool,
+    error: Option<ReadError>,
+}
+
+impl<'self, R> ReaderMatches<'self, R> {
+    /// What stopped the matches before the end of the text, if anything;
+    /// the matches that the text after it could have changed are dropped
+    pub fn error(&self) -> Option<ReadError> {
+        self.error
+    }
+}
+
+impl<'self, R: Reader> Iterator<(uint, uint)> for ReaderMatches<'self, R> {
+    fn next(&mut self) -> Option<(uint, uint)> {
+        while self.found.is_empty() && !self.done {
+            let mut block = vec::from_elem(READ_SIZE, 0u8);
+            let n = self.reader.read(block, READ_SIZE);
+            let mut found = if n == 0 {
+                self.done = true;
+                if self.partial.is_empty() {
+                    self.stream.finish()
+                } else {
+                    self.error = Some(TruncatedChar(self.read));
+                    ~[]
+                }
+            } else {
+                self.partial.push_all(block.slice(0, n));
+                let end = complete_prefix(self.partial);
+                let valid = valid_prefix(self.partial.slice(0, end));
+                if valid < end {
+                    self.done = true;
+                    self.error = Some(InvalidUtf8(self.read + valid));
+                }
+                let chunk = str::from_bytes(self.partial.slice(0, valid));
+                self.partial = self.partial.slice(end, self.partial.len()).to_owned();
+                self.read += valid;
+                self.stream.feed(chunk)
+            };
+            found.reverse();
+            self.found = found;
+        }
+        if self.found.is_empty() {
+            None
+        } else {
+            Some(self.found.pop())
+        }
+    }
+}
+
+/// Length of `bytes` but for a char cut at the end, which the next read
+/// completes
+fn complete_prefix(bytes: &[u8]) -> uint {
+    let n = bytes.len();
+    let mut i = n;
+    // a char is at most 4 bytes long
+    while i > 0 && n - i < 4 {
+        i -= 1;
+        if bytes[i] & 0xc0 != 0x80 {
+            return if i + str::utf8_char_width(bytes[i]) > n { i } else { n };
+        }
     }
+    n
+}
 
-    fn init(&mut self) {
-        self.ips = self.follow_jump(0);
-        if self.ips.is_empty() {
-            self.ips.push(0);
+/// Length of the longest start of `bytes` that is valid UTF-8
+fn valid_prefix(bytes: &[u8]) -> uint {
+    let mut i = 0;
+    while i < bytes.len() {
+        let width = str::utf8_char_width(bytes[i]);
+        if width == 0 || i + width > bytes.len() || !str::is_utf8(bytes.slice(i, i + width)) {
+            break;
         }
+        i += width;
     }
+    i
+}
 
-    fn iterate(&mut self, c: char) -> IterResult {
-        if self.ips.is_empty() {
-            return Halt;
+/// Iterator over the spans of the matches of a regexp in UTF-16 text, in
+/// units from the start of it, see `Regexp::find_iter_utf16`
+pub struct Utf16Matches<'self> {
+    stream: StreamMatcher<'self>,
+    units: &'self [u16],
+    /// next unit to decode and feed to the stream
+    next: uint,
+    /// matches found but not returned yet, in bytes of the UTF-8 text fed
+    /// to the stream, the last one first
+    found: ~[(uint, uint)],
+    /// offset in the UTF-8 text of the unit at `unit`, moved on as the
+    /// offsets of the matches, which come in order, are converted
+    byte: uint,
+    unit: uint,
+    /// whether the stream was finished
+    done: bool,
+}
+
+impl<'self> Iterator<(uint, uint)> for Utf16Matches<'self> {
+    fn next(&mut self) -> Option<(uint, uint)> {
+        while self.found.is_empty() && !self.done {
+            let mut found = if self.next < self.units.len() {
+                let end = cmp::min(self.next + DECODE_SIZE, self.units.len());
+                let mut chunk = str::with_capacity(end - self.next);
+                while self.next < end {
+                    let (c, next) = decode_utf16(self.units, self.next);
+                    chunk.push_char(c);
+                    self.next = next;
+                }
+                self.stream.feed(chunk)
+            } else {
+                self.done = true;
+                self.stream.finish()
+            };
+            found.reverse();
+            self.found = found;
+        }
+        if self.found.is_empty() {
+            None
         } else {
-            let mut new_ips = ~[];
-            let mut result = Continue;
-            for addr in self.ips.iter() {
-                match self.program[*addr] {
-                    inst::Match(m) => match m {
-                        inst::Char(ch) => if ch == c {
-                            new_ips = vec::append(new_ips, self.follow_jump(*addr+1));
+            let (s, e) = self.found.pop();
+            let start = self.to_unit(s);
+            Some((start, self.to_unit(e)))
+        }
+    }
+}
+
+impl<'self> Utf16Matches<'self> {
+    /// Unit at byte offset `byte` of the UTF-8 text, which may not be
+    /// before the last one converted
+    fn to_unit(&mut self, byte: uint) -> uint {
+        while self.byte < byte {
+            let (c, next) = decode_utf16(self.units, self.unit);
+            self.byte += c.len_utf8_bytes();
+            self.unit = next;
+        }
+        self.unit
+    }
+}
+
+/// Char starting at unit `i` of `units`, or U+FFFD if it is an unpaired
+/// surrogate, along with the index of the unit after it
+fn decode_utf16(units: &[u16], i: uint) -> (char, uint) {
+    let u = units[i] as u32;
+    if u < 0xd800 || u > 0xdfff {
+        return (u as char, i + 1);
+    }
+    if u <= 0xdbff && i + 1 < units.len() {
+        let low = units[i + 1] as u32;
+        if low >= 0xdc00 && low <= 0xdfff {
+            return ((0x10000 + ((u - 0xd800) << 10) + (low - 0xdc00)) as char, i + 2);
+        }
+    }
+    ('\ufffd', i + 1)
+}
+
+/// Text of the chars whose values are `bytes`, as byte searches read them
+fn latin1(bytes: &[u8]) -> ~str {
+    let mut string = str::with_capacity(bytes.len());
+    for &b in bytes.iter() {
+        string.push_char(b as char);
+    }
+    string
+}
+
+/// Move the offsets in `slots` back by `n` bytes
+fn shift(slots: &mut Slots, n: uint) {
+    for slot in slots.mut_iter() {
+        *slot = slot.map(|&pos| pos - n);
+    }
+}
+
+impl Regexp {
+    /// Compile `pattern` with the default options
+    pub fn new(pattern: &str) -> Result<Regexp, ParseError> {
+        compile(pattern)
+    }
+
+    /// Compile an expression built with `compile::expr::Expr`,
+    /// with the default options
+    pub fn from_expr(expr: Expr) -> Result<Regexp, ParseError> {
+        match expr.compile(&compile::Options::new()) {
+            Ok(p) => Ok(Regexp::from_program(p)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wrap an already compiled program
+    pub fn from_program(program: compile::CompiledRegexp) -> Regexp {
+        let (mut nslots, mut ncounters) = (2, 0);
+        for pc in range(0, program.code.len()) {
+            match program.code.op(pc) {
+                code::Save(slot) => nslots = cmp::max(nslots, slot + 1),
+                code::Reset(counter) => ncounters = cmp::max(ncounters, counter + 1),
+                _ => {},
+            }
+        }
+        let backtrack = program.needs_backtracking();
+        let strategy = if program.is_literal() {
+            LiteralScan(program.literal_prefix().len())
+        } else {
+            match ShiftOr::new(&program.code) {
+                Some(engine) => BitParallel(engine),
+                None if !backtrack && dfa::supports(&program.code) =>
+                    match program.reverse() {
+                        Some(reversed) => LazyDfas(reversed),
+                        // too big to reverse
+                        None => Candidates,
+                    },
+                None => Candidates,
+            }
+        };
+        let engine = if backtrack {
+            Backtracking
+        } else if program.longest || program.earliest {
+            // the other engines stop at the first match in priority order
+            Simulation
+        } else {
+            match OnePass::new(&program.code) {
+                Some(engine) => SinglePass(engine),
+                None if backtrack::supports_bounded(&program.code) => Bounded,
+                None => Simulation,
+            }
+        };
+        let prefilter = Prefilter::new(&program);
+        Regexp {
+            program: program,
+            nslots: nslots,
+            ncounters: ncounters,
+            strategy: strategy,
+            engine: engine,
+            prefilter: prefilter,
+            cache: arc::MutexARC(None),
+        }
+    }
+
+    /// Whether `string` contains a match
+    pub fn is_match(&self, string: &str) -> bool {
+        self.with_scratch(|scratch| self.search(scratch, string, 0, false, true)).is_some()
+    }
+
+    /// Find the leftmost match, returning its start and end byte offsets
+    pub fn find(&self, string: &str) -> Option<(uint, uint)> {
+        match self.with_scratch(|scratch| self.search(scratch, string, 0, false, false)) {
+            Some(slots) => Some((slots[0].unwrap(), slots[1].unwrap())),
+            None => None,
+        }
+    }
+
+    /// Find the leftmost match starting at byte offset `start` or later.
+    /// Assertions still see the text before `start`, so `\b` or lookbehinds
+    /// behave as in a search of the whole string.
+    pub fn find_at(&self, string: &str, start: uint) -> Option<(uint, uint)> {
+        if start > string.len() {
+            return None;
+        }
+        match self.with_scratch(|scratch| self.search(scratch, string, start, false, false)) {
+            Some(slots) => Some((slots[0].unwrap(), slots[1].unwrap())),
+            None => None,
+        }
+    }
+
+    /// Find the end of the match starting exactly at byte offset `start`,
+    /// as parsers need to tell whether a token starts there. Assertions
+    /// still see the text before `start`.
+    pub fn match_at(&self, string: &str, start: uint) -> Option<uint> {
+        if start > string.len() {
+            return None;
+        }
+        match self.with_scratch(|scratch| self.search_at(scratch, string, start, false, false)) {
+            Some(slots) => slots[1],
+            None => None,
+        }
+    }
+
+    /// Whether `bytes` contain a match, see `find_bytes`
+    pub fn is_match_bytes(&self, bytes: &[u8]) -> bool {
+        self.is_match(latin1(bytes))
+    }
+
+    /// Find the leftmost match in `bytes`, which need not be UTF-8, like
+    /// the contents of a binary file or a file name, returning its start
+    /// and end offsets. Every byte is read as the char of the same value,
+    /// from U+0000 to U+00FF, so `\xFF` or `[\x80-\xFF]` match high bytes
+    /// and text beyond ASCII is written as its bytes, like `\xC3\xA9` for
+    /// an 'é' in UTF-8. `(?-u)` makes sure every escape names a byte.
+    pub fn find_bytes(&self, bytes: &[u8]) -> Option<(uint, uint)> {
+        let string = latin1(bytes);
+        match self.find(string) {
+            Some((s, e)) => {
+                let start = string.slice(0, s).char_len();
+                Some((start, start + string.slice(s, e).char_len()))
+            },
+            None => None,
+        }
+    }
+
+    /// Whether the whole of `string` matches, as if the pattern were
+    /// wrapped in `\A(?:...)\z`
+    pub fn is_full_match(&self, string: &str) -> bool {
+        match self.engine {
+            Backtracking => backtrack::exec(&self.program.code, self.nslots, self.ncounters, 0,
+                                            string, 0, true).is_some(),
+            _ => self.with_scratch(|scratch| {
+                self.exec(scratch, 0, 2, string, 0, true, true, true)
+            }).is_some(),
+        }
+    }
+
+    /// Find the end of a match as early as possible, which need not be the
+    /// end of the match `find` returns: `a+` in "aaa" stops after one 'a'
+    pub fn shortest_match(&self, string: &str) -> Option<uint> {
+        match self.with_scratch(|scratch| self.search(scratch, string, 0, false, true)) {
+            Some(slots) => slots[1],
+            None => None,
+        }
+    }
+
+    /// Find the leftmost match and the spans of its groups
+    pub fn captures<'a>(&self, string: &'a str) -> Option<Captures<'a>> {
+        match self.with_scratch(|scratch| self.search(scratch, string, 0, true, false)) {
+            Some(slots) => Some(Captures::new(string, slots, &self.program.names)),
+            None => None,
+        }
+    }
+
+    /// Count the non-overlapping matches, without recording the spans of
+    /// any group but the whole match
+    pub fn count_matches(&self, string: &str) -> uint {
+        let mut n = 0;
+        for _ in self.find_iter(string) {
+            n += 1;
+        }
+        n
+    }
+
+    /// Iterate over the spans of successive non-overlapping matches. An
+    /// empty match right where the previous match ended is not reported:
+    /// `a*` yields (0, 0), (1, 3) and (4, 4) in "baab", but not (3, 3).
+    pub fn find_iter<'a>(&'a self, string: &'a str) -> Matches<'a> {
+        Matches {
+            regexp: self,
+            string: string,
+            pos: 0,
+            last_end: None,
+            groups: false,
+            scratch: self.scratch(),
+        }
+    }
+
+    /// Iterate over the match starting at each position of `string`, in
+    /// order, including those overlapping the previous one: `aa` yields
+    /// two matches in "aaa"
+    pub fn find_overlapping_iter<'a>(&'a self, string: &'a str) -> OverlappingMatches<'a> {
+        OverlappingMatches {
+            regexp: self,
+            string: string,
+            pos: 0,
+            scratch: self.scratch(),
+        }
+    }
+
+    /// Iterate over the groups of successive non-overlapping matches
+    pub fn captures_iter<'a>(&'a self, string: &'a str) -> CaptureMatches<'a> {
+        let mut matches = self.find_iter(string);
+        matches.groups = true;
+        CaptureMatches {
+            matches: matches,
+        }
+    }
+
+    /// Iterate over the pieces of `string` separated by the matches
+    /// `find_iter` yields, so that `x*` splits "axb" into "", "a", "b" and
+    /// "", and an empty pattern splits between every two chars
+    pub fn split<'a>(&'a self, string: &'a str) -> Splits<'a> {
+        Splits {
+            matches: self.find_iter(string),
+            last: 0,
+            done: false,
+        }
+    }
+
+    /// Iterate over at most `n` pieces of `string` separated by matches,
+    /// the last one holding the rest of the string
+    pub fn splitn<'a>(&'a self, string: &'a str, n: uint) -> SplitsN<'a> {
+        SplitsN {
+            splits: self.split(string),
+            n: n,
+        }
+    }
+
+    /// Matcher to feed the text in chunks, so that a large file or a
+    /// socket can be searched without reading it whole first, or an error
+    /// if the program has lookarounds or needs the backtracking engine,
+    /// which may read any part of the text
+    pub fn stream<'a>(&'a self) -> Result<StreamMatcher<'a>, ParseError> {
+        if !self.streams() {
+            return Err(ParseError::without_position(error::UnsupportedByStream));
+        }
+        Ok(StreamMatcher {
+            regexp: self,
+            buffer: ~"",
+            base: 0,
+            pos: 0,
+            last_end: None,
+            matched: None,
+            leftmost: None,
+            finished: false,
+            scratch: self.scratch(),
+        })
+    }
+
+    /// Iterate over the spans of successive non-overlapping matches in the
+    /// text `reader` gives, read a block at a time and run through a
+    /// `StreamMatcher`, so that a file need not be read whole first. The
+    /// text must be UTF-8: the matches stop where it is not, as
+    /// `ReaderMatches::error` then tells. Fails like `stream` for the
+    /// programs a stream cannot run.
+    pub fn find_iter_reader<'a, R: Reader>(&'a self, reader: R)
+                                           -> Result<ReaderMatches<'a, R>, ParseError> {
+        match self.stream() {
+            Ok(stream) => Ok(ReaderMatches {
+                stream: stream,
+                reader: reader,
+                partial: ~[],
+                found: ~[],
+                read: 0,
+                done: false,
+                error: None,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the leftmost match in `string` as the start of a longer text,
+    /// telling apart a match that no text to come can change from the
+    /// text still being able to match or to match differently, as protocol
+    /// parsers need to know whether to wait for more input. This is what
+    /// threads still reading chars or stopped at an assertion about the
+    /// text to come tell at the end of `string`. Anchor the pattern with
+    /// `\A` to ask about the start of the text only: otherwise a match can
+    /// always start in the text to come. Fails like `stream`.
+    pub fn partial_match(&self, string: &str) -> Result<PartialMatch, ParseError> {
+        if !self.streams() {
+            return Err(ParseError::without_position(error::UnsupportedByStream));
+        }
+        let mut scratch = self.scratch();
+        scratch.open = true;
+        let (longest, earliest) = (self.program.longest, self.program.earliest);
+        let matched = self.exec(&mut scratch, 0, 2, string, 0, earliest, false, false);
+        let start = match matched {
+            Some(ref slots) => slots[0],
+            None => None,
+        };
+        // the threads left, unless a match was found before the end, of
+        // which only those of a higher priority than the match, or
+        // started no later than it when looking for the longest one, count
+        let mut live = false;
+        if !earliest || matched.is_none() {
+            for t in scratch.threads.iter() {
+                match self.program.code.op(t.pc) {
+                    code::Succeed if !longest => break,
+                    code::Match(_) | code::Assert(_) =>
+                        if !longest || start.is_none()
+                                || t.slots[0].unwrap() <= start.unwrap() {
+                            live = true;
                         },
-                        inst::Dot => new_ips = vec::append(new_ips, self.follow_jump(*addr+1)),
+                    _ => {},
+                }
+            }
+        }
+        Ok(if live {
+            Incomplete
+        } else {
+            match matched {
+                Some(slots) => Complete(slots[0].unwrap(), slots[1].unwrap()),
+                None => NoMatch,
+            }
+        })
+    }
+
+    /// Iterate over the spans of successive non-overlapping matches in
+    /// `units`, UTF-16 text as Windows and JavaScript keep it, in units.
+    /// The text is decoded a chunk at a time into a `StreamMatcher`, so no
+    /// UTF-8 copy of the whole of it is made. An unpaired surrogate reads
+    /// as U+FFFD. Fails like `stream`.
+    pub fn find_iter_utf16<'a>(&'a self, units: &'a [u16])
+                               -> Result<Utf16Matches<'a>, ParseError> {
+        match self.stream() {
+            Ok(stream) => Ok(Utf16Matches {
+                stream: stream,
+                units: units,
+                next: 0,
+                found: ~[],
+                byte: 0,
+                unit: 0,
+                done: false,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the leftmost match with `rep`
+    pub fn replace<R: Replacer>(&self, string: &str, rep: R) -> ~str {
+        self.replacen(string, 1, rep)
+    }
+
+    /// Replace every non-overlapping match with `rep`
+    pub fn replace_all<R: Replacer>(&self, string: &str, rep: R) -> ~str {
+        self.replacen(string, 0, rep)
+    }
+
+    /// Replace the first `limit` matches, or all of them if `limit` is 0
+    fn replacen<R: Replacer>(&self, string: &str, limit: uint, rep: R) -> ~str {
+        let mut result = ~"";
+        let mut last = 0;
+        for (i, caps) in self.captures_iter(string).enumerate() {
+            if limit > 0 && i >= limit {
+                break;
+            }
+            let (s, e) = caps.pos(0).unwrap();
+            result.push_str(string.slice(last, s));
+            rep.replace_append(&caps, &mut result);
+            last = e;
+        }
+        result.push_str(string.slice_from(last));
+        result
+    }
+
+    /// Find the leftmost match starting at byte offset `start` or later,
+    /// recording the spans of the groups only if `groups` is set
+    fn search(&self, scratch: &mut Scratch, string: &str, start: uint, groups: bool,
+              stop_early: bool) -> Option<Slots> {
+        let stop_early = stop_early || self.program.earliest;
+        match self.strategy {
+            LiteralScan(len) => return match self.prefilter.find(string, start) {
+                Some(s) => Some(~[Some(s), Some(s + len)]),
+                None => None,
+            },
+            BitParallel(ref engine) => return match engine.find(string, start) {
+                Some((s, e)) => Some(~[Some(s), Some(e)]),
+                None => None,
+            },
+            LazyDfas(ref reversed) => {
+                let from = match self.prefilter.find(string, start) {
+                    Some(candidate) => candidate,
+                    None => return None,
+                };
+                let end = match scratch.forward.find_end(&self.program.code, string, from,
+                                                         stop_early) {
+                    Some(end) => end,
+                    None => return None,
+                };
+                if stop_early && !self.program.earliest {
+                    if !groups {
+                        // all that `is_match` and `shortest_match` need
+                        return Some(~[None, Some(end)]);
+                    }
+                } else {
+                    // when stopping at the earliest end, the match found is
+                    // the one starting leftmost among those ending there
+                    let start = match scratch.reverse.find_start(&reversed.code, string, from,
+                                                                 end) {
+                        Some(start) => start,
+                        None => fail!("No match ending where the DFA found one."),
+                    };
+                    let end = if self.program.longest && !stop_early {
+                        // the leftmost-first match starts leftmost too
+                        match scratch.forward_longest.find_longest_end(&self.program.code,
+                                                                       string, start) {
+                            Some(end) => end,
+                            None => fail!("No match starting where the DFA found one."),
+                        }
+                    } else {
+                        end
+                    };
+                    if !groups {
+                        return Some(~[Some(start), Some(end)]);
+                    }
+                    // the match is known to start there
+                    return self.search_at(scratch, string, start, groups, stop_early);
+                }
+            },
+            Candidates => (),
+        }
+        let mut start = start;
+        match self.engine {
+            Backtracking => (),
+            _ => {
+                // a single pass, starting a thread at every offset
+                let from = match self.prefilter.find(string, start) {
+                    Some(candidate) => candidate,
+                    None => return None,
+                };
+                let nslots = if groups { self.nslots } else { 2 };
+                return self.exec(scratch, 0, nslots, string, from, stop_early, false, false);
+            },
+        }
+        loop {
+            start = match self.prefilter.find(string, start) {
+                Some(candidate) => candidate,
+                None => return None,
+            };
+            match self.search_at(scratch, string, start, groups, stop_early) {
+                Some(slots) => return Some(slots),
+                None => {},
+            }
+            if start >= string.len() {
+                return None;
+            }
+            start = string.char_range_at(start).next;
+        }
+    }
+
+    /// Find the match starting exactly at byte offset `start`, with
+    /// whichever engine the program needs
+    fn search_at(&self, scratch: &mut Scratch, string: &str, start: uint, groups: bool,
+                 stop_early: bool) -> Option<Slots> {
+        let stop_early = stop_early || self.program.earliest;
+        let code = &self.program.code;
+        let nslots = if groups { self.nslots } else { 2 };
+        match self.engine {
+            // backreferences and conditionals need the groups anyway
+            Backtracking =>
+                backtrack::exec(code, self.nslots, self.ncounters, 0, string, start, false),
+            SinglePass(ref engine) => engine.exec(code, nslots, string, start),
+            Bounded if code.len() * (string.len() - start + 1) <= backtrack::MAX_VISITED =>
+                backtrack::exec_bounded(code, nslots, string, start),
+            Bounded | Simulation =>
+                self.exec(scratch, 0, nslots, string, start, stop_early, false, true),
+        }
+    }
+
+    /// Whether the program can run on a text it does not see whole, having
+    /// no lookaround and not needing the backtracking engine
+    fn streams(&self) -> bool {
+        !self.program.needs_backtracking() &&
+            range(0, self.program.code.len()).all(|pc| match self.program.code.op(pc) {
+                code::LookAhead(*) | code::LookBehind(*) => false,
+                _ => true,
+            })
+    }
+
+    /// Buffers for searching with this regexp
+    fn scratch(&self) -> Scratch {
+        Scratch::new(self.program.code.len())
+    }
+
+    /// Run a one-shot search with the buffers cached by the last one, or
+    /// new ones if another search holds them, and leave them in the cache
+    /// for the next one. The lock is only held to take and put them back.
+    fn with_scratch<T>(&self, search: &fn(&mut Scratch) -> T) -> T {
+        let mut cached = None;
+        // `access` is unsafe as nested calls deadlock, and these never nest
+        unsafe {
+            self.cache.access(|cache| util::swap(cache, &mut cached));
+        }
+        let mut scratch = match cached {
+            Some(scratch) => scratch,
+            None => self.scratch(),
+        };
+        let result = search(&mut scratch);
+        let mut cached = Some(scratch);
+        unsafe {
+            self.cache.access(|cache| util::swap(cache, &mut cached));
+        }
+        result
+    }
+
+    /// Run the program from address `pc` at byte offset `start` of `string`,
+    /// returning the first `nslots` slots of the highest-priority match, or
+    /// of the first one found if `stop_early` is set. If `full` is set, only
+    /// matches ending at the end of `string` count. Unless `anchored` is
+    /// set, a new thread of the lowest priority starts at every offset until
+    /// a match is found, so the leftmost match is found in a single pass;
+    /// when no thread is left, the search skips ahead to the next offset
+    /// the prefilter finds, unless the text is open.
+    /// Threads are kept in the order they started in, so for a program
+    /// finding the longest match, those started after the first match
+    /// found are the ones to drop.
+    fn exec(&self, scratch: &mut Scratch, pc: uint, nslots: uint, string: &str, start: uint,
+            stop_early: bool, full: bool, anchored: bool) -> Option<Slots> {
+        scratch.threads.clear();
+        scratch.visited.clear();
+        let mut matched = None;
+        // where the match found starts, when looking for the longest one
+        let longest = self.program.longest && nslots > 0;
+        let mut leftmost = None;
+        let first = Thread {
+            pc: pc,
+            slots: vec::from_elem(nslots, None),
+            counters: vec::from_elem(self.ncounters, 0u),
+        };
+        let mut i = start;
+        loop {
+            if i > start && !anchored && matched.is_none() && !scratch.open
+                    && scratch.threads.is_empty() {
+                // nothing is under way, so skip to where a match can start
+                match self.prefilter.find(string, i) {
+                    Some(candidate) if candidate > i => {
+                        // the states seen at `i` say nothing about there
+                        scratch.visited.clear();
+                        i = candidate;
                     },
-                    inst::Succeed => result = Matched,
-                    _ => fail!("Unexpected jump instruction."),
+                    Some(_) => {},
+                    None => break,
                 }
             }
-            self.ips = new_ips;
-            result
+            if i == start || (!anchored && matched.is_none()) {
+                self.follow_jump(first.clone(), string, i, scratch.open, &mut scratch.stack,
+                                 &mut scratch.visited, &mut scratch.threads,
+                                 &mut scratch.lookaround);
+            }
+            if scratch.threads.is_empty() && (anchored || matched.is_some()) {
+                break;
+            }
+            let (ch, next) = if i < string.len() {
+                let CharRange {ch, next} = string.char_range_at(i);
+                (Some(ch), next)
+            } else {
+                (None, i)
+            };
+            if self.step(scratch, string, ch, next, full, longest, stop_early, &mut leftmost,
+                         &mut matched) {
+                return matched;
+            }
+            if ch.is_none() {
+                break;
+            }
+            util::swap(&mut scratch.threads, &mut scratch.next_threads);
+            util::swap(&mut scratch.visited, &mut scratch.next_visited);
+            i = next;
+        }
+        matched
+    }
+
+    /// Move the threads of `scratch` over `ch`, the char at the current
+    /// offset, into its next threads at byte offset `next`, where `ch`
+    /// ends. The first thread to succeed records its slots in `matched`
+    /// and drops the threads of a lower priority, or, when looking for the
+    /// longest match, those started after it, whose start it records in
+    /// `leftmost`; a thread started before it may still succeed later and
+    /// replace it. Returns whether to stop there, as a match was found and
+    /// `stop_early` is set.
+    fn step(&self, scratch: &mut Scratch, string: &str, ch: Option<char>, next: uint,
+            full: bool, longest: bool, stop_early: bool, leftmost: &mut Option<uint>,
+            matched: &mut Option<Slots>) -> bool {
+        let open = scratch.open;
+        let Scratch {
+            threads: ref threads,
+            next_threads: ref mut next_threads,
+            stack: ref mut stack,
+            visited: _,
+            next_visited: ref mut next_visited,
+            open: _,
+            forward: _,
+            reverse: _,
+            forward_longest: _,
+            lookaround: ref mut lookaround,
+        } = *scratch;
+        next_threads.clear();
+        next_visited.clear();
+        let mut succeeded = false;
+        for t in threads.iter() {
+            match self.program.code.op(t.pc) {
+                // a thread started after the match cannot replace it, but
+                // one started earlier still can
+                _ if leftmost.is_some() && t.slots[0].unwrap() > leftmost.unwrap() => {},
+                code::Match(m) => match ch {
+                    Some(c) if m.matches(c) =>
+                        self.follow_jump(Thread { pc: t.pc + 1, .. t.clone() }, string, next, open,
+                                         &mut *stack, &mut *next_visited, &mut *next_threads,
+                                         &mut *lookaround),
+                    _ => {},
+                },
+                // stopped at an assertion the text to come decides
+                code::Assert(_) => next_threads.push(t.clone()),
+                code::Succeed if full && ch.is_some() => {},
+                // only the first thread to succeed here counts, as threads
+                // are in the order they started in, and the others go on in
+                // case they find a longer match; it starts no later than
+                // the match found before, which it replaces
+                code::Succeed if longest => if !succeeded {
+                    succeeded = true;
+                    *leftmost = t.slots[0];
+                    *matched = Some(t.slots.clone());
+                    if stop_early {
+                        return true;
+                    }
+                },
+                code::Succeed => {
+                    *matched = Some(t.slots.clone());
+                    if stop_early {
+                        return true;
+                    }
+                    // the remaining threads have a lower priority
+                    break;
+                },
+                _ => fail!("Unexpected jump instruction."),
+            }
         }
+        false
     }
 
-    fn follow_jump(&self, i: uint) -> ~[uint] {
-        let mut addresses = ~[];
-        let mut working_set = ~[i];
-        while !working_set.is_empty() {
-            let mut new_working_set = ~[];
-            for address in working_set.iter() {
-                match self.program[*address] {
-                    inst::Split(a, b) => {
-                        new_working_set.push(a);
-                        new_working_set.push(b);
+    /// Add to `threads` the threads reachable from `t` without consuming
+    /// input, in priority order, checking zero-width assertions and
+    /// recording captures and counting loops at byte offset `pos`, and
+    /// skipping the states `visited` already has a thread in. If `open` is
+    /// set, a thread at an assertion that depends on the text after
+    /// `string` is added as it is, stopped there. `stack` is left empty,
+    /// as it is found, and lookarounds are run with the buffers of
+    /// `lookaround`.
+    fn follow_jump(&self, t: Thread, string: &str, pos: uint, open: bool, stack: &mut ~[Thread],
+                   visited: &mut Visited, threads: &mut ~[Thread],
+                   lookaround: &mut Option<~Scratch>) {
+        stack.push(t);
+        while !stack.is_empty() {
+            let mut t = stack.pop();
+            // a thread of a higher priority may have got there already
+            if visited.first(&t) {
+                match self.program.code.op(t.pc) {
+                    code::Split(a, b) => {
+                        stack.push(Thread { pc: b, .. t.clone() });
+                        t.pc = a;
+                        stack.push(t);
+                    },
+                    code::Jmp(a) => {
+                        t.pc = a;
+                        stack.push(t);
+                    },
+                    code::Assert(ref a) => if open && a.needs_more(string, pos) {
+                        threads.push(t);
+                    } else if a.holds(string, pos) {
+                        t.pc += 1;
+                        stack.push(t);
+                    },
+                    code::LookAhead(body, negated) =>
+                        if self.look(lookaround, body, string, pos) != negated {
+                            t.pc += 1;
+                            stack.push(t);
+                        },
+                    code::LookBehind(body, negated, width) => {
+                        let found = match inst::rewind(string, pos, width) {
+                            Some(start) => self.look(lookaround, body, string, start),
+                            None => false,
+                        };
+                        if found != negated {
+                            t.pc += 1;
+                            stack.push(t);
+                        }
+                    },
+                    code::Save(slot) => {
+                        if slot < t.slots.len() {
+                            t.slots[slot] = Some(pos);
+                        }
+                        t.pc += 1;
+                        stack.push(t);
+                    },
+                    code::Reset(counter) => {
+                        t.counters[counter] = 0;
+                        t.pc += 1;
+                        stack.push(t);
+                    },
+                    code::Increment(counter) => {
+                        t.counters[counter] += 1;
+                        t.pc += 1;
+                        stack.push(t);
+                    },
+                    code::CountBelow(counter, n) => if t.counters[counter] < n {
+                        t.pc += 1;
+                        stack.push(t);
                     },
-                    inst::Jmp(a) => new_working_set.push(a),
-                    _ => addresses.push(*address),
+                    code::CountAtLeast(counter, n) => if t.counters[counter] >= n {
+                        t.pc += 1;
+                        stack.push(t);
+                    },
+                    _ => threads.push(t),
                 }
             }
-            working_set = new_working_set;
         }
-        addresses
     }
+
+    /// Whether the lookaround body at address `body` matches from byte
+    /// offset `start`, run with the buffers `lookaround` keeps from one
+    /// lookaround to the next, made the first time
+    fn look(&self, lookaround: &mut Option<~Scratch>, body: uint, string: &str,
+            start: uint) -> bool {
+        if lookaround.is_none() {
+            *lookaround = Some(~self.scratch());
+        }
+        match *lookaround {
+            Some(ref mut scratch) =>
+                self.exec(&mut **scratch, body, 0, string, start, true, false, true).is_some(),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Regexp {
+    /// Compile `s` with the default options, or return `None` if it is not
+    /// a valid pattern; use `Regexp::new` to learn why
+    fn from_str(s: &str) -> Option<Regexp> {
+        match Regexp::new(s) {
+            Ok(r) => Some(r),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Compiles a pattern with options other than the defaults:
+///
+///     let r = RegexpBuilder::new("^abc$").case_insensitive(true).multi_line(true).build();
+pub struct RegexpBuilder {
+    pattern: ~str,
+    options: compile::Options,
+}
+
+impl RegexpBuilder {
+    pub fn new(pattern: &str) -> RegexpBuilder {
+        RegexpBuilder {
+            pattern: pattern.to_owned(),
+            options: compile::Options::new(),
+        }
+    }
+
+    /// See `compile::Options::case_insensitive`
+    pub fn case_insensitive(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.case_insensitive = yes;
+        builder
+    }
+
+    /// See `compile::Options::multi_line`
+    pub fn multi_line(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.multi_line = yes;
+        builder
+    }
+
+    /// See `compile::Options::dot_all`
+    pub fn dot_all(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.dot_all = yes;
+        builder
+    }
+
+    /// See `compile::Options::extended`
+    pub fn extended(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.extended = yes;
+        builder
+    }
+
+    /// See `compile::Options::octal`
+    pub fn octal(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.octal = yes;
+        builder
+    }
+
+    /// See `compile::Options::unicode`
+    pub fn unicode(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.unicode = yes;
+        builder
+    }
+
+    /// See `compile::Options::class_sets`
+    pub fn class_sets(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.class_sets = yes;
+        builder
+    }
+
+    /// See `compile::Options::longest`
+    pub fn longest(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.longest = yes;
+        builder
+    }
+
+    /// See `compile::Options::earliest`
+    pub fn earliest(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.earliest = yes;
+        builder
+    }
+
+    /// See `compile::Options::swap_greed`
+    pub fn swap_greed(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.swap_greed = yes;
+        builder
+    }
+
+    /// See `compile::Options::nest_limit`
+    pub fn nest_limit(self, limit: uint) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.nest_limit = limit;
+        builder
+    }
+
+    /// See `compile::Options::unroll_limit`; raising it trades a larger
+    /// program for matching without counters
+    pub fn unroll_limit(self, limit: uint) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.unroll_limit = limit;
+        builder
+    }
+
+    /// See `compile::Options::dfa_state_limit`
+    pub fn dfa_state_limit(self, limit: uint) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.dfa_state_limit = limit;
+        builder
+    }
+
+    /// See `compile::Options::size_limit`
+    pub fn size_limit(self, limit: uint) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.size_limit = limit;
+        builder
+    }
+
+    /// See `compile::Options::backtrack`
+    pub fn backtrack(self, yes: bool) -> RegexpBuilder {
+        let mut builder = self;
+        builder.options.backtrack = yes;
+        builder
+    }
+
+    pub fn build(&self) -> Result<Regexp, ParseError> {
+        compile_with(self.pattern, &self.options)
+    }
+
+    /// Build a DFA ahead of time instead, see `dfa::Dfa`
+    pub fn build_dfa(&self) -> Result<Dfa, ParseError> {
+        match compile::compile(self.pattern, &self.options) {
+            Ok(p) => Dfa::new(&p, self.options.dfa_state_limit),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Quote `text` so that it matches itself when used as a pattern, or as
+/// part of one, even in extended mode
+pub fn escape(text: &str) -> ~str {
+    let mut quoted = ~"";
+    for c in text.iter() {
+        match c {
+            '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}'
+            | '^' | '$' | '#' | '-' => quoted.push_char('\\'),
+            // extended mode drops unescaped whitespace
+            _ if c.is_whitespace() => quoted.push_char('\\'),
+            _ => (),
+        }
+        quoted.push_char(c);
+    }
+    quoted
+}
+
+pub fn compile(pattern: &str) -> Result<Regexp, ParseError> {
+    compile_with(pattern, &compile::Options::new())
 }
 
-pub fn compile(pattern: &str) -> Result<Engine, ~str> {
-    match compile::compile(pattern) {
-        Ok(p) => Ok(Engine::new(p)),
+pub fn compile_with(pattern: &str, options: &compile::Options) -> Result<Regexp, ParseError> {
+    match compile::compile(pattern, options) {
+        Ok(p) => Ok(Regexp::from_program(p)),
         Err(e) => Err(e),
     }
 }
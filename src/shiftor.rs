@@ -0,0 +1,90 @@
+use std::str::CharRange;
+
+use compile::code;
+use compile::inst;
+
+/// most chars a pattern may match for `ShiftOr` to run it, one per bit
+static MAX_POSITIONS: uint = 64;
+
+/// chars below this have their masks kept in a table
+static TABLE_SIZE: uint = 128;
+
+/// Bit-parallel engine for patterns matching a fixed number of chars, each
+/// from a class of its own, like `\d{4}-\d\d` or `x[^x]{3}y`, and nothing
+/// else: no group, no assertion and no repetition but a counted one. It
+/// keeps one bit per position of the pattern, cleared while the text read
+/// so far ends with what the pattern matches up to there, and moves all of
+/// them over a char with a shift and an or.
+pub struct ShiftOr {
+    /// what every position matches
+    positions: ~[inst::Match],
+    /// mask of every char below `TABLE_SIZE`, with the bit of every
+    /// position not matching it set
+    table: ~[u64],
+}
+
+impl ShiftOr {
+    /// Engine for `code`, or `None` if it matches more than a sequence of
+    /// up to `MAX_POSITIONS` chars
+    pub fn new(code: &code::Code) -> Option<ShiftOr> {
+        let n = code.len();
+        if n < 4 || n - 3 > MAX_POSITIONS {
+            return None;
+        }
+        let mut positions = ~[];
+        for pc in range(0, n) {
+            match code.op(pc) {
+                code::Save(0) if pc == 0 => (),
+                code::Save(1) if pc + 2 == n => (),
+                code::Succeed if pc + 1 == n => (),
+                code::Match(m) if pc > 0 && pc + 2 < n => positions.push(m.clone()),
+                _ => return None,
+            }
+        }
+        let mut engine = ShiftOr {
+            positions: positions,
+            table: ~[],
+        };
+        let table = range(0, TABLE_SIZE).map(|c| engine.compute_mask(c as char)).collect();
+        engine.table = table;
+        Some(engine)
+    }
+
+    /// Span of the first match starting at byte offset `start` or later
+    pub fn find(&self, string: &str, start: uint) -> Option<(uint, uint)> {
+        let last = 1u64 << (self.positions.len() - 1) as u64;
+        let mut state = !0u64;
+        let mut i = start;
+        while i < string.len() {
+            let CharRange {ch, next} = string.char_range_at(i);
+            state = state << 1 | self.mask(ch);
+            if state & last == 0 {
+                // every match is as many chars long as there are positions
+                match inst::rewind(string, next, self.positions.len()) {
+                    Some(s) => return Some((s, next)),
+                    None => fail!("Match longer than the text."),
+                }
+            }
+            i = next;
+        }
+        None
+    }
+
+    fn mask(&self, c: char) -> u64 {
+        if (c as uint) < TABLE_SIZE {
+            self.table[c as uint]
+        } else {
+            self.compute_mask(c)
+        }
+    }
+
+    fn compute_mask(&self, c: char) -> u64 {
+        let mut mask = 0u64;
+        for (j, m) in self.positions.iter().enumerate() {
+            if !m.matches(c) {
+                mask |= 1 << j as u64;
+            }
+        }
+        mask
+    }
+}
@@ -0,0 +1,45 @@
+use std::vec;
+
+/// Set of integers below a bound, with constant-time insertion, lookup and
+/// clearing, used to keep track of the addresses a step of the NFA
+/// simulation already has a thread at. Members are kept in `dense` in
+/// insertion order, and `sparse` gives the index of every member there; a
+/// stale index left by a cleared member points past the end of `dense` or
+/// to another member, so `sparse` never needs to be reset.
+pub struct SparseSet {
+    priv dense: ~[uint],
+    priv sparse: ~[uint],
+}
+
+impl SparseSet {
+    /// Empty set for integers below `size`
+    pub fn new(size: uint) -> SparseSet {
+        SparseSet {
+            dense: vec::with_capacity(size),
+            sparse: vec::from_elem(size, 0u),
+        }
+    }
+
+    pub fn contains(&self, n: uint) -> bool {
+        let i = self.sparse[n];
+        i < self.dense.len() && self.dense[i] == n
+    }
+
+    /// Add `n`, returning whether it was not there yet
+    pub fn insert(&mut self, n: uint) -> bool {
+        if self.contains(n) {
+            return false;
+        }
+        self.sparse[n] = self.dense.len();
+        self.dense.push(n);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    pub fn len(&self) -> uint {
+        self.dense.len()
+    }
+}
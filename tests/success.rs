@@ -1,18 +1,312 @@
+extern mod extra;
 extern mod re;
 
+use std::comm::SharedChan;
+use std::comm::stream;
+use std::io;
+use std::task;
+use std::vec;
+
+use extra::arc;
 use re::*;
 
 fn test_success(pattern: &str, string: &str) {
     match re::compile(pattern) {
         Ok(p) => {
-            let mut pm = p;
-            if !pm.matches(string) {
+            let pm = p;
+            if !pm.is_match(string) {
                 printfln!("\n[FAILED] Pattern '%s' against '%s'.", pattern, string);
             } else {
                 print(".");
             }
         },
-        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e),
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_failure(pattern: &str, string: &str) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            if pm.is_match(string) {
+                printfln!("\n[FAILED] Pattern '%s' unexpectedly matched '%s'.", pattern, string);
+            } else {
+                print(".");
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_captures(pattern: &str, string: &str, expected: &[Option<(uint, uint)>]) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            match pm.captures(string) {
+                Some(caps) => {
+                    let mut ok = caps.len() == expected.len();
+                    for (i, e) in expected.iter().enumerate() {
+                        ok = ok && caps.pos(i) == *e;
+                    }
+                    if ok {
+                        print(".");
+                    } else {
+                        printfln!("\n[FAILED] Pattern '%s' against '%s' captured %?.",
+                                  pattern, string, caps.spans);
+                    }
+                },
+                None => printfln!("\n[FAILED] Pattern '%s' against '%s'.", pattern, string),
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_find(pattern: &str, string: &str, expected: Option<(uint, uint)>) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            let found = pm.find(string);
+            if found == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Pattern '%s' against '%s' found %?.", pattern, string, found);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_find_iter(pattern: &str, string: &str, expected: &[(uint, uint)]) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            let found: ~[(uint, uint)] = pm.find_iter(string).collect();
+            if found.as_slice() == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Pattern '%s' against '%s' found %?.", pattern, string, found);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_replace(pattern: &str, string: &str, template: &str, all: bool, expected: &str) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            let result = if all {
+                pm.replace_all(string, template)
+            } else {
+                pm.replace(string, template)
+            };
+            if result.as_slice() == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Replacing '%s' in '%s' gave '%s'.", pattern, string, result);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_split(pattern: &str, string: &str, expected: &[&str]) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            let pieces: ~[&str] = pm.split(string).collect();
+            if pieces.as_slice() == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Splitting '%s' by '%s' gave %?.", string, pattern, pieces);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_splitn(pattern: &str, string: &str, n: uint, expected: &[&str]) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            let pieces: ~[&str] = pm.splitn(string, n).collect();
+            if pieces.as_slice() == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Splitting '%s' by '%s' gave %?.", string, pattern, pieces);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_full_match(pattern: &str, string: &str, expected: bool) {
+    match re::compile(pattern) {
+        Ok(p) => {
+            let pm = p;
+            if pm.is_full_match(string) == expected {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Full match of '%s' against '%s'.", pattern, string);
+            }
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_error(pattern: &str, kind: compile::error::ErrorKind, position: Option<uint>) {
+    match re::compile(pattern) {
+        Ok(_) => printfln!("\n[FAILED] Pattern '%s' compiled.", pattern),
+        Err(e) => if e.kind == kind && e.position == position {
+            print(".");
+        } else {
+            printfln!("\n[FAILED] Pattern '%s' failed with: %s.", pattern, e.to_str());
+        },
+    }
+}
+
+fn parse(pattern: &str) -> ~[compile::parse::Ast] {
+    let mut parser = compile::parse::Parser::new(pattern, &compile::Options::new());
+    match parser.parse() {
+        Ok(ast) => ast,
+        Err(e) => fail!(fmt!("Parsing '%s' failed: %s.", pattern, e.to_str())),
+    }
+}
+
+fn test_to_pattern(pattern: &str, expected: &str) {
+    let written = compile::parse::to_pattern(parse(pattern));
+    if written != expected.to_owned() {
+        printfln!("\n[FAILED] Writing '%s' back gave '%s'.", pattern, written);
+    } else {
+        print(".");
+    }
+}
+
+struct GroupCounter {
+    count: uint,
+}
+
+impl compile::parse::Visitor for GroupCounter {
+    fn visit_one(&mut self, one: &compile::parse::One) {
+        match *one {
+            compile::parse::Group(Some(_), _, _) => self.count += 1,
+            _ => (),
+        }
+        compile::parse::walk_one(self, one);
+    }
+}
+
+fn test_class_sets(pattern: &str, string: &str, expected: bool) {
+    match re::RegexpBuilder::new(pattern).class_sets(true).build() {
+        Ok(r) => if r.is_match(string) == expected { print(".") } else {
+            printfln!("\n[FAILED] Class set '%s' against '%s'.", pattern, string);
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_ranges(set: &compile::charset::CharSet, expected: &[(char, char)]) {
+    if set.ranges() != expected {
+        printfln!("\n[FAILED] Character set %?, expected %?.", set.ranges(), expected);
+    } else {
+        print(".");
+    }
+}
+
+fn test_dfa(pattern: &str, string: &str, expected: Option<uint>) {
+    match re::RegexpBuilder::new(pattern).build_dfa() {
+        Ok(dfa) => if dfa.find_end(string, 0) == expected { print(".") } else {
+            printfln!("\n[FAILED] DFA for '%s' against '%s'.", pattern, string);
+        },
+        Err(e) => printfln!("\nBuilding a DFA for '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_longest(pattern: &str, string: &str, expected: Option<(uint, uint)>) {
+    match re::RegexpBuilder::new(pattern).longest(true).build() {
+        Ok(r) => if r.find(string) == expected { print(".") } else {
+            printfln!("\n[FAILED] Longest match of '%s' in '%s'.", pattern, string);
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_earliest(pattern: &str, string: &str, expected: Option<(uint, uint)>) {
+    match re::RegexpBuilder::new(pattern).earliest(true).build() {
+        Ok(r) => if r.find(string) == expected { print(".") } else {
+            printfln!("\n[FAILED] Earliest match of '%s' in '%s'.", pattern, string);
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_stream(pattern: &str, string: &str, expected: &[(uint, uint)]) {
+    let pm = match re::compile(pattern) {
+        Ok(p) => p,
+        Err(e) => {
+            printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str());
+            return;
+        },
+    };
+    // feed the text in chunks of every size up to the whole of it
+    for size in range(1u, string.char_len() + 2) {
+        let mut stream = match pm.stream() {
+            Ok(stream) => stream,
+            Err(e) => {
+                printfln!("\nStreaming '%s' failed: %s.", pattern, e.to_str());
+                return;
+            },
+        };
+        let mut found = ~[];
+        let mut chunk = ~"";
+        for c in string.iter() {
+            chunk.push_char(c);
+            if chunk.char_len() == size {
+                found.push_all_move(stream.feed(chunk));
+                chunk = ~"";
+            }
+        }
+        found.push_all_move(stream.feed(chunk));
+        found.push_all_move(stream.finish());
+        if found.as_slice() != expected {
+            printfln!("\n[FAILED] Streaming '%s' through '%s' in chunks of %u found %?.",
+                      pattern, string, size, found);
+            return;
+        }
+    }
+    print(".");
+}
+
+fn test_partial(pattern: &str, string: &str, expected: PartialMatch) {
+    match re::compile(pattern) {
+        Ok(r) => match r.partial_match(string) {
+            Ok(found) => if found == expected { print(".") } else {
+                printfln!("\n[FAILED] Partial match of '%s' in '%s' is %?.", pattern, string,
+                          found);
+            },
+            Err(e) => printfln!("\nPartial matching '%s' failed: %s.", pattern, e.to_str()),
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
+    }
+}
+
+fn test_utf16(pattern: &str, units: &[u16], expected: &[(uint, uint)]) {
+    match re::compile(pattern) {
+        Ok(r) => match r.find_iter_utf16(units) {
+            Ok(m) => {
+                let mut matches = m;
+                let found: ~[(uint, uint)] = matches.collect();
+                if found.as_slice() == expected {
+                    print(".");
+                } else {
+                    printfln!("\n[FAILED] Pattern '%s' against %? found %?.", pattern, units,
+                              found);
+                }
+            },
+            Err(e) => printfln!("\nMatching '%s' failed: %s.", pattern, e.to_str()),
+        },
+        Err(e) => printfln!("\nCompiling '%s' failed: %s.", pattern, e.to_str()),
     }
 }
 
@@ -50,9 +344,1269 @@ fn main() {
     test_success(s, "cam");
     test_success(s, "can");
     test_success(s, "cab");
+    let s = ~"a.c";
+    test_success(s, "abc");
+    test_success(s, "a c");
+    test_failure(s, "a\nc");
+    test_failure(s, "ac");
     println("\nEscaped characters");
     let s = ~"\\?\\*\\+\\.\\|\\(\\)";
     test_success(s, "?*+.|()");
+    println("\nCharacter classes");
+    let s = ~"[bcr]at";
+    test_success(s, "bat");
+    test_success(s, "cat");
+    test_success(s, "a rat");
+    let s = ~"[a-z0-9]+@";
+    test_success(s, "user42@");
+    test_success(s, "mail: x@");
+    let s = ~"[]-]";
+    test_success(s, "]");
+    test_success(s, "-");
+    let s = ~"^[x-zd-fa-cb-e]+$";
+    test_success(s, "abcdefxyz");
+    test_failure(s, "abcg");
+    println("\nNegated character classes");
+    let s = ~"[^0-9]+";
+    test_success(s, "abc");
+    test_success(s, "123a");
+    let s = ~"x[^aeiou]z";
+    test_success(s, "xyz");
+    test_success(s, "x^z");
+    test_failure(s, "xaz");
+    let s = ~"^[^d-fa-cb-e]$";
+    test_success(s, "g");
+    test_success(s, "\x00");
+    test_failure(s, "a");
+    test_failure(s, "f");
+    println("\nAnchors");
+    let s = ~"^foo$";
+    test_success(s, "foo");
+    test_failure(s, " foo");
+    test_failure(s, "foo ");
+    let s = ~"^a|b$";
+    test_success(s, "abc");
+    test_success(s, "cab");
+    test_failure(s, "cba");
+    test_success("^$", "");
+    test_success("x*$", "");
+    println("\nCounted repetition");
+    let s = ~"^a{3}$";
+    test_success(s, "aaa");
+    test_failure(s, "aa");
+    test_failure(s, "aaaa");
+    let s = ~"^a{2,}$";
+    test_success(s, "aa");
+    test_success(s, "aaaaa");
+    test_failure(s, "a");
+    let s = ~"^(ab){1,2}c$";
+    test_success(s, "abc");
+    test_success(s, "ababc");
+    test_failure(s, "abababc");
+    test_failure(s, "c");
+    // large counts compile to loops with counters
+    let s = ~"^a{1,100}$";
+    test_success(s, "a".repeat(100));
+    test_failure(s, "a".repeat(101));
+    let s = ~"^(ab){40,50}c$";
+    test_success(s, "ab".repeat(45) + "c");
+    test_failure(s, "ab".repeat(39) + "c");
+    let s = ~"^a{40,}$";
+    test_success(s, "a".repeat(41));
+    test_failure(s, "a".repeat(39));
+    test_captures("a{35,40}?", "a".repeat(45), [Some((0, 35))]);
+    test_captures("(a{40,50})a", "a".repeat(45), [Some((0, 45)), Some((0, 44))]);
+    test_success("^(?:a{40,50}+)a$", "a".repeat(51));
+    test_failure("^(?:a{40,50}+)a$", "a".repeat(50));
+    let b = "b".repeat(33);
+    test_success("^(?:(a)b{33}\\1){2}$", fmt!("a%saa%sa", b, b));
+    println("\nLazy quantifiers");
+    let s = ~"<.+?>";
+    test_success(s, "<a><b>");
+    test_failure(s, "<>");
+    let s = ~"^a*?b??c{1,3}?$";
+    test_success(s, "aac");
+    test_success(s, "bccc");
+    test_failure(s, "bcccc");
+    println("\nPerl classes");
+    let s = ~"^\\d+\\.\\d+$";
+    test_success(s, "3.14");
+    test_failure(s, "3.x4");
+    let s = ~"\\w+\\s\\W";
+    test_success(s, "foo_1 !");
+    test_failure(s, "foo bar");
+    let s = ~"^\\D\\S$";
+    test_success(s, "a-");
+    test_failure(s, "1-");
+    test_failure(s, "a ");
+    let s = ~"^[\\d\\s-]+$";
+    test_success(s, "555 12-34");
+    test_failure(s, "555x");
+    println("\nWord boundaries");
+    let s = ~"\\bcat\\b";
+    test_success(s, "cat");
+    test_success(s, "a cat!");
+    test_failure(s, "concatenate");
+    let s = ~"\\Bcat\\B";
+    test_success(s, "concatenate");
+    test_failure(s, "a cat");
+    println("\nCaptures");
+    test_captures("(\\d+)-(\\d+)", "tel 555-1234", [Some((4, 12)), Some((4, 7)), Some((8, 12))]);
+    test_captures("a(b)?c", "ac", [Some((0, 2)), None]);
+    test_captures("(a+)(a*)", "aaa", [Some((0, 3)), Some((0, 3)), Some((3, 3))]);
+    test_captures("(a+?)(a*)", "aaa", [Some((0, 3)), Some((0, 1)), Some((1, 3))]);
+    test_captures("(\\w)+", "abc", [Some((0, 3)), Some((2, 3))]);
+    println("\nNamed groups");
+    test_captures("(?P<year>\\d{4})-(?P<month>\\d\\d)", "on 2013-08",
+                  [Some((3, 10)), Some((3, 7)), Some((8, 10))]);
+    match re::compile("(?P<year>\\d{4})-(?P<month>\\d\\d)") {
+        Ok(p) => {
+            let pm = p;
+            match pm.captures("on 2013-08") {
+                Some(caps) if caps.name("year") == Some((3, 7)) && caps.name("day").is_none() =>
+                    print("."),
+                _ => println("\n[FAILED] Looking up group 'year'."),
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::compile("(\\w+)(x)?@(\\w+)") {
+        Ok(p) => {
+            let pm = p;
+            match pm.captures("to: joe@example") {
+                Some(caps) => {
+                    let groups: ~[Option<&str>] = caps.iter().collect();
+                    if caps.at(1) == "joe" && caps.get(2).is_none() && caps.at(2) == ""
+                        && groups == ~[Some("joe@example"), Some("joe"), None, Some("example")] {
+                        print(".");
+                    } else {
+                        println("\n[FAILED] Getting the text of groups.");
+                    }
+                },
+                None => println("\n[FAILED] Getting the text of groups."),
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nNon-capturing groups");
+    test_captures("(?:ab)+(c)", "ababc", [Some((0, 5)), Some((4, 5))]);
+    test_captures("^(?:a|b)*$", "abba", [Some((0, 4))]);
+    test_failure("^(?:a|b)$", "");
+    test_find("a|b|c", "xc", Some((1, 2)));
+    test_success("^(?:a||b)$", "");
+    test_find("(?:|a)b", "ab", Some((0, 2)));
+    println("\nBackreferences");
+    let s = ~"(\\w+) \\1";
+    test_success(s, "hello hello");
+    test_failure(s, "hello world");
+    test_captures("<(\\w+)>.*</\\1>", "x<b>y</i></b>", [Some((1, 13)), Some((2, 3))]);
+    test_failure("^(a)?b\\1$", "b");
+    // loops whose body matches empty, which must not repeat at the same offset
+    test_failure("(a*)*\\1b", "aac");
+    test_failure("(a?)*\\1c", "aab");
+    test_success("(a?)*\\1b", "aab");
+    test_failure("(?:a?)*+b", "aac");
+    test_success("(?:a?)*+b", "aab");
+    test_failure("(b)?(?:)*\\1c", "ab");
+    test_success("(b)?(?:)*\\1c", "bbc");
+    let mut opts = compile::Options::new();
+    opts.backtrack = false;
+    match re::compile_with("(a)\\1", &opts) {
+        Ok(_) => println("\n[FAILED] Backreference compiled without backtracking."),
+        Err(_) => print("."),
+    }
+    println("\nInline flags");
+    test_success("(?i)abc", "abc");
+    test_success("a(?ms)b", "ab");
+    test_success("a(?s-i:b)c", "abc");
+    test_captures("(a(?i)b)(c)", "abc", [Some((0, 3)), Some((0, 2)), Some((2, 3))]);
+    match re::compile("(?q)a") {
+        Ok(_) => println("\n[FAILED] Unknown flag accepted."),
+        Err(_) => print("."),
+    }
+    test_success("^(?i:a(?-i:b)c)$", "AbC");
+    test_failure("^(?i:a(?-i:b)c)$", "ABC");
+    test_success("^(?i:a(?-i)b)B$", "AbB");
+    test_failure("(?i:a)b", "AB");
+    test_success("(?x-i: a b )c", "abc");
+    match re::compile("(?i-:a)") {
+        Ok(_) => println("\n[FAILED] Dangling '-' accepted."),
+        Err(_) => print("."),
+    }
+    println("\nCase-insensitive matching");
+    let s = ~"(?i)hello [a-c]+";
+    test_success(s, "HeLLo aBc");
+    test_failure(s, "HeLLo d");
+    test_failure("(?i)[^a]", "A");
+    test_success("a(?i:b)c", "aBc");
+    test_failure("a(?i:b)c", "aBC");
+    test_success("(?i)é", "É");
+    test_success("(?i)[à-é]+", "ÀÉ");
+    test_success("(?i)k", "\u212a");
+    test_failure("(?i-u)k", "\u212a");
+    test_success("(?i)(a)\\1", "aA");
+    test_success("(?i)(é)\\1", "éÉ");
+    test_success("^(?i)(k)\\1$", "k\u212a");
+    test_failure("(a)\\1", "aA");
+    test_failure("(a)(?i:\\1)b", "aAB");
+    let mut opts = compile::Options::new();
+    opts.case_insensitive = true;
+    match re::compile_with("rust", &opts) {
+        Ok(p) => {
+            let pm = p;
+            if pm.is_match("RUST") {
+                print(".");
+            } else {
+                println("\n[FAILED] Case-insensitive option ignored.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nMulti-line mode");
+    let s = ~"(?m)^ERROR: \\w+$";
+    test_success(s, "INFO: ok\nERROR: disk\nINFO: ok");
+    test_failure(s, "INFO: ERROR: disk");
+    test_failure("^ERROR$", "INFO\nERROR\nINFO");
+    test_captures("(?m)^(\\w+)$", "a b\ncd\n", [Some((4, 6)), Some((4, 6))]);
+    println("\nDot-all mode");
+    test_success("(?s)a.c", "a\nc");
+    test_failure("(?s:a).c", "a\nc");
+    test_captures("(?s)<(.*)>", "<a\nb>", [Some((0, 5)), Some((1, 4))]);
+    println("\nExtended mode");
+    let s = ~"(?x)
+        (\\d{4}) - (\\d{2})   # year and month
+        \\ [ ]? \\#            # escaped space, bracketed space and hash
+    ";
+    test_captures(s, "2013-08 #", [Some((0, 9)), Some((0, 4)), Some((5, 7))]);
+    test_failure(s, "2013-08#");
+    test_success("(?x: a b )c d", "abc d");
+    test_failure("(?x: a b )c d", "abcd");
+    println("\nLookahead");
+    let s = ~"^(?=.*\\d)(?=.*[a-z])\\w{6,}$";
+    test_success(s, "abc123");
+    test_failure(s, "abcdef");
+    test_failure(s, "abc12");
+    test_captures("\\w+(?=!)", "hi there!", [Some((3, 8))]);
+    test_captures("foo(?!bar)\\w*", "foobar foobaz", [Some((7, 13))]);
+    test_success("(a)(?=b)b\\1?", "ab");
+    test_success("(a)(?=\\1)", "aa");
+    test_failure("(a)(?=\\1)", "ab");
+    test_success("(a)(?<=(?(1)a|b))", "a");
+    // nested lookarounds, each running with buffers of its own
+    test_captures("(?=a(?=b))\\w+", "aab", [Some((1, 3))]);
+    test_captures("x(?=(?<=ax)y(?!z))", "bxy axyz axy", [Some((10, 11))]);
+    println("\nLookbehind");
+    test_captures("(?<=\\$)\\d+", "costs $42", [Some((7, 9))]);
+    test_captures("(?<!\\$)\\b\\d+", "$42 or 17", [Some((7, 9))]);
+    test_success("^(?<!a)b", "b");
+    test_success("(?<=ab|cd)e", "cde");
+    match re::compile("(?<=a+)b") {
+        Ok(_) => println("\n[FAILED] Variable-width lookbehind accepted."),
+        Err(_) => print("."),
+    }
+    println("\nUnicode properties");
+    let s = ~"^\\p{L}+$";
+    test_success(s, "hello");
+    test_success(s, "привет");
+    test_success(s, "日本語");
+    test_failure(s, "a1");
+    test_success("^\\p{Greek}+ \\pN$", "λόγος 7");
+    test_failure("\\p{Greek}", "logos");
+    test_success("^[\\p{Lu}\\d]+$", "ABC123");
+    test_success("^\\P{L}+$", "123 !");
+    test_failure("^[^\\P{Lu}]$", "a");
+    println("\nNumeric escapes");
+    test_success("^\\x41\\u00e9\\x{1F600}\\u{263a}$", "Aé😀☺");
+    test_success("^[\\x00-\\x1f]+$", "\t\r\n");
+    test_failure("[\\x00-\\x1f]", "abc");
+    for s in ["\\x4", "\\xZZ", "\\u{}", "\\u{110000}", "\\u{d800}"].iter() {
+        match re::compile(*s) {
+            Ok(_) => printfln!("\n[FAILED] Malformed escape '%s' accepted.", *s),
+            Err(_) => print("."),
+        }
+    }
+    println("\nPOSIX classes");
+    let s = ~"^[[:alpha:]_][[:alnum:]_]*$";
+    test_success(s, "_tmp1");
+    test_failure(s, "1tmp");
+    test_success("^[[:xdigit:][:space:]]+$", "de ad BE EF");
+    test_failure("[[:^print:]]", "plain text");
+    test_success("^[[a]+$", "[a[");
+    match re::compile("[[:alfa:]]") {
+        Ok(_) => println("\n[FAILED] Unknown POSIX class accepted."),
+        Err(_) => print("."),
+    }
+    println("\nQuoting");
+    test_success("^\\Q1+1=2?\\E$", "1+1=2?");
+    test_failure("^\\Q1+1=2?\\E$", "11=2");
+    test_success("^a\\Q.|\\d\\E+$", "a.|\\ddd");
+    test_failure("^a\\Q.|\\d\\E+$", "a.|\\d\\d");
+    test_success("^\\Q(a)", "(a)");
+    test_success("^a\\Q\\Eb\\E$", "ab");
+    println("\nComments");
+    test_captures("(\\d+)(?#a (number\\)-(\\w+)", "42-abc",
+                  [Some((0, 6)), Some((0, 2)), Some((3, 6))]);
+    test_success("^a(?#b)c$", "ac");
+    match re::compile("a(?#unterminated") {
+        Ok(_) => println("\n[FAILED] Unterminated comment accepted."),
+        Err(_) => print("."),
+    }
+    println("\nAtomic groups");
+    test_failure("^(?>a+)ab$", "aaab");
+    test_success("^(?>a+)b$", "aaab");
+    test_captures("(?>(\\w+)\\s)x|(\\w+)", "ab cd", [Some((0, 2)), None, Some((0, 2))]);
+    test_success("^(?>x|xy)$", "x");
+    test_failure("^(?>x|xy)$", "xy");
+    println("\nPossessive quantifiers");
+    test_failure("^a*+a$", "aaa");
+    test_success("^a*+b$", "aaab");
+    test_failure("^\\d++0$", "100");
+    test_failure("^a?+a$", "a");
+    test_captures("\"[^\"]*+\"", "say \"hi\"", [Some((4, 8))]);
+    test_success("^(?:ab){1,2}+c$", "ababc");
+    println("\nConditionals");
+    let s = ~"^(<)?\\w+(?(1)>|!)$";
+    test_success(s, "<tag>");
+    test_success(s, "tag!");
+    test_failure(s, "<tag!");
+    test_failure(s, "tag>");
+    test_success("^(?P<q>\")?\\w+(?(<q>)\")$", "\"word\"");
+    test_failure("^(?P<q>\")?\\w+(?(<q>)\")$", "\"word");
+    for s in ["(?(1)a)", "(a)(?(1)b|c|d)", "(?(<x>)a)"].iter() {
+        match re::compile(*s) {
+            Ok(_) => printfln!("\n[FAILED] Bad conditional '%s' accepted.", *s),
+            Err(_) => print("."),
+        }
+    }
+    println("\nText anchors");
+    test_success("(?m)\\Afoo$", "foo\nbar");
+    test_failure("(?m)\\Abar", "foo\nbar");
+    test_failure("(?m)foo\\z", "foo\nbar");
+    test_success("foo\\Z", "foo\n");
+    test_failure("foo\\z", "foo\n");
+    test_failure("foo\\Z", "foo\n\n");
+    println("\nLine breaks");
+    let s = ~"^a\\Rb$";
+    test_success(s, "a\r\nb");
+    test_success(s, "a\nb");
+    test_success(s, "a\rb");
+    test_success(s, "a\u2028b");
+    test_failure(s, "a\n\rb");
+    test_captures("a(\\R)", "a\r\n", [Some((0, 3)), Some((1, 3))]);
+    test_success("^\\R{2}$", "\n\r\n");
+    println("\nOctal and control escapes");
+    test_success("^\\0\\012\\0101$", "\x00\nA");
+    test_success("^\\cA\\cz\\c[\\c?$", "\x01\x1a\x1b\x7f");
+    test_success("^[\\01-\\cZ]+$", "\x01\t\x1a");
+    match re::compile("\\c1") {
+        Ok(_) => println("\n[FAILED] Bad control escape accepted."),
+        Err(_) => print("."),
+    }
+    let mut opts = compile::Options::new();
+    opts.octal = false;
+    match re::compile_with("\\012", &opts) {
+        Ok(_) => println("\n[FAILED] Octal escape accepted while disabled."),
+        Err(_) => print("."),
+    }
+    println("\nBranch reset");
+    test_captures("(?|(a)|(b))", "b", [Some((0, 1)), Some((0, 1))]);
+    test_captures("(?|(a)(b)|(c))(d)", "cd",
+                  [Some((0, 2)), Some((0, 1)), None, Some((1, 2))]);
+    test_success("^(?|(a)|(b))\\1$", "bb");
+    test_failure("^(?|(a)|(b))\\1$", "ba");
+    println("\nFind");
+    test_find("\\d+", "abc 123 45", Some((4, 7)));
+    test_find("a*", "baa", Some((0, 0)));
+    test_find("x", "abc", None);
+    test_find("é+", "cafées", Some((3, 7)));
+    match re::compile("a+") {
+        Ok(p) => {
+            let pm = p;
+            if pm.shortest_match("baaa") == Some(2) && pm.shortest_match("b").is_none() {
+                print(".");
+            } else {
+                println("\n[FAILED] Finding the shortest match.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nFull matches");
+    test_full_match("a|ab", "ab", true);
+    test_full_match("\\d+", "123", true);
+    test_full_match("\\d+", "123a", false);
+    test_full_match("\\d+", "a123", false);
+    test_full_match("(a)\\1|aab", "aab", true);
+    test_full_match("", "", true);
+    match re::compile("\\bab") {
+        Ok(pm) => {
+            if pm.find_at("ab cab ab", 1) == Some((7, 9)) && pm.find_at("ab", 3).is_none() {
+                print(".");
+            } else {
+                println("\n[FAILED] Finding from an offset.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nFind all");
+    test_find_iter("\\d+", "a1 22 333", [(1, 2), (3, 5), (6, 9)]);
+    test_find_iter("a*", "baab", [(0, 0), (1, 3), (4, 4)]);
+    test_find_iter("a*|b", "aab", [(0, 2), (3, 3)]);
+    test_find_iter("x*", "éx", [(0, 0), (2, 3)]);
+    test_find_iter("(?=b)|a", "ab", [(0, 1)]);
+    test_find_iter("", "é", [(0, 0), (2, 2)]);
+    test_find_iter("x", "abc", []);
+    test_find_iter("\\bw", "w ww", [(0, 1), (2, 3)]);
+    match re::compile("(a)(b)?") {
+        Ok(pm) => {
+            if pm.count_matches("xaabab") == 3 && pm.count_matches("") == 0 {
+                print(".");
+            } else {
+                println("\n[FAILED] Counting matches.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nOverlapping matches");
+    match re::compile("a[ab]a") {
+        Ok(pm) => {
+            let found: ~[(uint, uint)] = pm.find_overlapping_iter("xabaaa").collect();
+            if found == ~[(1, 4), (3, 6)] {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Overlapping matches found %?.", found);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nCaptures of all matches");
+    match re::compile("(\\w+)=(\\w*)") {
+        Ok(p) => {
+            let pm = p;
+            let pairs: ~[(~str, ~str)] = pm.captures_iter("a=1, bc=, d=42")
+                .map(|caps| (caps.at(1).to_owned(), caps.at(2).to_owned())).collect();
+            if pairs == ~[(~"a", ~"1"), (~"bc", ~""), (~"d", ~"42")] {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Iterating over captures found %?.", pairs);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nReplacement");
+    test_replace("(\\w+)@(\\w+)", "a@b c@d", "$2@$1", false, "b@a c@d");
+    test_replace("(\\w+)@(\\w+)", "a@b c@d", "$2@$1", true, "b@a d@c");
+    test_replace("(?P<k>\\w)=(?P<v>\\w)", "x=1", "${v}${k}$$", true, "1x$");
+    test_replace("a*", "baab", "-", true, "-b-b-");
+    test_replace("(a)", "aa", "$3[$1]$", true, "[a]$[a]$");
+    test_replace("b*", "abc", "-", true, "-a-c-");
+    match re::compile("\\d+") {
+        Ok(p) => {
+            let pm = p;
+            let f: &fn(&Captures) -> ~str = |caps| fmt!("<%u>", caps.at(0).len());
+            let result = pm.replace_all("1 22 333", f);
+            if result == ~"<1> <2> <3>" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Replacing with a closure gave '%s'.", result);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nSplitting");
+    test_split("\\s*,\\s*", "a, b ,c", [&"a", &"b", &"c"]);
+    test_split(",", ",a,,b,", [&"", &"a", &"", &"b", &""]);
+    test_split("x", "", [&""]);
+    test_split("x", "abc", [&"abc"]);
+    test_split("x*", "axb", [&"", &"a", &"b", &""]);
+    test_split("", "ab", [&"", &"a", &"b", &""]);
+    test_splitn(":\\s*", "key: a: b", 2, [&"key", &"a: b"]);
+    test_splitn(":", "a:b", 5, [&"a", &"b"]);
+    test_splitn(":", "a:b", 1, [&"a:b"]);
+    test_splitn(":", "a:b", 0, []);
+    println("\nEscaping");
+    let s = re::escape("(1+1) = [2]? $x^ #a-b\\");
+    test_full_match(s, "(1+1) = [2]? $x^ #a-b\\", true);
+    test_full_match(fmt!("(?x)%s", s), "(1+1) = [2]? $x^ #a-b\\", true);
+    test_full_match(fmt!("%s+", re::escape("é.")), "é..", true);
+    let s = re::escape("a\tb\nc\u3000d");
+    test_full_match(fmt!("(?x)%s", s), "a\tb\nc\u3000d", true);
+    test_full_match(fmt!("(?x)%s", s), "abcd", false);
+    println("\nRegexp");
+    match re::Regexp::new("(\\w+)@(\\w+)") {
+        Ok(r) => {
+            if r.is_match("me@home") && r.find("at me@home") == Some((3, 10))
+                && r.captures("me@home").map_default(false, |c| c.at(2) == "home") {
+                print(".");
+            } else {
+                println("\n[FAILED] Using a Regexp.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::Regexp::new("a)") {
+        Ok(_) => println("\n[FAILED] Invalid pattern accepted."),
+        Err(_) => print("."),
+    }
+    match from_str::<re::Regexp>("a+b") {
+        Some(r) => if r.is_match("caab") { print(".") } else { println("\n[FAILED] from_str.") },
+        None => println("\n[FAILED] from_str."),
+    }
+    if from_str::<re::Regexp>("a(").is_some() {
+        println("\n[FAILED] from_str accepted an invalid pattern.");
+    } else {
+        print(".");
+    }
+    println("\nBuilder");
+    match re::RegexpBuilder::new("^a.c$").case_insensitive(true).dot_all(true).build() {
+        Ok(r) => if r.is_match("A\nC") { print(".") } else { println("\n[FAILED] Builder.") },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("a+?").swap_greed(true).build() {
+        Ok(r) => if r.find("aaa") == Some((0, 3)) { print(".") } else {
+            println("\n[FAILED] Swapping greed.")
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("a+").swap_greed(true).build() {
+        Ok(r) => if r.find("aaa") == Some((0, 1)) { print(".") } else {
+            println("\n[FAILED] Swapping greed.")
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("\\pL").unicode(false).build() {
+        Ok(_) => println("\n[FAILED] Unicode property accepted while disabled."),
+        Err(_) => print("."),
+    }
+    println("\nErrors");
+    test_error("a)", compile::error::UnbalancedParen, Some(1));
+    test_error("(a", compile::error::UnexpectedEnd, Some(2));
+    test_error("a{3,1}", compile::error::InvalidRepetitionRange(3, 1), Some(1));
+    test_error("a{1,5000000000}", compile::error::InvalidRepetition, Some(1));
+    test_error("a{99999999999999999999999}", compile::error::InvalidRepetition, Some(1));
+    test_error("(?<=(?:(?:a{4000}){4000}){4000})", compile::error::InvalidRepetition, Some(0));
+    test_error("[z-a]", compile::error::InvalidClassRange('z', 'a'), Some(1));
+    test_error("(?<=a+)", compile::error::VariableLookbehind, Some(0));
+    test_error("\\p{Klingon}", compile::error::UnknownProperty(~"Klingon"), Some(1));
+    let mut deep = ~"";
+    for _ in range(0, 100000) {
+        deep.push_char('(');
+    }
+    test_error(deep, compile::error::NestTooDeep, Some(250));
+    test_error("(((a{30}){30}){30}){30}", compile::error::ProgramTooBig(865863), None);
+    match re::RegexpBuilder::new("a{10}").size_limit(12).build() {
+        Ok(_) => println("\n[FAILED] Size limit ignored."),
+        Err(_) => print("."),
+    }
+    match re::RegexpBuilder::new("(a{1000}){1000}").unroll_limit(1000).build() {
+        Err(e) if e.kind == compile::error::ProgramTooBig(1002003) => print("."),
+        _ => println("\n[FAILED] Unroll limit ignored."),
+    }
+    match re::RegexpBuilder::new("^a{40}$").unroll_limit(0).build() {
+        Ok(r) => if r.is_match("a".repeat(40)) && !r.is_match("a".repeat(39)) { print(".") } else {
+            println("\n[FAILED] Matching with counters only.");
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("((a))").nest_limit(1).build() {
+        Ok(_) => println("\n[FAILED] Nesting limit ignored."),
+        Err(_) => print("."),
+    }
+    let mut deep = ~"";
+    for _ in range(0, 100000) {
+        deep.push_str("(?:");
+    }
+    deep.push_char('a');
+    for _ in range(0, 100000) {
+        deep.push_char(')');
+    }
+    match re::RegexpBuilder::new(deep).nest_limit(100000).build() {
+        Ok(r) => if r.is_match("xa") && !r.is_match("b") { print(".") } else {
+            println("\n[FAILED] Deeply nested groups.");
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::compile("(?x)\n\t(ab))") {
+        Err(e) if e.diagnostic("(?x)\n\t(ab))") == ~"Unbalanced ')' at 10\n\t(ab))\n\t    ^" =>
+            print("."),
+        _ => println("\n[FAILED] Caret diagnostic."),
+    }
+    let mut opts = compile::Options::new();
+    opts.backtrack = false;
+    match re::compile_with("(a)\\1", &opts) {
+        Err(e) if e.position.is_none() && e.diagnostic("(a)\\1")
+                == ~"Pattern needs the backtracking engine, which is disabled.\n(a)\\1" =>
+            print("."),
+        _ => println("\n[FAILED] Diagnostic without a position."),
+    }
+    match re::RegexpBuilder::new("a{10}").size_limit(12).build() {
+        Err(e) if e.to_str().ends_with("is too big") => print("."),
+        Err(e) => printfln!("\n[FAILED] Size error rendered as: %s.", e.to_str()),
+        Ok(_) => println("\n[FAILED] Size limit ignored."),
+    }
+    println("\nDisassembly");
+    match compile::compile("a+[^b-d]", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Char a\n0002: Split -> 0001, 0003\n\
+                            0003: Ranges [\\x{0}-ae-\\x{10ffff}]\n0004: Save 1\n0005: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Disassembly:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match compile::compile("a{40}", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Reset 0\n0002: Split -> 0003, 0007\n\
+                            0003: CountBelow 0 40\n0004: Char a\n0005: Increment 0\n\
+                            0006: Jmp -> 0002\n0007: CountAtLeast 0 40\n0008: Save 1\n\
+                            0009: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Disassembly:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nSyntax trees");
+    test_to_pattern("a(b|c)", "a(b|c)");
+    test_to_pattern("a|b", "a|b");
+    test_to_pattern("x(?:a|b)", "x(?:a|b)");
+    test_to_pattern("(?i)a", "(?:)[Aa]");
+    test_to_pattern("a{2,}?b++", "a{2,}?b++");
+    test_to_pattern("^\\.$", "\\A\\.\\z");
+    let mut counter = GroupCounter { count: 0 };
+    compile::parse::walk(&mut counter, parse("(a)(?:(b)|(?<=c))(?P<d>d)"));
+    if counter.count == 3 { print(".") } else { println("\n[FAILED] Visiting groups.") }
+    let ast = parse("ab+(c|de)");
+    let spans: ~[(uint, uint)] = ast.iter().map(|a| a.span()).collect();
+    if spans == ~[(0, 1), (1, 3), (3, 9)] { print(".") } else { println("\n[FAILED] Spans.") }
+    match ast[2] {
+        compile::parse::Fragment(compile::parse::Group(_, ref body, span), _, _, _) => {
+            if span == (3, 9) && body[0].span() == (4, 8) {
+                print(".");
+            } else {
+                println("\n[FAILED] Spans of a group.");
+            }
+        },
+        _ => println("\n[FAILED] Spans of a group."),
+    }
+    println("\nExpressions");
+    let version = compile::expr::Expr::literal("v")
+        .then(compile::expr::Expr::class('0', '9').plus().capture())
+        .or(compile::expr::Expr::literal("latest"));
+    if version.to_str() == ~"v([0-9]+)|latest" { print(".") } else {
+        printfln!("\n[FAILED] Writing an expression gave '%s'.", version.to_str());
+    }
+    match re::Regexp::from_expr(version) {
+        Ok(r) => match r.captures("v12") {
+            Some(caps) if caps.at(1) == "12" && r.is_match("latest") => print("."),
+            _ => println("\n[FAILED] Matching an expression."),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::Regexp::from_expr(compile::expr::Expr::literal("a.b").named("dot")) {
+        Ok(r) => match r.captures("xa.b") {
+            Some(caps) if caps.name("dot") == Some((1, 4)) && !r.is_match("axb") => print("."),
+            _ => println("\n[FAILED] Matching a literal expression."),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::Regexp::from_expr(compile::expr::Expr::class('a', 'z').plus().lazy()) {
+        Ok(r) => if r.find("abc") == Some((0, 1)) { print(".") } else {
+            println("\n[FAILED] Lazy expression.")
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    let twice = compile::expr::Expr::any().named("x").then(compile::expr::Expr::any().named("x"));
+    match re::Regexp::from_expr(twice) {
+        Ok(_) => println("\n[FAILED] Duplicate group name accepted."),
+        Err(_) => print("."),
+    }
+    let mut nested = compile::expr::Expr::literal("a");
+    for _ in range(0, 300) {
+        nested = nested.group();
+    }
+    match re::Regexp::from_expr(nested) {
+        Err(e) if e.kind == compile::error::NestTooDeep => print("."),
+        _ => println("\n[FAILED] Nesting limit ignored for an expression."),
+    }
+    let reversed = do task::try {
+        compile::expr::Expr::one_of([('a', 'c'), ('z', 'x')]);
+    };
+    let repeated = do task::try {
+        compile::expr::Expr::literal("a").repeat(3, Some(1));
+    };
+    if reversed.is_err() && repeated.is_err() { print(".") } else {
+        println("\n[FAILED] Reversed bounds accepted in an expression.");
+    }
+    println("\nPrefilters");
+    test_find("foo(bar|baz)", "foobfoobaz", Some((4, 10)));
+    test_find("\\bab+", "cab abb", Some((4, 7)));
+    test_find("é", "cafés", Some((3, 5)));
+    test_find("(?<=a)bc", "bcabc", Some((3, 5)));
+    test_find("foo(?=bar)\\w+", "foo foox foobar", Some((9, 15)));
+    test_find_iter("(?<=-)ab+|(?=c)cd", "ab -abb cd -ab", [(4, 7), (8, 10), (12, 14)]);
+    match compile::compile("(foo)+(bar|baz)", &compile::Options::new()) {
+        Ok(p) => if p.literal_prefix() == ~"foo" { print(".") } else {
+            printfln!("\n[FAILED] Literal prefix '%s'.", p.literal_prefix());
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    test_find("error|warn|fatal", "a warning", Some((2, 6)));
+    test_find("abcd|bc", "xabcd", Some((1, 5)));
+    test_find("x(?:ab|c)*y|z", "xacaby xy", Some((0, 6)));
+    test_find_iter("é|ü", "aéüb", [(1, 3), (3, 5)]);
+    test_find("needles?", "haystack with needles", Some((14, 21)));
+    test_find("héllo wörld", "xx héllo wörld", Some((3, 16)));
+    test_find("abcab", "abcabcab", Some((0, 5)));
+    test_find("aaab", "aaaaab", Some((2, 6)));
+    test_find("abcd", "abcabcdx", Some((3, 7)));
+    test_find("abcd", "abcabc", None);
+    test_find("xyz", "axyzxyz", Some((1, 4)));
+    test_find_iter("ab", "abcabab", [(0, 2), (3, 5), (5, 7)]);
+    test_find_iter("", "ab", [(0, 0), (1, 1), (2, 2)]);
+    test_failure("abc", "abd");
+    test_captures("abc", "xabc", [Some((1, 4))]);
+    match (compile::compile("a\\.c", &compile::Options::new()),
+           compile::compile("a(b)", &compile::Options::new())) {
+        (Ok(literal), Ok(group)) => if literal.is_literal() && !group.is_literal() {
+            print(".");
+        } else {
+            println("\n[FAILED] Detecting literal patterns.");
+        },
+        _ => println("\n[FAILED] Detecting literal patterns."),
+    }
+    match compile::compile("error|warn|(?:fa|fe)tal|a*b", &compile::Options::new()) {
+        Ok(p) => {
+            let prefixes = p.literal_prefixes();
+            let expected = [~"a", ~"b", ~"error", ~"fatal", ~"fetal", ~"warn"];
+            if prefixes.len() == expected.len() && expected.iter().all(|e| prefixes.contains(e)) {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Literal prefixes %?.", prefixes);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nOptimization");
+    match compile::compile("(?:a|b)|c", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Split -> 0002, 0007\n0002: Split -> 0003, 0005\n\
+                            0003: Char a\n0004: Jmp -> 0008\n0005: Char b\n0006: Jmp -> 0008\n\
+                            0007: Char c\n0008: Save 1\n0009: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Threading jumps:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match compile::compile("a|", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Split -> 0002, 0003\n0002: Char a\n\
+                            0003: Save 1\n0004: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Removing jumps to the next instruction:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    let empty = compile::expr::Expr::empty().or(compile::expr::Expr::empty());
+    match empty.compile(&compile::Options::new()) {
+        Ok(p) => if p.code.len() == 3 { print(".") } else {
+            printfln!("\n[FAILED] Collapsing splits:\n%s", p.to_str());
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    test_find("(?:a|b)|c", "xc", Some((1, 2)));
+    test_find("(?=(?:a|b)c)\\w+|d", "bcd", Some((0, 3)));
+    test_find("(x)?a(?(1)b|(?:c|d))", "ad", Some((0, 2)));
+    match compile::compile("abc|abd", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Char a\n0002: Char b\n0003: Split -> 0004, 0006\n\
+                            0004: Char c\n0005: Jmp -> 0007\n0006: Char d\n0007: Save 1\n\
+                            0008: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Factoring alternatives:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    test_find("a|ab", "ab", Some((0, 1)));
+    test_find("ab|a", "ab", Some((0, 2)));
+    test_find("abc|x|abd|abe", "abe", Some((0, 3)));
+    test_find("abc|abd|acx|acy|b", "acy", Some((0, 3)));
+    test_captures("(ab)c|a(b)d", "abd", [Some((0, 3)), None, Some((1, 2))]);
+    match compile::compile("cat|dog|car", &compile::Options::new()) {
+        Ok(p) => {
+            let listing = p.to_str();
+            if listing == ~"0000: Save 0\n0001: Split -> 0002, 0009\n0002: Char c\n0003: Char a\n\
+                            0004: Split -> 0005, 0007\n0005: Char t\n0006: Jmp -> 0012\n\
+                            0007: Char r\n0008: Jmp -> 0012\n0009: Char d\n0010: Char o\n\
+                            0011: Char g\n0012: Save 1\n0013: Succeed\n" {
+                print(".");
+            } else {
+                printfln!("\n[FAILED] Compiling literals into a trie:\n%s", listing);
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    test_find("cat|dog|car", "a car", Some((2, 5)));
+    test_find("(?:a|b|ab)c", "abc", Some((0, 3)));
+    test_find("(?:ab|b|a)c", "abc", Some((0, 3)));
+    test_find("ab|a|abc", "abc", Some((0, 2)));
+    test_find("abc|b|a|ab", "ab", Some((0, 1)));
+    test_find("x(?:y|)", "xy", Some((0, 2)));
+    // long literals make a trie as deep as they are long
+    let long = "a".repeat(40000);
+    test_find(fmt!("%s|b", long), fmt!("x%s", long), Some((1, 40001)));
+    test_find(fmt!("%sc|%sd|b", long, long), fmt!("%sd", long), Some((0, 40001)));
+    println("\nCharacter sets");
+    let letters = compile::charset::CharSet::from_ranges([('m', 'p'), ('a', 'c'), ('d', 'f'),
+                                                          ('o', 'z')]);
+    test_ranges(&letters, [('a', 'f'), ('m', 'z')]);
+    let vowels = compile::charset::CharSet::from_ranges([('a', 'a'), ('e', 'e'), ('i', 'i'),
+                                                         ('o', 'o'), ('u', 'u')]);
+    test_ranges(&letters.intersect(&vowels), [('a', 'a'), ('e', 'e'), ('o', 'o'), ('u', 'u')]);
+    test_ranges(&letters.union(&vowels), [('a', 'f'), ('i', 'i'), ('m', 'z')]);
+    test_ranges(&letters.negate(), [('\x00', '`'), ('g', 'l'), ('{', '\U0010ffff')]);
+    test_ranges(&compile::charset::CharSet::from_ranges([('a', 'c'), ('X', 'Z')]).fold_case(),
+                [('A', 'C'), ('X', 'Z'), ('a', 'c'), ('x', 'z')]);
+    if letters.contains('n') && !letters.contains('g') { print(".") } else {
+        println("\n[FAILED] Looking up a char in a set.");
+    }
+    test_success("^[^\\W\\d]+$", "abc_");
+    test_failure("^[^\\W\\d]+$", "ab1");
+    println("\nClass set operations");
+    test_class_sets("^[a-z&&[^aeiou]]+$", "rhythm", true);
+    test_class_sets("^[a-z&&[^aeiou]]+$", "rhyme", false);
+    test_class_sets("^[\\w--\\d]+$", "a_b", true);
+    test_class_sets("^[\\w--\\d]+$", "a1", false);
+    test_class_sets("^[a-c[x-z]]+$", "abzx", true);
+    test_class_sets("^[a-z--[aeiou]&&[a-m]]+$", "bcd", true);
+    test_class_sets("^[a-z--[aeiou]&&[a-m]]+$", "bcn", false);
+    test_class_sets("(?i)^[a-z--[a]]$", "A", false);
+    test_class_sets("(?i)^[a-z--[a]]$", "B", true);
+    test_class_sets("^[^a-z&&[aeiou]]$", "b", true);
+    test_success("^[[a]+$", "[a[");
+    test_success("^[+--]+$", "+,-");
+    let deep = "[".repeat(100000) + "a" + "]".repeat(100000);
+    match re::RegexpBuilder::new(deep).class_sets(true).build() {
+        Err(e) if e.kind == compile::error::NestTooDeep && e.position == Some(251) => print("."),
+        _ => println("\n[FAILED] Nesting limit ignored for classes."),
+    }
+    test_class_sets(fmt!("^%sa%s$", "[".repeat(20), "]".repeat(20)), "a", true);
+    println("\nLazy DFA");
+    test_success("\\bcat\\b", "concat cat");
+    test_failure("\\bcat\\b", "concat cats");
+    test_find("\\Bcat", "cat concat", Some((7, 10)));
+    test_success("(?m)^b$", "a\nb\nc");
+    test_failure("^b$", "a\nb\nc");
+    test_find("abcd|c", "abcd", Some((0, 4)));
+    test_find("bcde|c", "abcdf", Some((2, 3)));
+    test_find("a*?$", "baa", Some((1, 3)));
+    test_success("x[^x]{3}y", "ab".repeat(5000) + "xabcy");
+    test_failure("x[^x]{3}y", "ab".repeat(5000) + "xabcdy");
+    // every window of 13 chars, making more states than the cache holds
+    let mut text = ~"";
+    for i in range(0u, 8192) {
+        for bit in range(0u, 13) {
+            text.push_char(if (i >> bit) & 1 == 1 { 'a' } else { 'b' });
+        }
+    }
+    test_failure("a[ab]{12}c", text);
+    test_success("a[ab]{12}c", fmt!("%sa%sc", text, "b".repeat(12)));
+    test_find("a+b", "xaaab", Some((1, 5)));
+    test_find("ab|abcd", "xabcd", Some((1, 3)));
+    test_find("\\b\\w+\\b", "  word ", Some((2, 6)));
+    test_find("(?m)^\\d+$", "ab\n12\n", Some((3, 5)));
+    test_find("(?:x|xy)*z$", "xyxz", Some((0, 4)));
+    test_find("b*$", "aab", Some((2, 3)));
+    test_captures("(a+)(b+)", "xxaabbb", [Some((2, 7)), Some((2, 4)), Some((4, 7))]);
+    match (compile::compile("a(?=b)|\\Z", &compile::Options::new()),
+           compile::compile("a+b", &compile::Options::new())) {
+        (Ok(lookahead), Ok(plain)) => if lookahead.reverse().is_none()
+                && plain.reverse().is_some() {
+            print(".");
+        } else {
+            println("\n[FAILED] Reversing programs.");
+        },
+        _ => println("\n[FAILED] Reversing programs."),
+    }
+    // the states cached by a search are reused by the next one
+    test_find_iter("\\b[a-z]+\\d\\b", "ab1 x cd2 e3 f4g", [(0, 3), (6, 9), (10, 12)]);
+    test_find_iter("a[ab]{12}c", fmt!("%sc%sc", text, text), [(106483, 106497), (212980, 212994)]);
+    // as is the scratch state a one-shot search leaves for the next
+    match re::compile("(?<=-)[a-z]+\\d\\b") {
+        Ok(r) => if r.find("-ab1") == Some((1, 4)) && r.find("x -cd2") == Some((3, 6))
+                && !r.is_match("ef3") && r.find("-ab1") == Some((1, 4)) {
+            print(".")
+        } else {
+            println("\n[FAILED] Reusing the scratch state of one-shot searches.");
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nOne-pass");
+    test_captures("^(\\w+)@(\\w+)\\.com$", "me@host.com",
+                  [Some((0, 11)), Some((0, 2)), Some((3, 7))]);
+    test_captures("(\\d+)-(\\d+)?x", "a 12-x", [Some((2, 6)), Some((2, 4)), None]);
+    test_captures("(a|b)*c", "abac", [Some((0, 4)), Some((2, 3))]);
+    test_captures("a(b)?", "ac", [Some((0, 1)), None]);
+    test_captures("x(y*)", "xyyz", [Some((0, 3)), Some((1, 3))]);
+    test_captures("x(y*?)", "xyyz", [Some((0, 1)), Some((1, 1))]);
+    test_captures("(\\w+)\\b", "ab cd", [Some((0, 2)), Some((0, 2))]);
+    // not one-pass, as the first `a+` can stop at any `a`
+    test_captures("(a+)(a+)", "aaa", [Some((0, 3)), Some((0, 2)), Some((2, 3))]);
+    println("\nBounded backtracking");
+    test_captures("(a|ab)(c|bcd)(d*)", "abcd",
+                  [Some((0, 4)), Some((0, 1)), Some((1, 4)), Some((4, 4))]);
+    test_captures("(a|aa)*(a*)c", fmt!("%sc", "a".repeat(30)),
+                  [Some((0, 31)), Some((29, 30)), Some((30, 30))]);
+    // too long for the bitmap, so the NFA simulation runs instead
+    test_captures("(a|aa)*(a*)c", fmt!("%sc", "a".repeat(10000)),
+                  [Some((0, 10001)), Some((9999, 10000)), Some((10000, 10000))]);
+    println("\nShift-Or");
+    test_find("\\d{4}-\\d\\d", "on 2024-06-01", Some((3, 10)));
+    test_find("x[^x]{3}y", "xxaby xabcy", Some((6, 11)));
+    test_find("a.c", "\u00e9a\u00e9c", Some((2, 6)));
+    test_find("(?i)ab", "xAB", Some((1, 3)));
+    test_find("[ab]{64}", "a".repeat(63), None);
+    test_find("[ab]{64}", fmt!("c%s", "ab".repeat(32)), Some((1, 65)));
+    test_find_iter("a[bc]", "ab ac ad", [(0, 2), (3, 5)]);
+    println("\nStrategies");
+    // a literal, a sequence of classes, a DFA program and a lookahead,
+    // each found by different engines
+    for &pattern in ["abc", "[a-c]b[c-e]", "ab+c", "ab(?=c)c"].iter() {
+        test_find(pattern, "xxabcxx", Some((2, 5)));
+        test_captures(pattern, "xxabcxx", [Some((2, 5))]);
+    }
+    test_captures("(a)bc|(b)\\2", "xxabcxx", [Some((2, 5)), Some((2, 3)), None]);
+    println("\nUnanchored search");
+    test_find("a(?=c)|b", "xxaab ac", Some((4, 5)));
+    test_captures("(a+)(?!b)", "aab aa", [Some((0, 1)), Some((0, 1))]);
+    test_find("(?=\\d)\\w+$", fmt!("%s7z", "ab ".repeat(5000)), Some((15000, 15002)));
+    // every `a` doubles the threads unless they are deduplicated
+    test_captures("((?:a|[ab])*)c", fmt!("%sc", "a".repeat(30000)),
+                  [Some((0, 30001)), Some((0, 30000))]);
+    test_captures("(?:(a)|[ab]){2,40}c", "aaabc", [Some((0, 5)), Some((2, 3))]);
+    println("\nFull DFA");
+    test_dfa("[a-z]+@", "mail: x@y", Some(8));
+    test_dfa("abcd|c", "abcd", Some(4));
+    test_dfa("\\bcat\\b", "concat cats", None);
+    test_dfa("(?m)^b$", "a\nb\nc", Some(3));
+    test_dfa("\u00e9+", "caf\u00e9\u00e9!", Some(7));
+    test_dfa("x[^x]{3}y", "ab".repeat(500) + "xabcy", Some(1005));
+    match re::RegexpBuilder::new("(a|b)*a(a|b){12}").dfa_state_limit(1000).build_dfa() {
+        Err(e) if e.kind == compile::error::TooManyStates(1000) => print("."),
+        _ => println("\n[FAILED] DFA state limit ignored."),
+    }
+    match re::RegexpBuilder::new("a(?=b)").build_dfa() {
+        Err(e) if e.kind == compile::error::UnsupportedByDfa => print("."),
+        _ => println("\n[FAILED] Lookahead accepted by the DFA."),
+    }
+    // patterns matching the same have minimal DFAs of the same size
+    match (re::RegexpBuilder::new("(?:a|aa)+b").build_dfa(),
+           re::RegexpBuilder::new("a+b").build_dfa()) {
+        (Ok(x), Ok(y)) => if x.len() == y.len() { print(".") } else {
+            println("\n[FAILED] Minimizing a DFA.");
+        },
+        _ => println("\n[FAILED] Building DFAs to minimize."),
+    }
+    match re::RegexpBuilder::new("\\b[a-z]+@\\w+").build_dfa() {
+        Ok(dfa) => match re::dfa::Dfa::from_bytes(dfa.to_bytes()) {
+            Some(copy) => if copy.find_end("to: x@y.z", 0) == Some(7) { print(".") } else {
+                println("\n[FAILED] Decoding a DFA.");
+            },
+            None => println("\n[FAILED] Decoding a DFA."),
+        },
+        Err(e) => printfln!("\nBuilding a DFA failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("\\x{d7ff}").build_dfa() {
+        Ok(dfa) => match re::dfa::Dfa::from_bytes(dfa.to_bytes()) {
+            Some(copy) => if copy.is_match("a\ud7ff") && !copy.is_match("\ue000") {
+                print(".")
+            } else {
+                println("\n[FAILED] Decoding a DFA next to the surrogates.");
+            },
+            None => println("\n[FAILED] Decoding a DFA next to the surrogates."),
+        },
+        Err(e) => printfln!("\nBuilding a DFA failed: %s.", e.to_str()),
+    }
+    if re::dfa::Dfa::from_bytes([1, 2, 3]).is_none() { print(".") } else {
+        println("\n[FAILED] Decoding bytes that are not a DFA.");
+    }
+    // counts so large that the size they call for overflows 32 bits
+    let mut bytes = ~['r' as u8, 'e' as u8, '-' as u8, 'd' as u8, 'f' as u8, 'a' as u8, 1];
+    bytes.push_all([0xff, 0xff, 0xff, 0, 0xff, 0xff, 0xff, 0]);
+    bytes.push_all(vec::from_elem(64, 0u8));
+    if re::dfa::Dfa::from_bytes(bytes).is_none() { print(".") } else {
+        println("\n[FAILED] Decoding a DFA with overflowing counts.");
+    }
+    match re::RegexpBuilder::new("x+y").build_dfa() {
+        Ok(dfa) => if dfa.is_match("axxy") && !dfa.is_match("axx") { print(".") } else {
+            println("\n[FAILED] Matching with a DFA.");
+        },
+        Err(e) => printfln!("\nBuilding a DFA failed: %s.", e.to_str()),
+    }
+    println("\nLeftmost-first");
+    // the same match and groups from the bounded backtracker, the NFA
+    // simulation and the backtracking engine
+    test_captures("(a|ab)(b*)", "abb", [Some((0, 3)), Some((0, 1)), Some((1, 3))]);
+    test_captures("(a|ab)(b*)", fmt!("%sabb", "x".repeat(30000)),
+                  [Some((30000, 30003)), Some((30000, 30001)), Some((30001, 30003))]);
+    test_captures("(a|ab)(?=b)(b*)", "abb", [Some((0, 3)), Some((0, 1)), Some((1, 3))]);
+    test_captures("(?>(a|ab))(b*)", "abb", [Some((0, 3)), Some((0, 1)), Some((1, 3))]);
+    test_captures("(ab|a)(b*)", "abb", [Some((0, 3)), Some((0, 2)), Some((2, 3))]);
+    test_captures("(a*?)(a*)", "aa", [Some((0, 2)), Some((0, 0)), Some((0, 2))]);
+    test_find_iter("a|ab", "ab ab", [(0, 1), (3, 4)]);
+    println("\nLeftmost-longest");
+    test_longest("a|ab", "ab", Some((0, 2)));
+    test_longest("if|ifdef|[a-z]+", "ifdefx", Some((0, 6)));
+    test_longest("a*?", "aaa", Some((0, 3)));
+    test_longest("bcd|ab", "abcd", Some((0, 2)));
+    test_longest("(?=.)(?:bcd|ab)", "abcd", Some((0, 2)));
+    // the match starting later ends first, and the earlier one replaces it
+    test_longest("abcd|bc", "abcd", Some((0, 4)));
+    test_longest("(?=.)(?:abcd|bc)", "abcd", Some((0, 4)));
+    test_longest("(?=.)(?:abcd|bc)", "abcx", Some((1, 3)));
+    match re::RegexpBuilder::new("abcd|bc").longest(true).build() {
+        Ok(r) => match r.stream() {
+            Ok(stream) => {
+                let mut stream = stream;
+                let mut found = stream.feed("abcd");
+                found.push_all(stream.finish());
+                if found == ~[(0, 4)] {
+                    print(".");
+                } else {
+                    printfln!("\n[FAILED] Longest matches of a stream %?.", found);
+                }
+            },
+            Err(e) => printfln!("\nStreaming failed: %s.", e.to_str()),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    test_longest("(?:a|ab)(?:c|bcd)", fmt!("%sabcd", "x".repeat(30000)), Some((30000, 30004)));
+    test_longest("x{2,40}?", "xxxxx", Some((0, 5)));
+    match re::RegexpBuilder::new("(a|ab)(b*?)").longest(true).build() {
+        Ok(r) => match r.captures("abb") {
+            Some(caps) if caps.pos(1) == Some((0, 1)) && caps.pos(2) == Some((1, 3)) =>
+                print("."),
+            _ => println("\n[FAILED] Groups of the longest match."),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("(a)\\1").longest(true).build() {
+        Err(e) if e.kind == compile::error::LongestNeedsNfa => print("."),
+        _ => println("\n[FAILED] Longest match with a backreference."),
+    }
+    println("\nEarliest match");
+    test_earliest("a+", "baaa", Some((1, 2)));
+    test_earliest("abc|b", "abc", Some((1, 2)));
+    test_earliest("(?=a)a+", "baa", Some((1, 2)));
+    test_earliest("x*", "xx", Some((0, 0)));
+    test_earliest("if|ifdef|[a-z]+", "ifdef", Some((0, 1)));
+    test_earliest("a+", "bbb", None);
+    match re::RegexpBuilder::new("(a+)(b*)").earliest(true).build() {
+        Ok(r) => {
+            match r.captures("aab") {
+                Some(caps) if caps.pos(1) == Some((0, 1)) && caps.pos(2) == Some((1, 1)) =>
+                    print("."),
+                _ => println("\n[FAILED] Groups of the earliest match."),
+            }
+            let found: ~[(uint, uint)] = r.find_iter("aab").collect();
+            if found == ~[(0, 1), (1, 2)] {
+                print(".");
+            } else {
+                println("\n[FAILED] Earliest matches of an iterator.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::RegexpBuilder::new("(a)\\1").earliest(true).build() {
+        Err(e) if e.kind == compile::error::EarliestNeedsNfa => print("."),
+        _ => println("\n[FAILED] Earliest match with a backreference."),
+    }
+    match (re::compile("[0-9]+"), re::RegexpBuilder::new("[0-9]+").earliest(true).build()) {
+        (Ok(r), Ok(e)) => {
+            if r.match_at("x123", 1) == Some(4) && r.match_at("x123", 0) == None &&
+                    e.match_at("x123", 1) == Some(2) && r.match_at("x123", 5) == None {
+                print(".");
+            } else {
+                println("\n[FAILED] Matches at a position.");
+            }
+        },
+        _ => println("\nCompiling failed."),
+    }
+    println("\nStreams");
+    test_stream("\\d+", "a1 22 333", [(1, 2), (3, 5), (6, 9)]);
+    test_stream("a*", "baab", [(0, 0), (1, 3), (4, 4)]);
+    test_stream("a.*c|ab", "abxab", [(0, 2), (3, 5)]);
+    test_stream("a.*c|ab", "abxac", [(0, 5)]);
+    test_stream("\\bfoo\\b", "foo foobar foo", [(0, 3), (11, 14)]);
+    test_stream("(?m)^\\w+$", "ab\ncd\n", [(0, 2), (3, 5)]);
+    test_stream("\\Ax|x\\z", "xaxax", [(0, 1), (4, 5)]);
+    test_stream("é+", "aééb", [(1, 5)]);
+    test_stream("(a|b){2,3}", "ababab", [(0, 3), (3, 6)]);
+    test_stream("x", "", []);
+    match re::compile("a(?=b)") {
+        Ok(r) => match r.stream() {
+            Err(e) if e.kind == compile::error::UnsupportedByStream => print("."),
+            _ => println("\n[FAILED] Streaming a lookahead."),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    match re::compile("[^ ]+") {
+        Ok(r) => {
+            let text = "one twö three";
+            let reader = io::BytesReader { bytes: text.as_bytes(), pos: @mut 0 };
+            match r.find_iter_reader(reader) {
+                Ok(m) => {
+                    let mut matches = m;
+                    let found: ~[(uint, uint)] = matches.collect();
+                    if found == ~[(0, 3), (4, 8), (9, 14)] {
+                        print(".");
+                    } else {
+                        printfln!("\n[FAILED] Matches read found %?.", found);
+                    }
+                },
+                Err(e) => printfln!("\nReading failed: %s.", e.to_str()),
+            }
+            // "ab cd\xFFef", then "ab \xC3" cut off inside an 'é'
+            let texts: [(&[u8], re::ReadError), ..2] =
+                [(&[97, 98, 32, 99, 100, 255, 101, 102], re::InvalidUtf8(5)),
+                 (&[97, 98, 32, 195], re::TruncatedChar(3))];
+            for &(bytes, error) in texts.iter() {
+                let reader = io::BytesReader { bytes: bytes, pos: @mut 0 };
+                match r.find_iter_reader(reader) {
+                    Ok(m) => {
+                        let mut matches = m;
+                        let mut found = ~[];
+                        loop {
+                            match matches.next() {
+                                Some(span) => found.push(span),
+                                None => break,
+                            }
+                        }
+                        if found == ~[(0, 2)] && matches.error() == Some(error) {
+                            print(".");
+                        } else {
+                            printfln!("\n[FAILED] Matches read found %? and error %?.", found,
+                                      matches.error());
+                        }
+                    },
+                    Err(e) => printfln!("\nReading failed: %s.", e.to_str()),
+                }
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nPartial matches");
+    test_partial("\\Aabc", "ab", Incomplete);
+    test_partial("\\Aabc", "abc", Complete(0, 3));
+    test_partial("\\Aabc", "abx", NoMatch);
+    test_partial("\\Aab+", "abb", Incomplete);
+    test_partial("\\Aab+?", "ab", Complete(0, 2));
+    test_partial("\\Aa\\b", "a", Incomplete);
+    test_partial("\\Aa\\b", "a b", Complete(0, 1));
+    test_partial("\\Ax$", "x", Incomplete);
+    test_partial("\\Ax$", "xy", NoMatch);
+    test_partial("\\Ax\\Z", "x\n", Incomplete);
+    test_partial("abc", "xyz", Incomplete);
+    test_partial("a|abc", "xab", Complete(1, 2));
+    test_partial("abc|a", "xab", Incomplete);
+    match re::compile("a(?=b)") {
+        Ok(r) => match r.partial_match("a") {
+            Err(e) if e.kind == compile::error::UnsupportedByStream => print("."),
+            _ => println("\n[FAILED] Partial match of a lookahead."),
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nBytes");
+    match (re::compile("\\xFF+"), re::compile("a.c"), re::compile("\\xC3\\xA9")) {
+        (Ok(high), Ok(dot), Ok(utf8)) => {
+            if high.find_bytes([0x61, 0xff, 0xff, 0x62]) == Some((1, 3)) &&
+                    high.find_bytes([0x61, 0xfe]) == None &&
+                    dot.is_match_bytes([0x61, 0x80, 0x63]) &&
+                    !dot.is_match_bytes([0x61, 0x0a, 0x63]) &&
+                    utf8.find_bytes("café".as_bytes()) == Some((3, 5)) {
+                print(".");
+            } else {
+                println("\n[FAILED] Byte searches.");
+            }
+        },
+        _ => println("\nCompiling failed."),
+    }
+    test_error("(?-u)\\x{100}", compile::error::NotAByte, Some(6));
+    test_error("(?-u:\\pL)", compile::error::UnicodeDisabled, Some(6));
+    test_success("(?-u)\\xFF(?u)\\x{100}", "\u00ff\u0100");
+    match (re::compile("(?-u)é+"), re::compile("(?-u)[^a]"), re::compile("(?i-u)[^a]")) {
+        (Ok(utf8), Ok(byte), Ok(folded)) => {
+            if utf8.find_bytes("caféé".as_bytes()) == Some((3, 7)) &&
+                    byte.find_bytes([0x61, 0xff, 0x61]) == Some((1, 2)) &&
+                    folded.find_bytes([0x41]) == None {
+                print(".");
+            } else {
+                println("\n[FAILED] Byte classes and literals.");
+            }
+        },
+        _ => println("\nCompiling failed."),
+    }
+    test_failure("(?-u).", "\u0101");
+    test_failure("(?-u)[^a]", "\u0101");
+    test_failure("(?-u)\\W", "\u0101");
+    test_success("(?-u:\\xE9)|\\x{101}", "\u0101");
+    test_error("(?-u)[é]", compile::error::NotAByte, Some(6));
+    match re::compile("(?-u)\\Qé\\E") {
+        Ok(r) => if r.find_bytes("é".as_bytes()) == Some((0, 2)) && !r.is_match_bytes([0xe9]) {
+            print(".");
+        } else {
+            println("\n[FAILED] Quoted bytes.");
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
+    println("\nUTF-16");
+    test_utf16("é+", [0x61, 0xe9, 0xe9, 0x62], [(1, 3)]);
+    test_utf16(".", [0x78, 0xd83d, 0xde00, 0x79], [(0, 1), (1, 3), (3, 4)]);
+    test_utf16("\\x{1F600}+", [0xd83d, 0xde00, 0xd83d, 0xde00, 0x20], [(0, 4)]);
+    test_utf16("\\x{FFFD}a", [0x62, 0xd800, 0x61], [(1, 3)]);
+    test_utf16("a*", [0x62, 0x61], [(0, 0), (1, 2)]);
+    test_utf16("x", [], []);
+    println("\nGrapheme clusters");
+    test_find_iter("\\X", "e\u0301x", [(0, 3), (3, 4)]);
+    test_find_iter("\\X", "\r\na", [(0, 2), (2, 3)]);
+    test_find_iter("\\X", "\U0001F468\u200D\U0001F469\u200D\U0001F467", [(0, 18)]);
+    test_find_iter("\\X", "\U0001F1EB\U0001F1F7\U0001F1E9", [(0, 8), (8, 12)]);
+    test_find_iter("\\X", "\u1100\u1161\u11A8\uAC00\u11A8", [(0, 9), (9, 15)]);
+    test_find("^\\X$", "\u0915\u093F", Some((0, 6)));
+    test_success("^\\X\\x{301}$", "e\u0301");
+    test_error("(?-u)\\X", compile::error::UnicodeDisabled, Some(6));
+    println("\nTasks");
+    match re::compile("(\\w+)@(\\w+)") {
+        Ok(r) => {
+            let shared = arc::ARC(r);
+            let (port, chan) = stream();
+            let chan = SharedChan::new(chan);
+            for i in range(0u, 4) {
+                let (local, chan) = (shared.clone(), chan.clone());
+                do task::spawn {
+                    let text = fmt!("user%u@host", i);
+                    chan.send(local.get().find(text) == Some((0, text.len())));
+                }
+            }
+            if range(0, 4).all(|_| port.recv()) { print(".") } else {
+                println("\n[FAILED] Sharing a regexp between tasks.");
+            }
+        },
+        Err(e) => printfln!("\nCompiling failed: %s.", e.to_str()),
+    }
     println("\nMiscelaneous");
     let s = ~"a?b+c*|d+|e+";
     test_success(s, "b");